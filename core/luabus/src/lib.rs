@@ -7,14 +7,24 @@ extern crate luakit;
 mod socket_dns;
 mod socket_tcp;
 mod socket_udp;
+mod socket_unix;
 mod socket_mgr;
+mod socket_codec;
+mod socket_dgram;
+mod socket_filter;
+mod socket_kcp;
+mod socket_kcp_listener;
 mod socket_ping;
+mod socket_proxy;
 mod socket_helper;
 mod socket_stream;
 mod socket_router;
+mod socket_thread;
+mod socket_session;
 mod socket_listener;
 mod lua_socket_mgr;
 mod lua_socket_node;
+mod lua_socket_dgram;
 
 use lua::lua_State;
 use libc::c_int as int;
@@ -25,6 +35,7 @@ use socket_tcp::SocketTcp;
 use socket_udp::SocketUdp;
 use lua_socket_mgr::LuaSocketMgr;
 use lua_socket_node::LuaSocketNode;
+use lua_socket_dgram::LuaSocketDgram;
 
 #[no_mangle]
 pub extern "C" fn luaopen_luabus(L: *mut lua_State) -> int {
@@ -42,6 +53,25 @@ pub extern "C" fn luaopen_luabus(L: *mut lua_State) -> int {
         "rpc", socket_mgr::Prototype::ProtoRpc,
         "text", socket_mgr::Prototype::ProtoText
     );
+    luakit::new_enum!(luabus, "emaster_policy",
+        "lowest_id", socket_router::MasterPolicy::LowestId,
+        "designated", socket_router::MasterPolicy::Designated,
+        "least_loaded", socket_router::MasterPolicy::LeastLoaded
+    );
+    luakit::new_enum!(luabus, "ehash_mode",
+        "modulo", socket_router::HashMode::Modulo,
+        "consistent", socket_router::HashMode::Consistent
+    );
+    luakit::new_enum!(luabus, "ecompress_mode",
+        "none", socket_codec::CompressMode::None,
+        "lz4", socket_codec::CompressMode::Lz4,
+        "zstd", socket_codec::CompressMode::Zstd
+    );
+    luakit::new_enum!(luabus, "eencrypt_mode",
+        "none", socket_codec::EncryptMode::None,
+        "xxtea", socket_codec::EncryptMode::Xxtea,
+        "aes", socket_codec::EncryptMode::Aes
+    );
     luakit::new_class!(SocketUdp, luabus, "SocketUdp",
         "send", SocketUdp::send,
         "recv", SocketUdp::recv,
@@ -61,22 +91,66 @@ pub extern "C" fn luaopen_luabus(L: *mut lua_State) -> int {
     );
     luakit::new_class!(LuaSocketMgr, luabus, "LuaSocketMgr",
         "wait", LuaSocketMgr::wait,
+        "stats", LuaSocketMgr::stats,
         "listen", LuaSocketMgr::listen,
         "connect", LuaSocketMgr::connect,
+        "bind_udp", LuaSocketMgr::bind_udp,
+        "listen_kcp", LuaSocketMgr::listen_kcp,
+        "connect_kcp", LuaSocketMgr::connect_kcp,
         "map_token", LuaSocketMgr::map_token,
+        "get_master", LuaSocketMgr::get_master,
+        "get_deferred", LuaSocketMgr::get_deferred,
+        "set_hash_mode", LuaSocketMgr::set_hash_mode,
+        "set_node_weight", LuaSocketMgr::set_node_weight,
+        "set_master_policy", LuaSocketMgr::set_master_policy,
+        "set_io_threads", LuaSocketMgr::set_io_threads,
+        "set_dispatch_budget", LuaSocketMgr::set_dispatch_budget,
         "broadcast", LuaSocketMgr::broadcast,
         "broadgroup", LuaSocketMgr::broadgroup
     );
+    luakit::new_class!(LuaSocketDgram, luabus, "LuaSocketDgram",
+        "close", LuaSocketDgram::close,
+        "stats", LuaSocketDgram::stats,
+        "sendto", LuaSocketDgram::sendto,
+        "join_group", LuaSocketDgram::join_group,
+        "leave_group", LuaSocketDgram::leave_group,
+        "set_broadcast", LuaSocketDgram::set_broadcast,
+        "set_multicast_ttl", LuaSocketDgram::set_multicast_ttl,
+        "set_multicast_loop", LuaSocketDgram::set_multicast_loop;
+        "token" => token: u32
+    );
     luakit::new_class!(LuaSocketNode, luabus, "LuaSocketNode",
         "call", LuaSocketNode::call,
         "close", LuaSocketNode::close,
+        "stats", LuaSocketNode::stats,
+        "add_deny", LuaSocketNode::add_deny,
+        "add_allow", LuaSocketNode::add_allow,
+        "clear_filter", LuaSocketNode::clear_filter,
+        "set_rate_limit", LuaSocketNode::set_rate_limit,
+        "set_proxy_protocol", LuaSocketNode::set_proxy_protocol,
         "call_pb", LuaSocketNode::call_pb,
+        "forward_target", LuaSocketNode::forward_target,
+        "forward_master", LuaSocketNode::forward_master,
+        "forward_hash", LuaSocketNode::forward_hash,
+        "forward_broadcast", LuaSocketNode::forward_broadcast,
+        "forward_region_master", LuaSocketNode::forward_region_master,
+        "forward_group", LuaSocketNode::forward_group,
         "call_data", LuaSocketNode::call_data,
+        "set_codec", LuaSocketNode::set_codec,
+        "set_compress", LuaSocketNode::set_compress,
+        "set_encrypt", LuaSocketNode::set_encrypt,
         "set_nodelay", LuaSocketNode::set_nodelay,
         "set_timeout", LuaSocketNode::set_timeout,
+        "track_call", LuaSocketNode::track_call,
+        "finish_call", LuaSocketNode::finish_call,
+        "set_max_packet", LuaSocketNode::set_max_packet,
+        "set_flow_control", LuaSocketNode::set_flow_control,
+        "set_kcp_window", LuaSocketNode::set_kcp_window,
+        "set_kcp_nodelay", LuaSocketNode::set_kcp_nodelay,
         "get_route_count", LuaSocketNode::get_route_count,
         "build_session_id", LuaSocketNode::build_session_id;
         "ip" => ip: String;
+        "port" => port: u16;
         "token" => token: u32;
         "stoken"=> stoken: u32
     );
@@ -0,0 +1,106 @@
+#![allow(non_snake_case)]
+#![allow(dead_code)]
+
+use std::rc::Weak;
+use std::cell::RefCell;
+use std::net::{ IpAddr, Ipv4Addr, SocketAddr };
+
+use luakit::LuaGc;
+
+use crate::socket_dgram::MulticastIface;
+use crate::socket_mgr::{ SocketMgr, SocketStat };
+
+pub struct LuaSocketDgram {
+    socket_mgr: Weak<RefCell<SocketMgr>>,
+    pub token: u32,
+}
+
+impl LuaGc for LuaSocketDgram {}
+
+impl LuaSocketDgram {
+    pub fn new(token: u32, mgr: Weak<RefCell<SocketMgr>>) -> LuaSocketDgram {
+        LuaSocketDgram {
+            token : token,
+            socket_mgr: mgr,
+        }
+    }
+
+    pub fn close(&self) {
+        if let Some(socket_mgr) = self.socket_mgr.upgrade() {
+            socket_mgr.borrow_mut().close(self.token);
+        }
+    }
+
+    //errors包含发送失败以及发送队列满丢弃的数据报
+    pub fn stats(&self) -> Option<SocketStat> {
+        if let Some(socket_mgr) = self.socket_mgr.upgrade() {
+            return socket_mgr.borrow().stats(self.token);
+        }
+        None
+    }
+
+    pub fn sendto(&self, data: &[u8], ip: String, port: u32) -> bool {
+        let addr: SocketAddr = match format!("{}:{}", ip, port).parse() {
+            Ok(addr) => addr,
+            Err(_) => return false,
+        };
+        if let Some(socket_mgr) = self.socket_mgr.upgrade() {
+            return socket_mgr.borrow_mut().sendto(self.token, data, addr);
+        }
+        false
+    }
+
+    pub fn set_broadcast(&self, enable: bool) -> bool {
+        if let Some(socket_mgr) = self.socket_mgr.upgrade() {
+            return socket_mgr.borrow_mut().set_broadcast(self.token, enable);
+        }
+        false
+    }
+
+    pub fn join_group(&self, group: String, iface: String) -> bool {
+        self.set_multicast(group, iface, true)
+    }
+
+    pub fn leave_group(&self, group: String, iface: String) -> bool {
+        self.set_multicast(group, iface, false)
+    }
+
+    pub fn set_multicast_loop(&self, enable: bool) -> bool {
+        if let Some(socket_mgr) = self.socket_mgr.upgrade() {
+            return socket_mgr.borrow_mut().set_multicast_loop(self.token, enable);
+        }
+        false
+    }
+
+    //ipv6时为组播跳数
+    pub fn set_multicast_ttl(&self, ttl: u32) -> bool {
+        if let Some(socket_mgr) = self.socket_mgr.upgrade() {
+            return socket_mgr.borrow_mut().set_multicast_ttl(self.token, ttl);
+        }
+        false
+    }
+
+    //ipv4的iface为本地接口地址，ipv6的iface为接口索引，为空时由系统选择
+    fn set_multicast(&self, group: String, iface: String, join: bool) -> bool {
+        let group: IpAddr = match group.parse() {
+            Ok(addr) => addr,
+            Err(_) => return false,
+        };
+        let iface = match group {
+            IpAddr::V4(_) if iface.is_empty() => MulticastIface::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V4(_) => match iface.parse() {
+                Ok(addr) => MulticastIface::V4(addr),
+                Err(_) => return false,
+            },
+            IpAddr::V6(_) if iface.is_empty() => MulticastIface::V6(0),
+            IpAddr::V6(_) => match iface.parse() {
+                Ok(index) => MulticastIface::V6(index),
+                Err(_) => return false,
+            },
+        };
+        if let Some(socket_mgr) = self.socket_mgr.upgrade() {
+            return socket_mgr.borrow_mut().set_multicast(self.token, group, iface, join).is_ok();
+        }
+        false
+    }
+}
@@ -4,18 +4,57 @@
 
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::collections::HashMap;
 
 use lua::lua_State;
 use libc::c_int as int;
 
-use crate::socket_router::SocketRouter;
+use crate::socket_router::{ SocketRouter, MasterPolicy, HashMode };
 use crate::lua_socket_node::LuaSocketNode;
-use crate::socket_mgr::{Prototype, SocketMgr};
+use crate::lua_socket_dgram::LuaSocketDgram;
+use crate::socket_mgr::{ Prototype, SocketMgr, SocketStat, NodeEvent };
 
-use luakit::{ LuaGc, LuaPush, PtrBox };
+use lua::ternary;
+use luakit::{ LuaGc, LuaGuard, LuaPush, LuaTable, PtrBox };
+
+fn set_field<V: LuaPush>(L: *mut lua_State, key: &str, val: V) {
+    val.native_to_lua(L);
+    unsafe { lua::lua_setfield(L, -2, lua::to_char!(key)); }
+}
+
+impl LuaPush for SocketStat {
+    fn native_to_lua(self, L: *mut lua_State) -> i32 {
+        let now = luakit::steady_ms();
+        unsafe { lua::lua_createtable(L, 0, 14); }
+        set_field(L, "token", self.token);
+        set_field(L, "status", self.status);
+        set_field(L, "ip", self.ip);
+        set_field(L, "port", self.port);
+        set_field(L, "bytes_in", self.bytes_in);
+        set_field(L, "bytes_out", self.bytes_out);
+        set_field(L, "packets_in", self.packets_in);
+        set_field(L, "packets_out", self.packets_out);
+        set_field(L, "send_queue", self.send_queue);
+        set_field(L, "accepted", self.accepted);
+        set_field(L, "rejected", self.rejected);
+        set_field(L, "errors", self.errors);
+        set_field(L, "connect_time", self.connect_time);
+        set_field(L, "alive_time", ternary!(self.connect_time > 0, now.saturating_sub(self.connect_time), 0));
+        set_field(L, "recv_age", ternary!(self.lastrecv_time > 0, now.saturating_sub(self.lastrecv_time), 0));
+        1
+    }
+}
+
+//lua侧的连接对象，table持有引用保证node不被gc
+struct NodeRef {
+    table: LuaTable,
+    node: PtrBox<LuaSocketNode>,
+}
 
 pub struct LuaSocketMgr {
     lvm: *mut lua_State,
+    dgrams: HashMap<u32, LuaTable>,
+    nodes: HashMap<u32, NodeRef>,
     socket_mgr: Rc<RefCell<SocketMgr>>,
     socket_router: Rc<RefCell<SocketRouter>>,
 }
@@ -25,52 +64,306 @@ impl LuaGc for LuaSocketMgr {}
 impl LuaSocketMgr {
     pub fn new(L: *mut lua_State, max_conn: usize) -> LuaSocketMgr {
         let mgr = SocketMgr::new(max_conn);
-        let weak = Rc::downgrade(&mgr);
         LuaSocketMgr {
-            socket_router: SocketRouter::new(weak),
+            socket_router: SocketRouter::new(),
             socket_mgr: mgr,
+            dgrams: HashMap::new(),
+            nodes: HashMap::new(),
             lvm: L
         }
     }
 
-    pub fn map_token(&self, node_id: u32, token: u32) -> u32 {
-        self.socket_router.borrow_mut().map_token(node_id, token)
+    //可选参数: group, region
+    pub fn map_token(&mut self, L: *mut lua_State, node_id: u32, token: u32) -> int {
+        let group = lua::luaL_optinteger(L, 3, 0) as u16;
+        let region = lua::luaL_optinteger(L, 4, 0) as u16;
+        let master = self.socket_router.borrow_mut().map_token(node_id, token, group, region);
+        luakit::variadic_return!(L, master)
+    }
+
+    pub fn set_master_policy(&self, service_id: u8, policy: i32, designated: u32) -> u32 {
+        self.socket_router.borrow_mut().set_master_policy(service_id, MasterPolicy::from(policy as isize), designated)
+    }
+
+    pub fn set_hash_mode(&self, service_id: u8, mode: i32) {
+        self.socket_router.borrow_mut().set_hash_mode(service_id, HashMode::from(mode as isize));
+    }
+
+    pub fn set_node_weight(&self, node_id: u32, weight: u32) -> u32 {
+        self.socket_router.borrow_mut().set_node_weight(node_id, weight)
+    }
+
+    pub fn get_master(&self, service_id: u8, region: u16) -> u32 {
+        self.socket_router.borrow().get_master(service_id, region)
+    }
+
+    //汇总统计，nodes为各连接明细
+    pub fn stats(&self, L: *mut lua_State) -> int {
+        let mgr = self.socket_mgr.borrow();
+        let nodes = mgr.all_stats();
+        let mut total = SocketStat::default();
+        for stat in nodes.iter() {
+            total.bytes_in += stat.bytes_in;
+            total.bytes_out += stat.bytes_out;
+            total.packets_in += stat.packets_in;
+            total.packets_out += stat.packets_out;
+            total.send_queue += stat.send_queue;
+            total.accepted += stat.accepted;
+            total.rejected += stat.rejected;
+            total.errors += stat.errors;
+        }
+        unsafe { lua::lua_createtable(L, 0, 13); }
+        set_field(L, "count", mgr.get_count());
+        set_field(L, "bytes_in", total.bytes_in);
+        set_field(L, "bytes_out", total.bytes_out);
+        set_field(L, "packets_in", total.packets_in);
+        set_field(L, "packets_out", total.packets_out);
+        set_field(L, "send_queue", total.send_queue);
+        set_field(L, "accepted", total.accepted);
+        set_field(L, "rejected", total.rejected + mgr.get_rejected());
+        set_field(L, "errors", total.errors);
+        set_field(L, "route_total", self.socket_router.borrow().get_route_total());
+        set_field(L, "deferred", mgr.get_deferred());
+        unsafe { lua::lua_createtable(L, 0, nodes.len() as i32); }
+        for stat in nodes {
+            stat.token.native_to_lua(L);
+            stat.native_to_lua(L);
+            unsafe { lua::lua_settable(L, -3); }
+        }
+        unsafe { lua::lua_setfield(L, -2, lua::to_char!("nodes")); }
+        1
     }
 
     pub fn wait(&mut self, now: u64, timeout: u64) -> u32 {
-        self.socket_mgr.borrow_mut().wait(now, timeout)
+        let count = self.socket_mgr.borrow_mut().wait(now, timeout);
+        self.dispatch_node_events();
+        self.dispatch_datagrams();
+        self.dispatch_expired_calls();
+        count
+    }
+
+    //ptype参数位置由调用方指定
+    fn push_node(&mut self, L: *mut lua_State, token: u32, pidx: int) -> int {
+        let ptype = lua::luaL_optinteger(L, pidx, Prototype::ProtoRpc.into());
+        self.new_node(L, token, ptype.into());
+        "ok".native_to_lua(L);
+        2
+    }
+
+    //创建node并压栈
+    fn new_node(&mut self, L: *mut lua_State, token: u32, ptype: Prototype) {
+        let mgr = Rc::downgrade(&self.socket_mgr);
+        let router = Rc::downgrade(&self.socket_router);
+        let node = PtrBox::new(LuaSocketNode::new(token, self.lvm, mgr, router, ptype));
+        node.clone().native_to_lua(L);
+        self.nodes.insert(token, NodeRef { table: LuaTable::load(L, -1), node: node });
+    }
+
+    //accept的连接沿用监听者的协议类型，回调监听者的on_accept(session)
+    fn on_accept(&mut self, listener: u32, token: u32) {
+        let ptype = match self.nodes.get(&listener) {
+            Some(lnode) => lnode.node.ptype,
+            None => {
+                self.socket_mgr.borrow_mut().close(token);
+                return;
+            },
+        };
+        let L = self.lvm;
+        let _gl = LuaGuard::new(L);
+        self.new_node(L, token, ptype);
+        //pb编解码器同样沿用监听者的设置
+        let codec = self.nodes.get_mut(&listener).and_then(|lnode| lnode.node.pb_codec.take());
+        if let Some(codec) = codec {
+            codec.native_to_lua(L);
+            if let Some(lnode) = self.nodes.get_mut(&listener) {
+                lnode.node.pb_codec = Some(LuaTable::load(L, -1));
+            }
+            if let Some(session) = self.nodes.get_mut(&token) {
+                session.node.pb_codec = Some(LuaTable::load(L, -1));
+            }
+            lua::lua_pop(L, 1);
+        }
+        if let Some(lnode) = self.nodes.get_mut(&listener) {
+            if lnode.table.get_function("on_accept") {
+                unsafe { lua::lua_pushvalue(L, -2); }
+                if let Err(e) = luakit::lua_call_function(L, 1, 0) {
+                    println!("socket on_accept error: {}", e);
+                }
+            }
+        }
+    }
+
+    //I/O线程数，需在listen之前设置
+    pub fn set_io_threads(&self, L: *mut lua_State, count: usize) -> int {
+        let res = self.socket_mgr.borrow_mut().set_io_threads(count);
+        match res {
+            Ok(_) => luakit::variadic_return!(L, true),
+            Err(e) => luakit::variadic_return!(L, false, e),
+        }
+    }
+
+    //每次wait的派发预算，0表示不限
+    pub fn set_dispatch_budget(&self, packets: usize, time: u64) {
+        self.socket_mgr.borrow_mut().set_dispatch_budget(packets, time);
+    }
+
+    pub fn get_deferred(&self) -> usize {
+        self.socket_mgr.borrow().get_deferred()
+    }
+
+    //原生超时的rpc回调on_call_timeout(session_id, reason)，未设置回调时忽略
+    fn dispatch_expired_calls(&mut self) {
+        let expired = self.socket_mgr.borrow_mut().take_expired_calls();
+        for (token, session_id, reason) in expired {
+            if let Some(node) = self.nodes.get_mut(&token) {
+                let _gl = LuaGuard::new(self.lvm);
+                if node.table.get_function("on_call_timeout") {
+                    session_id.native_to_lua(self.lvm);
+                    reason.native_to_lua(self.lvm);
+                    if let Err(e) = luakit::lua_call_function(self.lvm, 2, 0) {
+                        println!("rpc on_call_timeout error: {}", e);
+                    }
+                }
+            }
+        }
+        if !self.nodes.is_empty() {
+            let mgr = self.socket_mgr.borrow();
+            self.nodes.retain(|token, _| mgr.get_object(*token).is_some());
+        }
+    }
+
+    //连接事件在wait结束后统一派发
+    fn dispatch_node_events(&mut self) {
+        let events = self.socket_mgr.borrow_mut().take_node_events();
+        for event in events {
+            match event {
+                NodeEvent::Accept(listener, token) => self.on_accept(listener, token),
+                NodeEvent::Package(token, data) => {
+                    if let Some(nref) = self.nodes.get_mut(&token) {
+                        nref.node.on_recv(&mut nref.table, &data);
+                    }
+                },
+                NodeEvent::Connect(token, res) => {
+                    if let Some(nref) = self.nodes.get_mut(&token) {
+                        let _gl = LuaGuard::new(self.lvm);
+                        if let Err(e) = nref.table.call1("on_connect", 0, res) {
+                            println!("socket on_connect error: {}", e);
+                        }
+                    }
+                },
+                NodeEvent::Peer(token, ip, port) => {
+                    if let Some(nref) = self.nodes.get_mut(&token) {
+                        nref.node.set_peer(ip, port);
+                    }
+                },
+                NodeEvent::Error(token, err) => {
+                    if let Some(nref) = self.nodes.get_mut(&token) {
+                        let _gl = LuaGuard::new(self.lvm);
+                        if let Err(e) = nref.table.call2("on_error", 0, token, err) {
+                            println!("socket on_error error: {}", e);
+                        }
+                    }
+                },
+                NodeEvent::Backpressure(token, paused) => {
+                    if let Some(node) = self.nodes.get_mut(&token) {
+                        let _gl = LuaGuard::new(self.lvm);
+                        if let Err(e) = node.table.call1("on_backpressure", 0, paused) {
+                            println!("socket on_backpressure error: {}", e);
+                        }
+                    }
+                },
+                //on_late_response(session_id, late)，未设置回调时忽略
+                NodeEvent::LateResponse(token, session_id, late) => {
+                    if let Some(node) = self.nodes.get_mut(&token) {
+                        let _gl = LuaGuard::new(self.lvm);
+                        if node.table.get_function("on_late_response") {
+                            session_id.native_to_lua(self.lvm);
+                            late.native_to_lua(self.lvm);
+                            if let Err(e) = luakit::lua_call_function(self.lvm, 2, 0) {
+                                println!("socket on_late_response error: {}", e);
+                            }
+                        }
+                    }
+                },
+            }
+        }
+    }
+
+    //udp数据在wait结束后统一派发，避免回调中重入socket_mgr
+    fn dispatch_datagrams(&mut self) {
+        let datagrams = self.socket_mgr.borrow_mut().take_datagrams();
+        for dgram in datagrams {
+            if let Some(node) = self.dgrams.get_mut(&dgram.token) {
+                let _gl = LuaGuard::new(self.lvm);
+                let ip = dgram.addr.ip().to_string();
+                if let Err(e) = node.call3("on_recv", 0, dgram.data, ip, dgram.addr.port()) {
+                    println!("udp on_recv error: {}", e);
+                }
+            }
+        }
+        if !self.dgrams.is_empty() {
+            let mgr = self.socket_mgr.borrow();
+            self.dgrams.retain(|token, _| mgr.get_object(*token).is_some());
+        }
     }
     
     pub fn listen(&mut self, L: *mut lua_State, ip: String, port: u32) -> int {
-        match self.socket_mgr.borrow_mut().listen(ip, port) {
+        let res = self.socket_mgr.borrow_mut().listen(ip, port);
+        match res {
             Err(e) => {
                 luakit::variadic_return!(L, lua::LUA_NIL, e)
             },
-            Ok(token) => {
-                let mgr = Rc::downgrade(&self.socket_mgr);
-                let router = Rc::downgrade(&self.socket_router);
-                let ptype = lua::luaL_optinteger(L, 3, Prototype::ProtoRpc.into());
-                let node = LuaSocketNode::new(token, self.lvm, mgr, router, ptype.into());
-                luakit::variadic_return!(L, PtrBox::new(node), "ok")
-            },
+            Ok(token) => self.push_node(L, token, 3),
         }
     }
 
     pub fn connect(&mut self, L: *mut lua_State, ip: String, port: u32, timeout: u64) -> int {
-        match self.socket_mgr.borrow_mut().connect(ip, port, timeout) {
+        let res = self.socket_mgr.borrow_mut().connect(ip, port, timeout);
+        match res {
+            Err(e) => {
+                luakit::variadic_return!(L, lua::LUA_NIL, e)
+            },
+            Ok(token) => self.push_node(L, token, 4),
+        }
+    }
+    
+    pub fn listen_kcp(&mut self, L: *mut lua_State, ip: String, port: u32) -> int {
+        let res = self.socket_mgr.borrow_mut().listen_kcp(ip, port);
+        match res {
+            Err(e) => {
+                luakit::variadic_return!(L, lua::LUA_NIL, e)
+            },
+            Ok(token) => self.push_node(L, token, 3),
+        }
+    }
+
+    pub fn connect_kcp(&mut self, L: *mut lua_State, ip: String, port: u32, timeout: u64) -> int {
+        let res = self.socket_mgr.borrow_mut().connect_kcp(ip, port, timeout);
+        match res {
+            Err(e) => {
+                luakit::variadic_return!(L, lua::LUA_NIL, e)
+            },
+            Ok(token) => self.push_node(L, token, 4),
+        }
+    }
+
+    pub fn bind_udp(&mut self, L: *mut lua_State, ip: String, port: u32) -> int {
+        let res = self.socket_mgr.borrow_mut().bind_udp(ip, port);
+        match res {
             Err(e) => {
                 luakit::variadic_return!(L, lua::LUA_NIL, e)
             },
             Ok(token) => {
                 let mgr = Rc::downgrade(&self.socket_mgr);
-                let router = Rc::downgrade(&self.socket_router);
-                let ptype = lua::luaL_optinteger(L, 4, Prototype::ProtoRpc.into());
-                let node = LuaSocketNode::new(token, self.lvm, mgr, router, ptype.into());
-                luakit::variadic_return!(L, PtrBox::new(node), "ok")
+                let dgram = LuaSocketDgram::new(token, mgr);
+                PtrBox::new(dgram).native_to_lua(L);
+                self.dgrams.insert(token, LuaTable::load(L, -1));
+                "ok".native_to_lua(L);
+                2
             },
         }
     }
-    
+
     pub fn broadcast(&mut self, kind: u32, data: &[u8]) {
         self.socket_mgr.borrow_mut().broadcast(kind, data);
     }
@@ -7,44 +7,83 @@ use libc::c_int as int;
 use std::rc::Weak;
 use std::cell::RefCell;
 
-use luakit::{LuaGc, Luakit};
+use luakit::{ Codec, LuaCodec, LuaGc, LuaGuard, Luakit, LuaPush, LuaTable, Slice };
 
-use crate::socket_mgr::{ SocketMgr, Prototype };
-use crate::socket_router::SocketRouter;
+use crate::socket_filter::Cidr;
+use crate::socket_codec::{ PacketCodec, CompressMode, EncryptMode, FLAG_ZIP, FLAG_ENCRYPT };
+use crate::socket_mgr::{ SocketMgr, SocketStat, Prototype };
+use crate::socket_router::{ SocketRouter, RouterHeaader, RpcType, ROUTER_HEADER_LEN };
+
+//与luapb的PbHeader一致: len(4) cmd_id(2) flag(1) type(1) crc8(1) session_id(2)
+const PB_HEADER_LEN: usize  = 11;
+const PB_FLAG_OFFSET: usize = 6;
 
 pub struct LuaSocketNode {
-    sindex: u16,
+    sindex: u32,
     luavm: Luakit,
-    ptype: Prototype,
+    codec: PacketCodec,
+    pub pb_codec: Option<LuaTable>,
     socket_mgr: Weak<RefCell<SocketMgr>>,
     socket_router: Weak<RefCell<SocketRouter>>,
+    pub ptype: Prototype,
     pub token: u32,
     pub stoken: u32,
     pub ip: String,
+    pub port: u16,
 }
 
 impl LuaGc for LuaSocketNode {}
 
 impl LuaSocketNode {
     pub fn new(token: u32, L: *mut lua_State, mgr: Weak<RefCell<SocketMgr>>, router: Weak<RefCell<SocketRouter>>, ptype: Prototype) -> LuaSocketNode {
+        let ((ip, port), stoken) = match mgr.upgrade() {
+            Some(socket_mgr) => {
+                let mut socket_mgr = socket_mgr.borrow_mut();
+                (socket_mgr.peer_addr(token).unwrap_or_default(), socket_mgr.alloc_stoken(token))
+            },
+            None => (("".to_string(), 0), 0),
+        };
         LuaSocketNode {
-            sindex : 1,
+            sindex : 0,
             ptype: ptype,
             token : token,
+            codec: PacketCodec::default(),
+            pb_codec: None,
             socket_mgr: mgr,
-            ip: "".to_string(),
+            ip: ip,
+            port: port,
             socket_router: router,
             luavm : Luakit::load(L),
-            stoken : (token & 0xffff) << 16,
+            stoken : stoken,
         }
     }
 
     pub fn close(&self) {
+        if let Some(socket_mgr) = self.socket_mgr.upgrade() {
+            socket_mgr.borrow_mut().close(self.token);
+        }
     }
 
-    pub fn build_session_id(&mut self) -> u32 {
-        self.sindex += 1;
-        self.stoken | self.sindex as u32
+    //无可用session_id时返回nil
+    pub fn build_session_id(&mut self) -> Option<u32> {
+        if let Some(socket_mgr) = self.socket_mgr.upgrade() {
+            return socket_mgr.borrow().build_session_id(self.token, self.stoken, &mut self.sindex);
+        }
+        None
+    }
+
+    //可选的原生超时跟踪，超时后回调on_call_timeout(session_id, reason)
+    pub fn track_call(&self, session_id: u32, timeout: u64) {
+        if let Some(socket_mgr) = self.socket_mgr.upgrade() {
+            socket_mgr.borrow_mut().track_call(self.token, session_id, timeout);
+        }
+    }
+
+    pub fn finish_call(&self, session_id: u32) -> bool {
+        if let Some(socket_mgr) = self.socket_mgr.upgrade() {
+            return socket_mgr.borrow_mut().finish_call(self.token, session_id);
+        }
+        false
     }
 
     pub fn get_route_count(&self) -> u32 {
@@ -53,29 +92,352 @@ impl LuaSocketNode {
         }
         0
     }
+
+    pub fn stats(&self) -> Option<SocketStat> {
+        if let Some(socket_mgr) = self.socket_mgr.upgrade() {
+            return socket_mgr.borrow().stats(self.token);
+        }
+        None
+    }
+
+    //PROXY协议解析后刷新为真实对端地址
+    pub fn set_peer(&mut self, ip: String, port: u16) {
+        self.ip = ip;
+        self.port = port;
+    }
+
+    pub fn set_proxy_protocol(&self, enable: bool) {
+        if let Some(socket_mgr) = self.socket_mgr.upgrade() {
+            socket_mgr.borrow_mut().set_proxy_protocol(self.token, enable);
+        }
+    }
+
+    pub fn add_allow(&self, cidr: String) -> bool {
+        self.add_filter(cidr, true)
+    }
+
+    pub fn add_deny(&self, cidr: String) -> bool {
+        self.add_filter(cidr, false)
+    }
+
+    pub fn clear_filter(&self) {
+        if let Some(socket_mgr) = self.socket_mgr.upgrade() {
+            socket_mgr.borrow_mut().clear_filter(self.token);
+        }
+    }
+
+    pub fn set_rate_limit(&self, count: u32, period: u64) {
+        if let Some(socket_mgr) = self.socket_mgr.upgrade() {
+            socket_mgr.borrow_mut().set_rate_limit(self.token, count, period);
+        }
+    }
+
+    fn add_filter(&self, cidr: String, allow: bool) -> bool {
+        let cidr = match Cidr::parse(&cidr) {
+            Ok(cidr) => cidr,
+            Err(_) => return false,
+        };
+        if let Some(socket_mgr) = self.socket_mgr.upgrade() {
+            socket_mgr.borrow_mut().add_filter(self.token, cidr, allow);
+            return true;
+        }
+        false
+    }
+
+    //mode: 0关闭，1为lz4，2为zstd；包体不小于threshold时压缩
+    pub fn set_compress(&mut self, mode: i32, threshold: usize) {
+        self.codec.set_compress(CompressMode::from(mode), threshold);
+    }
+
+    //mode: 0关闭，1为xxtea，2为aes；密钥由登录流程协商后设置
+    pub fn set_encrypt(&mut self, L: *mut lua_State, mode: i32, key: Vec<u8>) -> int {
+        match self.codec.set_encrypt(EncryptMode::from(mode), &key) {
+            Ok(_) => luakit::variadic_return!(L, true),
+            Err(e) => luakit::variadic_return!(L, false, e),
+        }
+    }
+
     pub fn set_timeout(&self, ms: u64) {
         if let Some(socket_mgr) = self.socket_mgr.upgrade() {
             return socket_mgr.borrow_mut().set_timeout(self.token, ms);
         }
     }
 
+    pub fn set_max_packet(&mut self, size: usize) {
+        self.codec.set_max_packet(size);
+        if let Some(socket_mgr) = self.socket_mgr.upgrade() {
+            return socket_mgr.borrow_mut().set_max_packet(self.token, size);
+        }
+    }
+
+    //backpressure为true时超过高水位回调on_backpressure(true)，降到低水位回调on_backpressure(false)
+    pub fn set_flow_control(&self, high: usize, low: usize, backpressure: bool) {
+        if let Some(socket_mgr) = self.socket_mgr.upgrade() {
+            return socket_mgr.borrow_mut().set_flow_control(self.token, high, low, backpressure);
+        }
+    }
+
+    pub fn set_kcp_nodelay(&self, nodelay: i32, interval: i32, resend: i32, nc: i32) {
+        if let Some(socket_mgr) = self.socket_mgr.upgrade() {
+            return socket_mgr.borrow_mut().set_kcp_nodelay(self.token, nodelay, interval, resend, nc);
+        }
+    }
+
+    pub fn set_kcp_window(&self, sndwnd: u32, rcvwnd: u32) {
+        if let Some(socket_mgr) = self.socket_mgr.upgrade() {
+            return socket_mgr.borrow_mut().set_kcp_window(self.token, sndwnd, rcvwnd);
+        }
+    }
+
     pub fn set_nodelay(&self, enable: bool) {
         if let Some(socket_mgr) = self.socket_mgr.upgrade() {
             return socket_mgr.borrow_mut().set_nodelay(self.token, enable);
         }
     }
 
-    pub fn call_pb(&mut self, L: *mut lua_State) -> int{
-        unsafe { lua::lua_pushinteger(L, 0) };
-        1
+    //lua: set_codec(codec)，pb协议由luapb的pbcodec负责包头和消息编解码
+    pub fn set_codec(&mut self, L: *mut lua_State) -> int {
+        self.pb_codec = Some(LuaTable::load(L, 1));
+        0
+    }
+
+    //lua: call_pb(session_id, cmd_id, flag, type, crc8, body)，返回发送的包长度
+    pub fn call_pb(&mut self, L: *mut lua_State) -> int {
+        let packet = match self.encode_pb(L) {
+            Ok(packet) => packet,
+            Err(e) => return luakit::variadic_return!(L, 0, e),
+        };
+        if let Some(socket_mgr) = self.socket_mgr.upgrade() {
+            socket_mgr.borrow_mut().send(self.token, &packet);
+            return luakit::variadic_return!(L, packet.len());
+        }
+        luakit::variadic_return!(L, 0)
+    }
+
+    //先由pbcodec编码整包，再按连接配置压缩加密包体并改写包头
+    fn encode_pb(&mut self, L: *mut lua_State) -> Result<Vec<u8>, String> {
+        let codec = self.pb_codec.as_mut().ok_or("pb codec not set".to_string())?;
+        let top = unsafe { lua::lua_gettop(L) };
+        let _gl = LuaGuard::new(L);
+        if !codec.get_function("encode") {
+            return Err("pb codec encode not found".to_string());
+        }
+        for index in 1..=top {
+            unsafe { lua::lua_pushvalue(L, index) };
+        }
+        luakit::lua_call_function(L, top, 2)?;
+        if unsafe { lua::lua_type(L, -2) } != lua::LUA_TSTRING {
+            let err: Option<String> = luakit::LuaRead::lua_to_native(L, -1);
+            return Err(err.unwrap_or("pb encode failed".to_string()));
+        }
+        let packet: Vec<u8> = luakit::LuaRead::lua_to_native(L, -2).unwrap_or_default();
+        if packet.len() < PB_HEADER_LEN {
+            return Err("pb packet too short".to_string());
+        }
+        let (flag, body) = self.codec.encode(packet[PB_FLAG_OFFSET], &packet[PB_HEADER_LEN..])?;
+        Ok(rebuild_pb(&packet, flag, &body))
+    }
+
+    //包体按连接配置压缩和加密，flag中带上ZIP/ENCRYPT标记
+    pub fn call_data(&mut self, L: *mut lua_State, session_id: u32, flag: u8, data: &[u8]) -> int{
+        let (flag, body) = match self.codec.encode(flag, data) {
+            Ok(res) => res,
+            Err(e) => return luakit::variadic_return!(L, 0, e),
+        };
+        let header = RouterHeaader::new((RpcType::RemoteCall as u8) << 4 | flag, session_id, 0, body.len());
+        if let Some(socket_mgr) = self.socket_mgr.upgrade() {
+            socket_mgr.borrow_mut().sendv(self.token, &vec![header.as_bytes(), &body]);
+            return luakit::variadic_return!(L, body.len());
+        }
+        luakit::variadic_return!(L, 0)
+    }
+    //lua: call(session_id, flag, ...)，参数按luakit编码，返回发送的包体长度
+    pub fn call(&mut self, L: *mut lua_State, session_id: u32, flag: u8) -> int{
+        let body = LuaCodec::new().encode(L, 3);
+        let (flag, body) = match self.codec.encode(flag, &body) {
+            Ok(res) => res,
+            Err(e) => return luakit::variadic_return!(L, 0, e),
+        };
+        let header = RouterHeaader::new((RpcType::RemoteCall as u8) << 4 | flag, session_id, 0, body.len());
+        if let Some(socket_mgr) = self.socket_mgr.upgrade() {
+            socket_mgr.borrow_mut().sendv(self.token, &vec![header.as_bytes(), &body]);
+            return luakit::variadic_return!(L, body.len());
+        }
+        luakit::variadic_return!(L, 0)
+    }
+
+    //转发包由router原样转发，包体不做压缩加密
+    //lua: forward_target(session_id, flag, target_id, ...)
+    pub fn forward_target(&mut self, L: *mut lua_State, session_id: u32, flag: u8, target_id: u32) -> int {
+        self.forward(L, RpcType::ForwardTarget, session_id, flag, target_id, 4)
     }
-    pub fn call_data(&mut self, L: *mut lua_State) -> int{
-        unsafe { lua::lua_pushinteger(L, 0) };
-        1
+
+    //lua: forward_master(session_id, flag, service_id, ...)
+    pub fn forward_master(&mut self, L: *mut lua_State, session_id: u32, flag: u8, service_id: u8) -> int {
+        self.forward(L, RpcType::ForwardMaster, session_id, flag, service_id as u32, 4)
     }
-    pub fn call(&mut self, L: *mut lua_State, _session_id: u32, _flag: u8) -> int{
-        unsafe { lua::lua_pushinteger(L, 0) };
-        1
+
+    //lua: forward_broadcast(session_id, flag, service_id, ...)
+    pub fn forward_broadcast(&mut self, L: *mut lua_State, session_id: u32, flag: u8, service_id: u8) -> int {
+        self.forward(L, RpcType::ForwardBroadcast, session_id, flag, service_id as u32, 4)
+    }
+
+    //lua: forward_hash(session_id, flag, service_id, hash, ...)
+    pub fn forward_hash(&mut self, L: *mut lua_State, session_id: u32, flag: u8, service_id: u8, hash: u16) -> int {
+        self.forward(L, RpcType::ForwardHash, session_id, flag, (service_id as u32) << 16 | hash as u32, 5)
+    }
+
+    //lua: forward_region_master(session_id, flag, service_id, region, ...)
+    pub fn forward_region_master(&mut self, L: *mut lua_State, session_id: u32, flag: u8, service_id: u8, region: u16) -> int {
+        self.forward(L, RpcType::ForwardRegionMaster, session_id, flag, (region as u32) << 16 | service_id as u32, 5)
+    }
+
+    //lua: forward_group(session_id, flag, service_id, group, ...)
+    pub fn forward_group(&mut self, L: *mut lua_State, session_id: u32, flag: u8, service_id: u8, group: u16) -> int {
+        self.forward(L, RpcType::ForwardGroup, session_id, flag, (group as u32) << 16 | service_id as u32, 5)
     }
 
-}
\ No newline at end of file
+    //参数从index开始按luakit编码，由router按rpc_type转发
+    fn forward(&mut self, L: *mut lua_State, rpc_type: RpcType, session_id: u32, flag: u8, target_id: u32, index: i32) -> int {
+        let body = LuaCodec::new().encode(L, index);
+        let header = RouterHeaader::new((rpc_type as u8) << 4 | flag, session_id, target_id, body.len());
+        if let Some(socket_mgr) = self.socket_mgr.upgrade() {
+            socket_mgr.borrow_mut().sendv(self.token, &vec![header.as_bytes(), &body]);
+            return luakit::variadic_return!(L, body.len());
+        }
+        luakit::variadic_return!(L, 0)
+    }
+
+    //收到完整包，按协议类型回调lua
+    pub fn on_recv(&mut self, node: &mut LuaTable, data: &[u8]) {
+        match self.ptype {
+            Prototype::ProtoRpc => self.on_rpc(node, data),
+            Prototype::ProtoPb => self.on_pb(node, data),
+            _ => {},
+        }
+    }
+
+    //on_call(recv_len, session_id, flag, ...)
+    fn on_rpc(&mut self, node: &mut LuaTable, data: &[u8]) {
+        let header = match RouterHeaader::read(data) {
+            Some(header) => header,
+            None => return,
+        };
+        if header.rpc_type() == RpcType::RouteError as u8 {
+            return self.on_route_error(node, header, data);
+        }
+        if header.rpc_type() != RpcType::RemoteCall as u8 {
+            return self.on_forward(node, header, data);
+        }
+        let L = self.luavm.L();
+        let _gl = LuaGuard::new(L);
+        if !node.get_function("on_call") {
+            return;
+        }
+        let body = match self.codec.decode(header.flag(), &data[ROUTER_HEADER_LEN..]) {
+            Ok(body) => body,
+            Err(e) => return println!("rpc decode error: {}", e),
+        };
+        let session_id = header.session_id;
+        data.len().native_to_lua(L);
+        session_id.native_to_lua(L);
+        (header.flag() & !(FLAG_ZIP | FLAG_ENCRYPT)).native_to_lua(L);
+        let mut slice = Slice::attach(&body);
+        let res = match luakit::decode_slice(L, &mut slice) {
+            Ok(argc) => luakit::lua_call_function(L, argc + 3, 0),
+            Err(e) => Err(e.to_string()),
+        };
+        if let Err(e) = res {
+            println!("rpc on_call error: {}", e);
+        }
+    }
+
+    //on_call_pb(recv_len, session_id, cmd_id, flag, type, crc8, body[, err])
+    fn on_pb(&mut self, node: &mut LuaTable, data: &[u8]) {
+        if data.len() < PB_HEADER_LEN {
+            return;
+        }
+        //先还原压缩加密的包体，再交给pbcodec解码
+        let flag = data[PB_FLAG_OFFSET];
+        let packet = match self.codec.decode(flag, &data[PB_HEADER_LEN..]) {
+            Ok(body) => rebuild_pb(data, flag & !(FLAG_ZIP | FLAG_ENCRYPT), &body),
+            Err(e) => return println!("pb decode error: {}", e),
+        };
+        let codec = match self.pb_codec.as_mut() {
+            Some(codec) => codec,
+            None => return,
+        };
+        let L = self.luavm.L();
+        let _gl = LuaGuard::new(L);
+        if !node.get_function("on_call_pb") || !codec.get_function("decode") {
+            return;
+        }
+        packet.as_slice().native_to_lua(L);
+        if let Err(e) = luakit::lua_call_function(L, 1, 8) {
+            return println!("pb decode error: {}", e);
+        }
+        if lua::lua_isnil(L, -8) {
+            let err: Option<String> = luakit::LuaRead::lua_to_native(L, -7);
+            return println!("pb decode error: {}", err.unwrap_or_default());
+        }
+        if let Err(e) = luakit::lua_call_function(L, 8, 0) {
+            println!("pb on_call_pb error: {}", e);
+        }
+    }
+
+    //router转发失败的回包，target_id为错误码，包体为原因
+    //on_route_error(session_id, code, reason)
+    fn on_route_error(&mut self, node: &mut LuaTable, header: RouterHeaader, data: &[u8]) {
+        let L = self.luavm.L();
+        let _gl = LuaGuard::new(L);
+        let reason = String::from_utf8_lossy(&data[ROUTER_HEADER_LEN..]).to_string();
+        if let Err(e) = node.call3("on_route_error", 0, header.session_id, header.target_id, reason) {
+            println!("rpc on_route_error error: {}", e);
+        }
+    }
+
+    //router收到的转发包，失败时router直接给来源回RouteError
+    fn on_forward(&mut self, node: &mut LuaTable, mut header: RouterHeaader, data: &[u8]) {
+        let (router, socket_mgr) = match (self.socket_router.upgrade(), self.socket_mgr.upgrade()) {
+            (Some(router), Some(socket_mgr)) => (router, socket_mgr),
+            _ => return,
+        };
+        let body = &data[ROUTER_HEADER_LEN..];
+        let mut broadcast_num = 0;
+        let rpc_type = header.rpc_type();
+        let mut mgr = socket_mgr.borrow_mut();
+        let mut router = router.borrow_mut();
+        let broadcast = match rpc_type {
+            t if t == RpcType::ForwardTarget as u8 => { router.do_forward_target(&mut mgr, &mut header, self.token, body); false },
+            t if t == RpcType::ForwardMaster as u8 => { router.do_forward_master(&mut mgr, &mut header, self.token, body); false },
+            t if t == RpcType::ForwardHash as u8 => { router.do_forward_hash(&mut mgr, &mut header, self.token, body); false },
+            t if t == RpcType::ForwardRegionMaster as u8 => { router.do_forward_region_master(&mut mgr, &mut header, self.token, body); false },
+            t if t == RpcType::ForwardBroadcast as u8 => router.do_forward_broadcast(&mut mgr, &mut header, self.token, body, &mut broadcast_num),
+            t if t == RpcType::ForwardGroup as u8 => router.do_forward_group(&mut mgr, &mut header, self.token, body, &mut broadcast_num),
+            _ => false,
+        };
+        drop(mgr);
+        drop(router);
+        //广播成功后告知来源送达数量
+        if broadcast && header.session_id > 0 {
+            let L = self.luavm.L();
+            let _gl = LuaGuard::new(L);
+            if let Err(e) = node.call2("on_forward_broadcast", 0, header.session_id, broadcast_num) {
+                println!("rpc on_forward_broadcast error: {}", e);
+            }
+        }
+    }
+
+}
+
+//替换pb包的flag和包体，len按包体长度的变化同步调整
+fn rebuild_pb(packet: &[u8], flag: u8, body: &[u8]) -> Vec<u8> {
+    let old_len = u32::from_ne_bytes([packet[0], packet[1], packet[2], packet[3]]);
+    let len = (old_len as usize + body.len()).saturating_sub(packet.len() - PB_HEADER_LEN);
+    let mut data = Vec::with_capacity(PB_HEADER_LEN + body.len());
+    data.extend_from_slice(&(len as u32).to_ne_bytes());
+    data.extend_from_slice(&packet[4..PB_HEADER_LEN]);
+    data[PB_FLAG_OFFSET] = flag;
+    data.extend_from_slice(body);
+    data
+}
@@ -0,0 +1,349 @@
+#![allow(non_snake_case)]
+#![allow(dead_code)]
+
+use std::io::Read;
+
+use zstd::stream::{ Decoder, encode_all };
+use lz4::block::{ compress, decompress, CompressionMode };
+use ring::rand::{ SecureRandom, SystemRandom };
+use ring::aead::{ Aad, LessSafeKey, Nonce, UnboundKey, AES_128_GCM, AES_256_GCM, NONCE_LEN };
+
+//与script/constant.lua中的FlagMask保持一致
+pub const FLAG_ENCRYPT: u8  = 0x04;
+pub const FLAG_ZIP: u8      = 0x08;
+
+const ZSTD_LEVEL: i32       = 3;
+//xxtea最少加密2个32位字
+const XXTEA_MIN_LEN: usize  = 8;
+
+//默认收包上限，与luakit的BaseCodec一致
+pub const MAX_PACKET_SIZE: usize = 0xffffff;
+
+//luabus的分包: 包头4字节(本机字节序)为后续数据长度，与RouterHeaader.len一致
+//返回整包长度，0表示数据不足，-1表示超出max_packet(0为默认上限)
+pub fn load_packet(data: &[u8], max_packet: usize) -> i32 {
+    if data.len() < 4 {
+        return 0;
+    }
+    let len = u32::from_ne_bytes([data[0], data[1], data[2], data[3]]) as usize;
+    let max_packet = if max_packet > 0 { max_packet } else { MAX_PACKET_SIZE };
+    if len > max_packet {
+        return -1;
+    }
+    if len + 4 > data.len() {
+        return 0;
+    }
+    (len + 4) as i32
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompressMode {
+    None    = 0,
+    Lz4     = 1,
+    Zstd    = 2,
+}
+
+impl From<i32> for CompressMode {
+    fn from(val: i32) -> CompressMode {
+        match val {
+            1 => CompressMode::Lz4,
+            2 => CompressMode::Zstd,
+            _ => CompressMode::None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EncryptMode {
+    None    = 0,
+    Xxtea   = 1,
+    Aes     = 2,
+}
+
+impl From<i32> for EncryptMode {
+    fn from(val: i32) -> EncryptMode {
+        match val {
+            1 => EncryptMode::Xxtea,
+            2 => EncryptMode::Aes,
+            _ => EncryptMode::None,
+        }
+    }
+}
+
+enum Cipher {
+    None,
+    Xxtea(String),
+    Aes(LessSafeKey),
+}
+
+//连接级的包体压缩和加密，先压缩后加密
+//压缩数据首字节为算法标识，接收端不依赖本端的压缩配置
+pub struct PacketCodec {
+    threshold: usize,
+    max_packet: usize,
+    compress: CompressMode,
+    cipher: Cipher,
+}
+
+impl Default for PacketCodec {
+    fn default() -> Self {
+        PacketCodec { threshold: 0, max_packet: MAX_PACKET_SIZE, compress: CompressMode::None, cipher: Cipher::None }
+    }
+}
+
+impl PacketCodec {
+    //解压后的包体同样受max_packet限制，0为默认上限
+    pub fn set_max_packet(&mut self, size: usize) {
+        self.max_packet = if size > 0 { size } else { MAX_PACKET_SIZE };
+    }
+
+    pub fn set_compress(&mut self, mode: CompressMode, threshold: usize) {
+        self.compress = mode;
+        self.threshold = threshold;
+    }
+
+    //aes使用GCM模式，密钥长度16或32字节
+    pub fn set_encrypt(&mut self, mode: EncryptMode, key: &[u8]) -> Result<(), String> {
+        self.cipher = match mode {
+            EncryptMode::None => Cipher::None,
+            EncryptMode::Xxtea => {
+                let key = String::from_utf8(key.to_vec()).map_err(|_| "xxtea key must be utf8".to_string())?;
+                Cipher::Xxtea(key)
+            },
+            EncryptMode::Aes => {
+                let algorithm = match key.len() {
+                    16 => &AES_128_GCM,
+                    32 => &AES_256_GCM,
+                    _ => return Err(format!("invalid aes key length: {}", key.len())),
+                };
+                let key = UnboundKey::new(algorithm, key).map_err(|_| "invalid aes key".to_string())?;
+                Cipher::Aes(LessSafeKey::new(key))
+            },
+        };
+        Ok(())
+    }
+
+    pub fn is_encrypt(&self) -> bool {
+        !matches!(self.cipher, Cipher::None)
+    }
+
+    //返回追加了ZIP/ENCRYPT标记的flag以及编码后的包体
+    pub fn encode(&self, flag: u8, data: &[u8]) -> Result<(u8, Vec<u8>), String> {
+        let mut flag = flag & !(FLAG_ZIP | FLAG_ENCRYPT);
+        let mut body = None;
+        if self.compress != CompressMode::None && data.len() >= self.threshold {
+            let zipped = self.do_compress(data)?;
+            //压缩无收益时保持原样
+            if zipped.len() < data.len() {
+                flag |= FLAG_ZIP;
+                body = Some(zipped);
+            }
+        }
+        if self.is_encrypt() {
+            let plain = body.as_deref().unwrap_or(data);
+            flag |= FLAG_ENCRYPT;
+            body = Some(self.do_encrypt(plain)?);
+        }
+        Ok((flag, body.unwrap_or_else(|| data.to_vec())))
+    }
+
+    pub fn decode(&self, flag: u8, data: &[u8]) -> Result<Vec<u8>, String> {
+        let mut body = None;
+        if flag & FLAG_ENCRYPT != 0 {
+            body = Some(self.do_decrypt(data)?);
+        }
+        if flag & FLAG_ZIP != 0 {
+            let zipped = body.as_deref().unwrap_or(data);
+            body = Some(do_decompress(zipped, self.max_packet)?);
+        }
+        Ok(body.unwrap_or_else(|| data.to_vec()))
+    }
+
+    fn do_compress(&self, data: &[u8]) -> Result<Vec<u8>, String> {
+        let zipped = match self.compress {
+            CompressMode::Lz4 => compress(data, Some(CompressionMode::DEFAULT), true),
+            CompressMode::Zstd => encode_all(data, ZSTD_LEVEL),
+            CompressMode::None => return Ok(data.to_vec()),
+        };
+        let zipped = zipped.map_err(|e| e.to_string())?;
+        let mut body = Vec::with_capacity(zipped.len() + 1);
+        body.push(self.compress as u8);
+        body.extend_from_slice(&zipped);
+        Ok(body)
+    }
+
+    fn do_encrypt(&self, data: &[u8]) -> Result<Vec<u8>, String> {
+        match self.cipher {
+            Cipher::None => Ok(data.to_vec()),
+            Cipher::Xxtea(ref key) => {
+                //xxtea按4字节对齐补0且至少需要2个字，明文前加4字节小端长度，解密后据此截断
+                let mut plain = Vec::with_capacity(data.len() + 8);
+                plain.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                plain.extend_from_slice(data);
+                plain.resize(plain.len().max(XXTEA_MIN_LEN), 0);
+                Ok(xxtea::encrypt_raw(&plain, key))
+            },
+            Cipher::Aes(ref key) => {
+                //随机nonce放在密文前面
+                let mut nonce = [0u8; NONCE_LEN];
+                SystemRandom::new().fill(&mut nonce).map_err(|_| "aes nonce failed".to_string())?;
+                let mut body = data.to_vec();
+                key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut body)
+                    .map_err(|_| "aes encrypt failed".to_string())?;
+                let mut out = Vec::with_capacity(NONCE_LEN + body.len());
+                out.extend_from_slice(&nonce);
+                out.extend_from_slice(&body);
+                Ok(out)
+            },
+        }
+    }
+
+    fn do_decrypt(&self, data: &[u8]) -> Result<Vec<u8>, String> {
+        match self.cipher {
+            Cipher::None => Err("packet encrypted but no key".to_string()),
+            Cipher::Xxtea(ref key) => {
+                //空包或未对齐的包会让xxtea越界panic，先行拒绝
+                if data.len() < XXTEA_MIN_LEN || data.len() % 4 != 0 {
+                    return Err(format!("invalid xxtea packet length: {}", data.len()));
+                }
+                let mut body = xxtea::decrypt_raw(&data.to_vec(), key);
+                let len = u32::from_le_bytes([body[0], body[1], body[2], body[3]]) as usize;
+                if len > body.len() - 4 || body.len() != (len + 4).div_ceil(4).max(2) * 4 {
+                    return Err("xxtea decrypt failed".to_string());
+                }
+                body.drain(..4);
+                body.truncate(len);
+                Ok(body)
+            },
+            Cipher::Aes(ref key) => {
+                if data.len() < NONCE_LEN {
+                    return Err("aes packet too short".to_string());
+                }
+                let mut nonce = [0u8; NONCE_LEN];
+                nonce.copy_from_slice(&data[..NONCE_LEN]);
+                let mut body = data[NONCE_LEN..].to_vec();
+                let len = key.open_in_place(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut body)
+                    .map_err(|_| "aes decrypt failed".to_string())?.len();
+                body.truncate(len);
+                Ok(body)
+            },
+        }
+    }
+}
+
+//解压大小不能超过max_packet，不信任对端声明的长度
+fn do_decompress(data: &[u8], max_packet: usize) -> Result<Vec<u8>, String> {
+    let (mode, zipped) = match data.split_first() {
+        Some((mode, zipped)) => (CompressMode::from(*mode as i32), zipped),
+        None => return Err("zip packet empty".to_string()),
+    };
+    match mode {
+        CompressMode::Lz4 => {
+            //lz4前4字节为小端的原始长度
+            if zipped.len() < 4 {
+                return Err("lz4 packet too short".to_string());
+            }
+            let size = i32::from_le_bytes([zipped[0], zipped[1], zipped[2], zipped[3]]);
+            if size < 0 || size as usize > max_packet {
+                return Err(format!("unzip size overflow: {}", size));
+            }
+            decompress(&zipped[4..], Some(size)).map_err(|e| e.to_string())
+        },
+        CompressMode::Zstd => {
+            let decoder = Decoder::new(zipped).map_err(|e| e.to_string())?;
+            let mut body = Vec::new();
+            decoder.take(max_packet as u64 + 1).read_to_end(&mut body).map_err(|e| e.to_string())?;
+            if body.len() > max_packet {
+                return Err("unzip size overflow".to_string());
+            }
+            Ok(body)
+        },
+        CompressMode::None => Err("unknown zip mode".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zip_codec(mode: CompressMode, max_packet: usize) -> PacketCodec {
+        let mut codec = PacketCodec::default();
+        codec.set_compress(mode, 0);
+        codec.set_max_packet(max_packet);
+        codec
+    }
+
+    #[test]
+    fn unzip_round_trip() {
+        let data = vec![7u8; 4096];
+        for mode in [CompressMode::Lz4, CompressMode::Zstd] {
+            let codec = zip_codec(mode, 0);
+            let (flag, body) = codec.encode(0, &data).unwrap();
+            assert_eq!(flag & FLAG_ZIP, FLAG_ZIP);
+            assert_eq!(codec.decode(flag, &body).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn unzip_over_max_packet() {
+        let data = vec![7u8; 4096];
+        for mode in [CompressMode::Lz4, CompressMode::Zstd] {
+            let (flag, body) = zip_codec(mode, 0).encode(0, &data).unwrap();
+            assert!(zip_codec(mode, 1024).decode(flag, &body).is_err());
+        }
+    }
+
+    fn crypt_codec(mode: EncryptMode, key: &[u8]) -> PacketCodec {
+        let mut codec = PacketCodec::default();
+        codec.set_encrypt(mode, key).unwrap();
+        codec
+    }
+
+    fn crypt_codecs() -> Vec<PacketCodec> {
+        vec![
+            crypt_codec(EncryptMode::Xxtea, b"quanta-xxtea-key"),
+            crypt_codec(EncryptMode::Aes, &[3u8; 16]),
+            crypt_codec(EncryptMode::Aes, &[5u8; 32]),
+        ]
+    }
+
+    #[test]
+    fn encrypt_round_trip() {
+        for codec in crypt_codecs() {
+            for len in [0, 1, 5, 4096] {
+                let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+                let (flag, body) = codec.encode(0, &data).unwrap();
+                assert_eq!(flag & FLAG_ENCRYPT, FLAG_ENCRYPT);
+                assert_eq!(codec.decode(flag, &body).unwrap(), data);
+            }
+        }
+    }
+
+    #[test]
+    fn encrypt_zip_round_trip() {
+        let mut codec = crypt_codec(EncryptMode::Xxtea, b"quanta-xxtea-key");
+        codec.set_compress(CompressMode::Lz4, 0);
+        let data = vec![9u8; 4093];
+        let (flag, body) = codec.encode(0, &data).unwrap();
+        assert_eq!(flag & (FLAG_ZIP | FLAG_ENCRYPT), FLAG_ZIP | FLAG_ENCRYPT);
+        assert_eq!(codec.decode(flag, &body).unwrap(), data);
+    }
+
+    #[test]
+    fn decrypt_truncated_body() {
+        for codec in crypt_codecs() {
+            assert!(codec.decode(FLAG_ENCRYPT, &[]).is_err());
+            let (flag, body) = codec.encode(0, &[1u8; 37]).unwrap();
+            assert!(codec.decode(flag, &body[..body.len() - 1]).is_err());
+            assert!(codec.decode(flag, &body[..4]).is_err());
+        }
+    }
+
+    #[test]
+    fn unzip_forged_lz4_size() {
+        let mut body = vec![CompressMode::Lz4 as u8];
+        body.extend_from_slice(&i32::MAX.to_le_bytes());
+        body.extend_from_slice(&[0u8; 8]);
+        assert!(PacketCodec::default().decode(FLAG_ZIP, &body).is_err());
+    }
+}
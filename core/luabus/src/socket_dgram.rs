@@ -0,0 +1,237 @@
+#![allow(non_snake_case)]
+#![allow(dead_code)]
+
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::net::{ IpAddr, Ipv4Addr, SocketAddr };
+
+use mio::net::UdpSocket;
+use socket2::SockRef;
+
+use crate::socket_helper::{ get_fd, SOCKET_DGRAM_LEN };
+use crate::socket_mgr::{ SocketObj, SocketStat, LinkStatus, ErrorFunction };
+
+//发送队列上限，超出时sendto返回false
+const DGRAM_SEND_QUEUE: usize = 1024;
+
+//组播接口: ipv4为本地接口地址，ipv6为接口索引
+#[derive(Debug, Clone, Copy)]
+pub enum MulticastIface {
+    V4(Ipv4Addr),
+    V6(u32),
+}
+
+pub struct Datagram {
+    pub token: u32,
+    pub data: Vec<u8>,
+    pub addr: SocketAddr,
+}
+
+pub struct SocketDgram {
+    pub token: u32,
+    pub status: LinkStatus,
+    pub packets_in: u64,
+    pub packets_out: u64,
+    pub errors: u64,
+    pub recv_pending: bool,
+    pub error_cb: ErrorFunction,
+    pub socket: Option<UdpSocket>,
+    recv_buf: Vec<u8>,
+    recv_queue: Vec<Datagram>,
+    send_queue: VecDeque<(Vec<u8>, SocketAddr)>,
+}
+
+impl SocketDgram {
+    pub fn new() -> SocketDgram {
+        SocketDgram {
+            token: 0,
+            socket: None,
+            packets_in: 0,
+            packets_out: 0,
+            errors: 0,
+            recv_pending: false,
+            error_cb: |_|{},
+            status: LinkStatus::LinkInit,
+            recv_queue: Vec::new(),
+            send_queue: VecDeque::new(),
+            recv_buf: vec![0; SOCKET_DGRAM_LEN],
+        }
+    }
+
+    pub fn bind(&mut self, ip: String, port: u32) -> Result<u32, String> {
+        let addr_str = format!("{}:{}", ip, port);
+        let addr: SocketAddr = match addr_str.parse() {
+            Ok(addr) => addr,
+            Err(e) => return Err(e.to_string()),
+        };
+        match UdpSocket::bind(addr) {
+            Ok(socket) => {
+                self.status = LinkStatus::LinkConnected;
+                self.token = get_fd(&socket);
+                self.socket = Some(socket);
+                Ok(self.token)
+            },
+            Err(e) => {
+                Err(e.to_string())
+            }
+        }
+    }
+
+    fn is_ipv6(&self) -> bool {
+        self.socket.as_ref().and_then(|socket| socket.local_addr().ok()).map_or(false, |addr| addr.is_ipv6())
+    }
+
+    fn on_error(&mut self, err: &str) {
+        if self.status == LinkStatus::LinkConnected {
+            self.status = LinkStatus::LinkClosed;
+            (self.error_cb)(err);
+        }
+    }
+}
+
+impl SocketObj for SocketDgram {
+    fn close(&mut self) {
+        self.status = LinkStatus::LinkClosed;
+    }
+    fn get_token(&self) -> u32 { self.token }
+    fn is_same_kind(&self, _kind: u32)-> bool { false }
+    fn set_error_callback(&mut self, callback: ErrorFunction) { self.error_cb = callback; }
+    fn sendto(&mut self, data: &[u8], addr: SocketAddr) -> bool {
+        if self.status != LinkStatus::LinkConnected {
+            return false;
+        }
+        //已有排队数据时保持顺序
+        if self.send_queue.is_empty() {
+            if let Some(ref socket) = self.socket {
+                match socket.send_to(data, addr) {
+                    Ok(_) => {
+                        self.packets_out += 1;
+                        return true;
+                    },
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => {},
+                    Err(_) => {
+                        self.errors += 1;
+                        return false;
+                    },
+                }
+            }
+        }
+        if self.send_queue.len() >= DGRAM_SEND_QUEUE {
+            self.errors += 1;
+            return false;
+        }
+        self.send_queue.push_back((data.to_vec(), addr));
+        true
+    }
+    fn set_broadcast(&mut self, flag: bool) -> bool {
+        if let Some(ref socket) = self.socket {
+            return socket.set_broadcast(flag).is_ok();
+        }
+        false
+    }
+    fn set_multicast(&mut self, group: IpAddr, iface: MulticastIface, join: bool) -> Result<(), String> {
+        let socket = match self.socket {
+            Some(ref socket) => socket,
+            None => return Err("socket invalid".to_string()),
+        };
+        let res = match (group, iface) {
+            (IpAddr::V4(group), MulticastIface::V4(iface)) if join => socket.join_multicast_v4(&group, &iface),
+            (IpAddr::V4(group), MulticastIface::V4(iface)) => socket.leave_multicast_v4(&group, &iface),
+            (IpAddr::V6(group), MulticastIface::V6(index)) if join => socket.join_multicast_v6(&group, index),
+            (IpAddr::V6(group), MulticastIface::V6(index)) => socket.leave_multicast_v6(&group, index),
+            _ => return Err("multicast address family mismatch".to_string()),
+        };
+        res.map_err(|e| e.to_string())
+    }
+    //按绑定地址的协议族设置，ipv6的跳数只能通过socket2设置
+    fn set_multicast_loop(&mut self, flag: bool) -> bool {
+        match self.socket {
+            Some(ref socket) if self.is_ipv6() => socket.set_multicast_loop_v6(flag).is_ok(),
+            Some(ref socket) => socket.set_multicast_loop_v4(flag).is_ok(),
+            None => false,
+        }
+    }
+    fn set_multicast_ttl(&mut self, ttl: u32) -> bool {
+        match self.socket {
+            Some(ref socket) if self.is_ipv6() => SockRef::from(socket).set_multicast_hops_v6(ttl).is_ok(),
+            Some(ref socket) => socket.set_multicast_ttl_v4(ttl).is_ok(),
+            None => false,
+        }
+    }
+    fn stats(&self) -> Option<SocketStat> {
+        let (ip, port) = match self.socket.as_ref().and_then(|socket| socket.local_addr().ok()) {
+            Some(addr) => (addr.ip().to_string(), addr.port()),
+            None => (String::new(), 0),
+        };
+        Some(SocketStat {
+            token: self.token,
+            status: self.status as u8,
+            ip: ip,
+            port: port,
+            packets_in: self.packets_in,
+            packets_out: self.packets_out,
+            send_queue: self.send_queue.len(),
+            errors: self.errors,
+            ..Default::default()
+        })
+    }
+    fn take_datagrams(&mut self, datagrams: &mut Vec<Datagram>) {
+        datagrams.append(&mut self.recv_queue);
+    }
+    fn do_recv(&mut self) {
+        self.do_recv_quota(usize::MAX);
+    }
+    //每个数据报计为一个包，配额用完时标记recv_pending
+    fn do_recv_quota(&mut self, quota: usize) -> usize {
+        let mut error = None;
+        let mut dispatched = 0;
+        self.recv_pending = false;
+        if let Some(ref socket) = self.socket {
+            while self.status == LinkStatus::LinkConnected {
+                if dispatched >= quota {
+                    self.recv_pending = true;
+                    break;
+                }
+                match socket.recv_from(&mut self.recv_buf) {
+                    Ok((n, addr)) => {
+                        dispatched += 1;
+                        self.packets_in += 1;
+                        let data = self.recv_buf[..n].to_vec();
+                        self.recv_queue.push(Datagram { token: self.token, data, addr });
+                    },
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                    //icmp不可达等错误不影响后续收包
+                    Err(ref e) if e.kind() == ErrorKind::ConnectionReset => continue,
+                    Err(e) => {
+                        error = Some(e.to_string());
+                        break;
+                    }
+                }
+            }
+        }
+        if let Some(err) = error {
+            self.on_error(&err);
+        }
+        dispatched
+    }
+    fn has_pending(&self) -> bool {
+        self.recv_pending && self.status == LinkStatus::LinkConnected
+    }
+    fn do_send(&mut self) {
+        if let Some(ref socket) = self.socket {
+            while let Some((data, addr)) = self.send_queue.front() {
+                match socket.send_to(data, *addr) {
+                    Ok(_) => self.packets_out += 1,
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                    //发送失败的数据报丢弃，计入errors
+                    Err(_) => self.errors += 1,
+                }
+                self.send_queue.pop_front();
+            }
+        }
+    }
+    fn update(&mut self, _now: u64) -> bool {
+        self.status == LinkStatus::LinkClosed
+    }
+}
@@ -0,0 +1,105 @@
+#![allow(non_snake_case)]
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+//CIDR网段，如 10.0.0.0/8、::1/128，不带掩码视为单个地址
+#[derive(Debug, Clone, Copy)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(text: &str) -> Result<Cidr, String> {
+        let (ip, prefix) = match text.split_once('/') {
+            Some((ip, prefix)) => (ip, Some(prefix)),
+            None => (text, None),
+        };
+        let addr: IpAddr = ip.trim().parse().map_err(|_| format!("invalid cidr: {}", text))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse::<u8>().map_err(|_| format!("invalid cidr: {}", text))?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(format!("invalid cidr: {}", text));
+        }
+        Ok(Cidr { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                (u32::from(net) & mask) == (u32::from(*ip) & mask)
+            },
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                (u128::from(net) & mask) == (u128::from(*ip) & mask)
+            },
+            _ => false,
+        }
+    }
+}
+
+//监听端的黑白名单以及单IP建连频率限制
+#[derive(Default)]
+pub struct IpFilter {
+    allows: Vec<Cidr>,
+    denys: Vec<Cidr>,
+    rate_count: u32,
+    rate_period: u64,
+    history: HashMap<IpAddr, (u64, u32)>,
+}
+
+impl IpFilter {
+    pub fn add(&mut self, cidr: Cidr, allow: bool) {
+        if allow {
+            self.allows.push(cidr);
+        } else {
+            self.denys.push(cidr);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.allows.clear();
+        self.denys.clear();
+    }
+
+    //count为0时关闭限频
+    pub fn set_rate_limit(&mut self, count: u32, period: u64) {
+        self.rate_count = count;
+        self.rate_period = period.max(1);
+        self.history.clear();
+    }
+
+    pub fn check(&mut self, ip: &IpAddr, now: u64) -> Result<(), &'static str> {
+        if self.denys.iter().any(|cidr| cidr.contains(ip)) {
+            return Err("ip denied");
+        }
+        if !self.allows.is_empty() && !self.allows.iter().any(|cidr| cidr.contains(ip)) {
+            return Err("ip not allowed");
+        }
+        if self.rate_count > 0 {
+            let entry = self.history.entry(*ip).or_insert((now, 0));
+            if now >= entry.0 + self.rate_period {
+                *entry = (now, 0);
+            }
+            entry.1 += 1;
+            if entry.1 > self.rate_count {
+                return Err("ip rate limited");
+            }
+        }
+        Ok(())
+    }
+
+    //清理过期的频率记录
+    pub fn update(&mut self, now: u64) {
+        if !self.history.is_empty() {
+            let period = self.rate_period;
+            self.history.retain(|_, (start, _)| now < *start + period);
+        }
+    }
+}
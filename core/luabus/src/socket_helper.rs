@@ -9,6 +9,7 @@ use std::os::windows::io::AsRawSocket;
 use std::net::{TcpListener, SocketAddrV4, Ipv4Addr};
 
 pub const SOCKET_RECV_LEN: usize   = 4096;
+pub const SOCKET_DGRAM_LEN: usize  = 65536;
 
 #[cfg(windows)]
 pub fn get_fd<T>(sock: &T) -> u32 where T: AsRawSocket {
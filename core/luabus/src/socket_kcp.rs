@@ -0,0 +1,944 @@
+#![allow(non_snake_case)]
+#![allow(dead_code)]
+
+use std::rc::Rc;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::collections::VecDeque;
+use std::sync::atomic::{ AtomicU32, Ordering };
+
+use mio::net::UdpSocket;
+use lua::ternary;
+use ring::rand::{ SecureRandom, SystemRandom };
+
+use crate::socket_stream::FlowControl;
+use crate::socket_codec::load_packet;
+use crate::socket_helper::{ get_fd, SOCKET_DGRAM_LEN };
+use crate::socket_mgr::{ SocketObj, SocketStat, LinkStatus, NodeEvent };
+
+const KCP_RTO_NDL: u32          = 30;       // no delay min rto
+const KCP_RTO_MIN: u32          = 100;      // normal min rto
+const KCP_RTO_DEF: u32          = 200;
+const KCP_RTO_MAX: u32          = 60000;
+const KCP_CMD_PUSH: u8          = 81;       // cmd: push data
+const KCP_CMD_ACK: u8           = 82;       // cmd: ack
+const KCP_CMD_WASK: u8          = 83;       // cmd: window probe (ask)
+const KCP_CMD_WINS: u8          = 84;       // cmd: window size (tell)
+const KCP_ASK_SEND: u32         = 1;
+const KCP_ASK_TELL: u32         = 2;
+const KCP_WND_SND: u32          = 32;
+const KCP_WND_RCV: u32          = 128;
+const KCP_MTU_DEF: usize        = 1400;
+const KCP_INTERVAL: u32         = 100;
+const KCP_OVERHEAD: usize       = 24;
+const KCP_DEADLINK: u32         = 20;
+const KCP_THRESH_INIT: u32      = 2;
+const KCP_THRESH_MIN: u32       = 2;
+const KCP_PROBE_INIT: u32       = 7000;
+const KCP_PROBE_LIMIT: u32      = 120000;
+const KCP_FASTACK_LIMIT: u32    = 5;
+
+//握手包: conv(0) + cmd + conv
+pub const KCP_HANDSHAKE_LEN: usize  = 9;
+pub const KCP_HANDSHAKE_SYN: u8     = 1;
+pub const KCP_HANDSHAKE_ACK: u8     = 2;
+pub const KCP_HANDSHAKE_FIN: u8     = 3;
+
+//accept的会话默认空闲超时，lua可用set_timeout覆盖
+const KCP_ACCEPT_TIMEOUT: u64   = 60000;
+
+//随机数不可用时的后备序列
+static KCP_CONV: AtomicU32 = AtomicU32::new(0);
+
+//会话conv同时作为socket_mgr中的token，随机分配且置最高位避免与fd冲突
+//与已有会话冲突时由socket_mgr重新分配
+pub fn new_conv() -> u32 {
+    let mut buf = [0u8; 4];
+    let conv = match SystemRandom::new().fill(&mut buf) {
+        Ok(_) => u32::from_le_bytes(buf),
+        Err(_) => KCP_CONV.fetch_add(1, Ordering::Relaxed),
+    };
+    conv | 0x80000000
+}
+
+pub fn handshake(cmd: u8, conv: u32) -> [u8; KCP_HANDSHAKE_LEN] {
+    let mut buf = [0u8; KCP_HANDSHAKE_LEN];
+    buf[4] = cmd;
+    buf[5..].copy_from_slice(&conv.to_le_bytes());
+    buf
+}
+
+pub fn read_u32(data: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+}
+
+fn timediff(later: u32, earlier: u32) -> i32 {
+    later.wrapping_sub(earlier) as i32
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct KcpOption {
+    pub nodelay: i32,
+    pub interval: i32,
+    pub resend: i32,
+    pub nc: i32,
+    pub snd_wnd: u32,
+    pub rcv_wnd: u32,
+}
+
+impl Default for KcpOption {
+    fn default() -> KcpOption {
+        KcpOption { nodelay: 0, interval: KCP_INTERVAL as i32, resend: 0, nc: 0, snd_wnd: KCP_WND_SND, rcv_wnd: KCP_WND_RCV }
+    }
+}
+
+#[derive(Default)]
+struct Segment {
+    conv: u32,
+    cmd: u8,
+    frg: u8,
+    wnd: u16,
+    ts: u32,
+    sn: u32,
+    una: u32,
+    resendts: u32,
+    rto: u32,
+    fastack: u32,
+    xmit: u32,
+    data: Vec<u8>,
+}
+
+impl Segment {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.conv.to_le_bytes());
+        buf.push(self.cmd);
+        buf.push(self.frg);
+        buf.extend_from_slice(&self.wnd.to_le_bytes());
+        buf.extend_from_slice(&self.ts.to_le_bytes());
+        buf.extend_from_slice(&self.sn.to_le_bytes());
+        buf.extend_from_slice(&self.una.to_le_bytes());
+        buf.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&self.data);
+    }
+}
+
+//KCP协议实现，输出的数据包缓存在output中由会话发送
+pub struct Kcp {
+    conv: u32,
+    mtu: usize,
+    mss: usize,
+    state: i32,
+    snd_una: u32,
+    snd_nxt: u32,
+    rcv_nxt: u32,
+    ssthresh: u32,
+    rx_rttval: u32,
+    rx_srtt: u32,
+    rx_rto: u32,
+    rx_minrto: u32,
+    snd_wnd: u32,
+    rcv_wnd: u32,
+    rmt_wnd: u32,
+    cwnd: u32,
+    probe: u32,
+    current: u32,
+    interval: u32,
+    ts_flush: u32,
+    xmit: u32,
+    nodelay: i32,
+    updated: bool,
+    ts_probe: u32,
+    probe_wait: u32,
+    dead_link: u32,
+    incr: usize,
+    fastresend: i32,
+    fastlimit: u32,
+    nocwnd: i32,
+    snd_queue: VecDeque<Segment>,
+    rcv_queue: VecDeque<Segment>,
+    snd_buf: VecDeque<Segment>,
+    rcv_buf: VecDeque<Segment>,
+    acklist: Vec<(u32, u32)>,
+    pub output: Vec<Vec<u8>>,
+}
+
+impl Kcp {
+    pub fn new(conv: u32) -> Kcp {
+        Kcp {
+            conv: conv,
+            mtu: KCP_MTU_DEF,
+            mss: KCP_MTU_DEF - KCP_OVERHEAD,
+            state: 0,
+            snd_una: 0,
+            snd_nxt: 0,
+            rcv_nxt: 0,
+            ssthresh: KCP_THRESH_INIT,
+            rx_rttval: 0,
+            rx_srtt: 0,
+            rx_rto: KCP_RTO_DEF,
+            rx_minrto: KCP_RTO_MIN,
+            snd_wnd: KCP_WND_SND,
+            rcv_wnd: KCP_WND_RCV,
+            rmt_wnd: KCP_WND_RCV,
+            cwnd: 0,
+            probe: 0,
+            current: 0,
+            interval: KCP_INTERVAL,
+            ts_flush: KCP_INTERVAL,
+            xmit: 0,
+            nodelay: 0,
+            updated: false,
+            ts_probe: 0,
+            probe_wait: 0,
+            dead_link: KCP_DEADLINK,
+            incr: 0,
+            fastresend: 0,
+            fastlimit: KCP_FASTACK_LIMIT,
+            nocwnd: 0,
+            snd_queue: VecDeque::new(),
+            rcv_queue: VecDeque::new(),
+            snd_buf: VecDeque::new(),
+            rcv_buf: VecDeque::new(),
+            acklist: Vec::new(),
+            output: Vec::new(),
+        }
+    }
+
+    pub fn is_dead(&self) -> bool {
+        self.state < 0
+    }
+
+    pub fn waitsnd(&self) -> usize {
+        self.snd_buf.len() + self.snd_queue.len()
+    }
+
+    pub fn mss(&self) -> usize {
+        self.mss
+    }
+
+    pub fn setup(&mut self, option: &KcpOption) {
+        self.set_nodelay(option.nodelay, option.interval, option.resend, option.nc);
+        self.set_wndsize(option.snd_wnd, option.rcv_wnd);
+    }
+
+    pub fn option(&self) -> KcpOption {
+        KcpOption {
+            nodelay: self.nodelay,
+            interval: self.interval as i32,
+            resend: self.fastresend,
+            nc: self.nocwnd,
+            snd_wnd: self.snd_wnd,
+            rcv_wnd: self.rcv_wnd,
+        }
+    }
+
+    pub fn set_nodelay(&mut self, nodelay: i32, interval: i32, resend: i32, nc: i32) {
+        if nodelay >= 0 {
+            self.nodelay = nodelay;
+            self.rx_minrto = ternary!(nodelay > 0, KCP_RTO_NDL, KCP_RTO_MIN);
+        }
+        if interval >= 0 {
+            self.interval = interval.clamp(10, 5000) as u32;
+        }
+        if resend >= 0 {
+            self.fastresend = resend;
+        }
+        if nc >= 0 {
+            self.nocwnd = nc;
+        }
+    }
+
+    pub fn set_wndsize(&mut self, sndwnd: u32, rcvwnd: u32) {
+        if sndwnd > 0 {
+            self.snd_wnd = sndwnd;
+        }
+        if rcvwnd > 0 {
+            self.rcv_wnd = rcvwnd.max(KCP_WND_RCV);
+        }
+    }
+
+    pub fn send(&mut self, data: &[u8]) -> i32 {
+        let count = ternary!(data.len() <= self.mss, 1, (data.len() + self.mss - 1) / self.mss);
+        if count >= KCP_WND_RCV as usize {
+            return -2;
+        }
+        for (i, chunk) in data.chunks(self.mss).enumerate() {
+            let frg = (count - i - 1) as u8;
+            self.snd_queue.push_back(Segment { frg, data: chunk.to_vec(), ..Default::default() });
+        }
+        if data.is_empty() {
+            self.snd_queue.push_back(Segment::default());
+        }
+        0
+    }
+
+    fn peeksize(&self) -> i32 {
+        let seg = match self.rcv_queue.front() {
+            Some(seg) => seg,
+            None => return -1,
+        };
+        if seg.frg == 0 {
+            return seg.data.len() as i32;
+        }
+        if self.rcv_queue.len() < seg.frg as usize + 1 {
+            return -1;
+        }
+        let mut length = 0;
+        for seg in self.rcv_queue.iter() {
+            length += seg.data.len();
+            if seg.frg == 0 {
+                break;
+            }
+        }
+        length as i32
+    }
+
+    pub fn recv(&mut self) -> Option<Vec<u8>> {
+        let peeksize = self.peeksize();
+        if peeksize < 0 {
+            return None;
+        }
+        let recover = self.rcv_queue.len() >= self.rcv_wnd as usize;
+        let mut data = Vec::with_capacity(peeksize as usize);
+        while let Some(seg) = self.rcv_queue.pop_front() {
+            data.extend_from_slice(&seg.data);
+            if seg.frg == 0 {
+                break;
+            }
+        }
+        self.move_rcv_buf();
+        if recover && self.rcv_queue.len() < self.rcv_wnd as usize {
+            self.probe |= KCP_ASK_TELL;
+        }
+        Some(data)
+    }
+
+    fn move_rcv_buf(&mut self) {
+        while let Some(seg) = self.rcv_buf.front() {
+            if seg.sn != self.rcv_nxt || self.rcv_queue.len() >= self.rcv_wnd as usize {
+                break;
+            }
+            let seg = self.rcv_buf.pop_front().unwrap();
+            self.rcv_queue.push_back(seg);
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+        }
+    }
+
+    fn update_ack(&mut self, rtt: i32) {
+        if self.rx_srtt == 0 {
+            self.rx_srtt = rtt as u32;
+            self.rx_rttval = rtt as u32 / 2;
+        } else {
+            let delta = (rtt - self.rx_srtt as i32).unsigned_abs();
+            self.rx_rttval = (3 * self.rx_rttval + delta) / 4;
+            self.rx_srtt = ((7 * self.rx_srtt + rtt as u32) / 8).max(1);
+        }
+        let rto = self.rx_srtt + self.interval.max(4 * self.rx_rttval);
+        self.rx_rto = rto.clamp(self.rx_minrto, KCP_RTO_MAX);
+    }
+
+    fn shrink_buf(&mut self) {
+        self.snd_una = match self.snd_buf.front() {
+            Some(seg) => seg.sn,
+            None => self.snd_nxt,
+        };
+    }
+
+    fn parse_ack(&mut self, sn: u32) {
+        if timediff(sn, self.snd_una) < 0 || timediff(sn, self.snd_nxt) >= 0 {
+            return;
+        }
+        if let Some(pos) = self.snd_buf.iter().position(|seg| seg.sn == sn) {
+            self.snd_buf.remove(pos);
+        }
+    }
+
+    fn parse_una(&mut self, una: u32) {
+        while let Some(seg) = self.snd_buf.front() {
+            if timediff(una, seg.sn) <= 0 {
+                break;
+            }
+            self.snd_buf.pop_front();
+        }
+    }
+
+    fn parse_fastack(&mut self, sn: u32) {
+        if timediff(sn, self.snd_una) < 0 || timediff(sn, self.snd_nxt) >= 0 {
+            return;
+        }
+        for seg in self.snd_buf.iter_mut() {
+            if timediff(sn, seg.sn) < 0 {
+                break;
+            }
+            if sn != seg.sn {
+                seg.fastack += 1;
+            }
+        }
+    }
+
+    fn parse_data(&mut self, newseg: Segment) {
+        let sn = newseg.sn;
+        if timediff(sn, self.rcv_nxt.wrapping_add(self.rcv_wnd)) >= 0 || timediff(sn, self.rcv_nxt) < 0 {
+            return;
+        }
+        let mut repeat = false;
+        let mut pos = 0;
+        for (i, seg) in self.rcv_buf.iter().enumerate().rev() {
+            if seg.sn == sn {
+                repeat = true;
+                break;
+            }
+            if timediff(sn, seg.sn) > 0 {
+                pos = i + 1;
+                break;
+            }
+        }
+        if !repeat {
+            self.rcv_buf.insert(pos, newseg);
+        }
+        self.move_rcv_buf();
+    }
+
+    pub fn input(&mut self, data: &[u8]) -> i32 {
+        let prev_una = self.snd_una;
+        let mut maxack = 0;
+        let mut flag = false;
+        if data.len() < KCP_OVERHEAD {
+            return -1;
+        }
+        let mut pos = 0;
+        while data.len() - pos >= KCP_OVERHEAD {
+            let conv = read_u32(data, pos);
+            if conv != self.conv {
+                return -1;
+            }
+            let cmd = data[pos + 4];
+            let frg = data[pos + 5];
+            let wnd = u16::from_le_bytes([data[pos + 6], data[pos + 7]]);
+            let ts = read_u32(data, pos + 8);
+            let sn = read_u32(data, pos + 12);
+            let una = read_u32(data, pos + 16);
+            let len = read_u32(data, pos + 20) as usize;
+            pos += KCP_OVERHEAD;
+            if data.len() - pos < len {
+                return -2;
+            }
+            if cmd != KCP_CMD_PUSH && cmd != KCP_CMD_ACK && cmd != KCP_CMD_WASK && cmd != KCP_CMD_WINS {
+                return -3;
+            }
+            self.rmt_wnd = wnd as u32;
+            self.parse_una(una);
+            self.shrink_buf();
+            match cmd {
+                KCP_CMD_ACK => {
+                    if timediff(self.current, ts) >= 0 {
+                        self.update_ack(timediff(self.current, ts));
+                    }
+                    self.parse_ack(sn);
+                    self.shrink_buf();
+                    if !flag || timediff(sn, maxack) > 0 {
+                        flag = true;
+                        maxack = sn;
+                    }
+                },
+                KCP_CMD_PUSH => {
+                    if timediff(sn, self.rcv_nxt.wrapping_add(self.rcv_wnd)) < 0 {
+                        self.acklist.push((sn, ts));
+                        if timediff(sn, self.rcv_nxt) >= 0 {
+                            let seg = Segment { conv, cmd, frg, wnd, ts, sn, una, data: data[pos..pos + len].to_vec(), ..Default::default() };
+                            self.parse_data(seg);
+                        }
+                    }
+                },
+                KCP_CMD_WASK => self.probe |= KCP_ASK_TELL,
+                _ => {},
+            }
+            pos += len;
+        }
+        if flag {
+            self.parse_fastack(maxack);
+        }
+        if timediff(self.snd_una, prev_una) > 0 && self.cwnd < self.rmt_wnd {
+            let mss = self.mss;
+            if self.cwnd < self.ssthresh {
+                self.cwnd += 1;
+                self.incr += mss;
+            } else {
+                self.incr = self.incr.max(mss);
+                self.incr += (mss * mss) / self.incr + (mss / 16);
+                if (self.cwnd as usize + 1) * mss <= self.incr {
+                    self.cwnd = ((self.incr + mss - 1) / mss.max(1)) as u32;
+                }
+            }
+            if self.cwnd > self.rmt_wnd {
+                self.cwnd = self.rmt_wnd;
+                self.incr = self.rmt_wnd as usize * mss;
+            }
+        }
+        0
+    }
+
+    fn wnd_unused(&self) -> u16 {
+        let len = self.rcv_queue.len() as u32;
+        ternary!(len < self.rcv_wnd, (self.rcv_wnd - len) as u16, 0)
+    }
+
+    fn flush_buffer(&mut self, buffer: &mut Vec<u8>, need: usize) {
+        if !buffer.is_empty() && buffer.len() + need > self.mtu {
+            self.output.push(std::mem::take(buffer));
+        }
+    }
+
+    pub fn flush(&mut self) {
+        if !self.updated {
+            return;
+        }
+        let current = self.current;
+        let mut buffer = Vec::with_capacity(self.mtu);
+        let mut seg = Segment { conv: self.conv, cmd: KCP_CMD_ACK, wnd: self.wnd_unused(), una: self.rcv_nxt, ..Default::default() };
+        for (sn, ts) in std::mem::take(&mut self.acklist) {
+            self.flush_buffer(&mut buffer, KCP_OVERHEAD);
+            seg.sn = sn;
+            seg.ts = ts;
+            seg.encode(&mut buffer);
+        }
+        //探测远端窗口
+        if self.rmt_wnd == 0 {
+            if self.probe_wait == 0 {
+                self.probe_wait = KCP_PROBE_INIT;
+                self.ts_probe = current.wrapping_add(self.probe_wait);
+            } else if timediff(current, self.ts_probe) >= 0 {
+                self.probe_wait = self.probe_wait.max(KCP_PROBE_INIT);
+                self.probe_wait = (self.probe_wait + self.probe_wait / 2).min(KCP_PROBE_LIMIT);
+                self.ts_probe = current.wrapping_add(self.probe_wait);
+                self.probe |= KCP_ASK_SEND;
+            }
+        } else {
+            self.ts_probe = 0;
+            self.probe_wait = 0;
+        }
+        seg.sn = 0;
+        seg.ts = 0;
+        if (self.probe & KCP_ASK_SEND) != 0 {
+            seg.cmd = KCP_CMD_WASK;
+            self.flush_buffer(&mut buffer, KCP_OVERHEAD);
+            seg.encode(&mut buffer);
+        }
+        if (self.probe & KCP_ASK_TELL) != 0 {
+            seg.cmd = KCP_CMD_WINS;
+            self.flush_buffer(&mut buffer, KCP_OVERHEAD);
+            seg.encode(&mut buffer);
+        }
+        self.probe = 0;
+        //发送队列移入发送缓冲
+        let mut cwnd = self.snd_wnd.min(self.rmt_wnd);
+        if self.nocwnd == 0 {
+            cwnd = cwnd.min(self.cwnd);
+        }
+        while timediff(self.snd_nxt, self.snd_una.wrapping_add(cwnd)) < 0 {
+            let mut newseg = match self.snd_queue.pop_front() {
+                Some(newseg) => newseg,
+                None => break,
+            };
+            newseg.conv = self.conv;
+            newseg.cmd = KCP_CMD_PUSH;
+            newseg.wnd = seg.wnd;
+            newseg.ts = current;
+            newseg.sn = self.snd_nxt;
+            newseg.una = self.rcv_nxt;
+            newseg.resendts = current;
+            newseg.rto = self.rx_rto;
+            self.snd_nxt = self.snd_nxt.wrapping_add(1);
+            self.snd_buf.push_back(newseg);
+        }
+        let resent = ternary!(self.fastresend > 0, self.fastresend as u32, u32::MAX);
+        let rtomin = ternary!(self.nodelay == 0, self.rx_rto >> 3, 0);
+        let mut lost = false;
+        let mut change = false;
+        let mut snd_buf = std::mem::take(&mut self.snd_buf);
+        for segment in snd_buf.iter_mut() {
+            let mut needsend = false;
+            if segment.xmit == 0 {
+                needsend = true;
+                segment.xmit += 1;
+                segment.rto = self.rx_rto;
+                segment.resendts = current.wrapping_add(segment.rto + rtomin);
+            } else if timediff(current, segment.resendts) >= 0 {
+                needsend = true;
+                segment.xmit += 1;
+                self.xmit += 1;
+                if self.nodelay == 0 {
+                    segment.rto += segment.rto.max(self.rx_rto);
+                } else {
+                    let step = ternary!(self.nodelay < 2, segment.rto, self.rx_rto);
+                    segment.rto += step / 2;
+                }
+                segment.resendts = current.wrapping_add(segment.rto);
+                lost = true;
+            } else if segment.fastack >= resent && (segment.xmit <= self.fastlimit || self.fastlimit == 0) {
+                needsend = true;
+                segment.xmit += 1;
+                segment.fastack = 0;
+                segment.resendts = current.wrapping_add(segment.rto);
+                change = true;
+            }
+            if needsend {
+                segment.ts = current;
+                segment.wnd = seg.wnd;
+                segment.una = self.rcv_nxt;
+                self.flush_buffer(&mut buffer, KCP_OVERHEAD + segment.data.len());
+                segment.encode(&mut buffer);
+                if segment.xmit >= self.dead_link {
+                    self.state = -1;
+                }
+            }
+        }
+        self.snd_buf = snd_buf;
+        if !buffer.is_empty() {
+            self.output.push(buffer);
+        }
+        if change {
+            let inflight = self.snd_nxt.wrapping_sub(self.snd_una);
+            self.ssthresh = (inflight / 2).max(KCP_THRESH_MIN);
+            self.cwnd = self.ssthresh.saturating_add(resent);
+            self.incr = self.cwnd as usize * self.mss;
+        }
+        if lost {
+            self.ssthresh = (cwnd / 2).max(KCP_THRESH_MIN);
+            self.cwnd = 1;
+            self.incr = self.mss;
+        }
+        if self.cwnd < 1 {
+            self.cwnd = 1;
+            self.incr = self.mss;
+        }
+    }
+
+    pub fn update(&mut self, current: u32) {
+        self.current = current;
+        if !self.updated {
+            self.updated = true;
+            self.ts_flush = current;
+        }
+        let mut slap = timediff(current, self.ts_flush);
+        if slap >= 10000 || slap < -10000 {
+            self.ts_flush = current;
+            slap = 0;
+        }
+        if slap >= 0 {
+            self.ts_flush = self.ts_flush.wrapping_add(self.interval);
+            if timediff(current, self.ts_flush) >= 0 {
+                self.ts_flush = current.wrapping_add(self.interval);
+            }
+            self.flush();
+        }
+    }
+}
+
+pub struct SocketKcp {
+    pub token: u32,
+    pub conv: u32,
+    pub timeout: u64,
+    pub connect_time: u64,
+    pub handshake_time: u64,
+    pub lastrecv_time: u64,
+    pub established_time: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub packets_in: u64,
+    pub packets_out: u64,
+    pub kcp: Kcp,
+    pub peer: SocketAddr,
+    pub flow: FlowControl,
+    pub status: LinkStatus,
+    pub socket: Option<Rc<UdpSocket>>,
+    recv_buf: Vec<u8>,
+    recv_buffer: Vec<u8>,
+    recv_pending: bool,
+    events: Vec<NodeEvent>,
+}
+
+impl SocketKcp {
+    fn new(peer: SocketAddr) -> SocketKcp {
+        SocketKcp {
+            token: 0,
+            conv: 0,
+            timeout: 0,
+            socket: None,
+            connect_time: 0,
+            handshake_time: 0,
+            lastrecv_time: 0,
+            established_time: 0,
+            bytes_in: 0,
+            bytes_out: 0,
+            packets_in: 0,
+            packets_out: 0,
+            peer: peer,
+            kcp: Kcp::new(0),
+            flow: FlowControl::default(),
+            status: LinkStatus::LinkInit,
+            recv_buf: Vec::new(),
+            recv_buffer: Vec::new(),
+            recv_pending: false,
+            events: Vec::new(),
+        }
+    }
+
+    //服务端会话，与监听者共用udp socket
+    pub fn accept(conv: u32, socket: Rc<UdpSocket>, peer: SocketAddr, option: &KcpOption, flow: FlowControl) -> SocketKcp {
+        let mut session = SocketKcp::new(peer);
+        session.conv = conv;
+        session.token = conv;
+        session.timeout = KCP_ACCEPT_TIMEOUT;
+        session.flow = flow;
+        session.socket = Some(socket);
+        session.kcp = Kcp::new(conv);
+        session.kcp.setup(option);
+        session.lastrecv_time = luakit::steady_ms();
+        session.established_time = session.lastrecv_time;
+        session.status = LinkStatus::LinkConnected;
+        session
+    }
+
+    //客户端会话，独占udp socket，token为fd
+    pub fn connect(&mut self, ip: String, port: u32, timeout: u64) -> Result<UdpSocket, String> {
+        let addr_str = format!("{}:{}", ip, port);
+        let addr: SocketAddr = match addr_str.parse() {
+            Ok(addr) => addr,
+            Err(e) => return Err(e.to_string()),
+        };
+        let local: SocketAddr = ternary!(addr.is_ipv4(), "0.0.0.0:0", "[::]:0").parse().unwrap();
+        match UdpSocket::bind(local) {
+            Ok(socket) => {
+                self.peer = addr;
+                self.token = get_fd(&socket);
+                self.status = LinkStatus::LinkConnecting;
+                self.connect_time = luakit::steady_ms() + timeout;
+                self.recv_buf = vec![0; SOCKET_DGRAM_LEN];
+                Ok(socket)
+            },
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    pub fn connector() -> SocketKcp {
+        SocketKcp::new("0.0.0.0:0".parse().unwrap())
+    }
+
+    pub fn set_socket(&mut self, socket: Rc<UdpSocket>) {
+        self.socket = Some(socket);
+    }
+
+    fn send_raw(&mut self, data: &[u8]) {
+        if let Some(ref socket) = self.socket {
+            //udp丢包由kcp重传兜底
+            if socket.send_to(data, self.peer).is_ok() {
+                self.bytes_out += data.len() as u64;
+            }
+        }
+    }
+
+    fn flush_output(&mut self) {
+        for pkt in std::mem::take(&mut self.kcp.output) {
+            self.send_raw(&pkt);
+        }
+    }
+
+    fn on_handshake(&mut self, cmd: u8, conv: u32) {
+        if cmd == KCP_HANDSHAKE_ACK && self.status == LinkStatus::LinkConnecting {
+            self.conv = conv;
+            //连接前设置的参数沿用到新的conv
+            let option = self.kcp.option();
+            self.kcp = Kcp::new(conv);
+            self.kcp.setup(&option);
+            self.on_connect(true, "ok");
+            return;
+        }
+        if cmd == KCP_HANDSHAKE_FIN && conv == self.conv {
+            self.on_error("connection closed");
+        }
+    }
+
+    fn on_error(&mut self, err: &str) {
+        if self.status == LinkStatus::LinkConnected {
+            self.status = LinkStatus::LinkClosed;
+            self.events.push(NodeEvent::Error(self.token, err.to_string()));
+        }
+    }
+
+    //kcp消息拼成字节流后与tcp一致按4字节长度分包
+    fn dispatch_package(&mut self) {
+        let mut offset = 0;
+        while self.status == LinkStatus::LinkConnected {
+            let packet_len = load_packet(&self.recv_buffer[offset..], self.flow.max_packet);
+            if packet_len < 0 {
+                self.on_error("packet size overflow");
+                return;
+            }
+            if packet_len == 0 {
+                break;
+            }
+            let end = offset + packet_len as usize;
+            self.packets_in += 1;
+            self.events.push(NodeEvent::Package(self.token, self.recv_buffer[offset..end].to_vec()));
+            offset = end;
+        }
+        self.recv_buffer.drain(..offset);
+    }
+
+    fn on_connect(&mut self, ok: bool,  err: &str) {
+        if ok {
+            self.lastrecv_time = luakit::steady_ms();
+            self.established_time = self.lastrecv_time;
+            self.status = LinkStatus::LinkConnected;
+        } else {
+            self.status = LinkStatus::LinkClosed;
+        }
+        self.events.push(NodeEvent::Connect(self.token, err.to_string()));
+    }
+}
+
+impl SocketObj for SocketKcp {
+    fn close(&mut self) {
+        if self.status == LinkStatus::LinkConnected {
+            self.send_raw(&handshake(KCP_HANDSHAKE_FIN, self.conv));
+        }
+        self.status = LinkStatus::LinkClosed;
+    }
+    fn send(&mut self, data: &[u8]) {
+        self.sendv(&vec![data]);
+    }
+    fn sendv(&mut self, items: &Vec<&[u8]>) {
+        if self.status != LinkStatus::LinkConnected {
+            return;
+        }
+        let data = items.concat();
+        let pending = (self.kcp.waitsnd() + 1) * self.kcp.mss();
+        if (self.flow.high_water > 0 && pending + data.len() > self.flow.high_water) || self.kcp.send(&data) < 0 {
+            self.on_error("send buffer overflow");
+            return;
+        }
+        self.packets_out += 1;
+    }
+    fn get_token(&self) -> u32 { self.token }
+    fn is_same_kind(&self, kind: u32)-> bool { self.token == kind }
+    fn set_timeout(&mut self, duration: u64){ self.timeout = duration; }
+    fn set_max_packet(&mut self, size: usize) { self.flow.max_packet = size; }
+    fn set_flow_control(&mut self, high: usize, low: usize, backpressure: bool) {
+        self.flow.high_water = high;
+        self.flow.low_water = low.min(high);
+        self.flow.backpressure = backpressure;
+    }
+    fn set_kcp_nodelay(&mut self, nodelay: i32, interval: i32, resend: i32, nc: i32) {
+        self.kcp.set_nodelay(nodelay, interval, resend, nc);
+    }
+    fn set_kcp_window(&mut self, sndwnd: u32, rcvwnd: u32) {
+        self.kcp.set_wndsize(sndwnd, rcvwnd);
+    }
+    fn stats(&self) -> Option<SocketStat> {
+        Some(SocketStat {
+            token: self.token,
+            status: self.status as u8,
+            ip: self.peer.ip().to_string(),
+            port: self.peer.port(),
+            bytes_in: self.bytes_in,
+            bytes_out: self.bytes_out,
+            packets_in: self.packets_in,
+            packets_out: self.packets_out,
+            send_queue: self.kcp.waitsnd(),
+            connect_time: self.established_time,
+            lastrecv_time: self.lastrecv_time,
+            ..Default::default()
+        })
+    }
+    fn is_alive(&self) -> bool {
+        self.status == LinkStatus::LinkConnected
+    }
+    fn peer_addr(&self) -> Option<(String, u16)> {
+        Some((self.peer.ip().to_string(), self.peer.port()))
+    }
+    fn take_events(&mut self, events: &mut Vec<NodeEvent>) {
+        events.append(&mut self.events);
+    }
+    fn kcp_input(&mut self, data: &[u8], addr: SocketAddr) {
+        //conv可被猜测，只接受会话对端地址的数据
+        if addr != self.peer {
+            return;
+        }
+        if data.len() == KCP_HANDSHAKE_LEN && read_u32(data, 0) == 0 {
+            self.on_handshake(data[4], read_u32(data, 5));
+            return;
+        }
+        if self.status != LinkStatus::LinkConnected {
+            return;
+        }
+        if self.kcp.input(data) < 0 {
+            return;
+        }
+        self.bytes_in += data.len() as u64;
+        self.lastrecv_time = luakit::steady_ms();
+        while let Some(message) = self.kcp.recv() {
+            self.recv_buffer.extend_from_slice(&message);
+        }
+        self.dispatch_package();
+    }
+    fn do_recv(&mut self) {
+        self.do_recv_quota(usize::MAX);
+    }
+    //按派发出的包计数，配额用完时停止读取并标记recv_pending
+    fn do_recv_quota(&mut self, quota: usize) -> usize {
+        let socket = match self.socket {
+            Some(ref socket) => Rc::clone(socket),
+            None => return 0,
+        };
+        let packets = self.packets_in;
+        self.recv_pending = false;
+        let mut recv_buf = std::mem::take(&mut self.recv_buf);
+        loop {
+            if (self.packets_in - packets) as usize >= quota {
+                self.recv_pending = true;
+                break;
+            }
+            match socket.recv_from(&mut recv_buf) {
+                Ok((n, addr)) => {
+                    self.kcp_input(&recv_buf[..n], addr);
+                },
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(ref e) if e.kind() == ErrorKind::ConnectionReset => continue,
+                Err(e) => {
+                    self.on_error(&e.to_string());
+                    break;
+                }
+            }
+        }
+        self.recv_buf = recv_buf;
+        (self.packets_in - packets) as usize
+    }
+    fn has_pending(&self) -> bool {
+        self.recv_pending && self.status == LinkStatus::LinkConnected
+    }
+    fn update(&mut self, now: u64) -> bool {
+        match self.status {
+            LinkStatus::LinkConnecting => {
+                if now >= self.connect_time {
+                    self.on_connect(false, "connect timeout");
+                } else if now >= self.handshake_time + self.kcp.interval as u64 {
+                    self.handshake_time = now;
+                    self.send_raw(&handshake(KCP_HANDSHAKE_SYN, 0));
+                }
+            },
+            LinkStatus::LinkConnected => {
+                self.kcp.update(now as u32);
+                self.flush_output();
+                if self.kcp.is_dead() {
+                    self.on_error("dead link");
+                } else if self.timeout > 0 && now > self.lastrecv_time + self.timeout {
+                    self.on_error("timeout");
+                }
+            },
+            _ => {},
+        }
+        self.status == LinkStatus::LinkClosed
+    }
+}
@@ -0,0 +1,201 @@
+#![allow(non_snake_case)]
+#![allow(dead_code)]
+
+use std::rc::Rc;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::collections::HashMap;
+
+use mio::net::UdpSocket;
+
+use crate::socket_stream::FlowControl;
+use crate::socket_helper::{ get_fd, SOCKET_DGRAM_LEN };
+use crate::socket_mgr::{ SocketObj, LinkStatus, ErrorFunction, AcceptFunction };
+use crate::socket_kcp::{ SocketKcp, KcpOption, handshake, read_u32, KCP_HANDSHAKE_LEN, KCP_HANDSHAKE_SYN, KCP_HANDSHAKE_ACK };
+
+//重复SYN的去重窗口
+const KCP_SYN_WINDOW: u64 = 3000;
+
+//Syn由socket_mgr检查容量并分配conv后再回调kcp_accept
+pub enum KcpEvent {
+    Syn(u32, SocketAddr),
+    Input(u32, SocketAddr, Vec<u8>),
+}
+
+pub struct SocketKcpListener {
+    pub token: u32,
+    pub status: LinkStatus,
+    pub flow: FlowControl,
+    pub option: KcpOption,
+    pub error_cb: ErrorFunction,
+    pub accept_cb: AcceptFunction,
+    pub socket: Option<Rc<UdpSocket>>,
+    recv_buf: Vec<u8>,
+    recv_pending: bool,
+    events: Vec<KcpEvent>,
+    handshakes: HashMap<SocketAddr, (u32, u64)>,
+}
+
+impl SocketKcpListener {
+    pub fn new() -> SocketKcpListener {
+        SocketKcpListener {
+            token: 0,
+            socket: None,
+            error_cb: |_|{},
+            accept_cb: |_|{},
+            status: LinkStatus::LinkInit,
+            flow: FlowControl::default(),
+            option: KcpOption::default(),
+            recv_buf: vec![0; SOCKET_DGRAM_LEN],
+            recv_pending: false,
+            handshakes: HashMap::new(),
+            events: Vec::new(),
+        }
+    }
+
+    pub fn listen(&mut self, ip: String, port: u32) -> Result<UdpSocket, String> {
+        let addr_str = format!("{}:{}", ip, port);
+        let addr: SocketAddr = match addr_str.parse() {
+            Ok(addr) => addr,
+            Err(e) => return Err(e.to_string()),
+        };
+        match UdpSocket::bind(addr) {
+            Ok(socket) => {
+                self.status = LinkStatus::LinkConnected;
+                self.token = get_fd(&socket);
+                Ok(socket)
+            },
+            Err(e) => {
+                Err(e.to_string())
+            }
+        }
+    }
+
+    fn on_error(&mut self, err: &str) {
+        if self.status == LinkStatus::LinkConnected {
+            self.status = LinkStatus::LinkClosed;
+            (self.error_cb)(err);
+        }
+    }
+
+    //重传的SYN直接应答已分配的conv，新的SYN交给socket_mgr
+    fn on_syn(&mut self, socket: &Rc<UdpSocket>, addr: SocketAddr) {
+        if !self.resend_ack(socket, addr) {
+            self.events.push(KcpEvent::Syn(self.token, addr));
+        }
+    }
+
+    fn resend_ack(&self, socket: &UdpSocket, addr: SocketAddr) -> bool {
+        match self.handshakes.get(&addr) {
+            Some((conv, time)) if luakit::steady_ms() < time + KCP_SYN_WINDOW => {
+                let _ = socket.send_to(&handshake(KCP_HANDSHAKE_ACK, *conv), addr);
+                true
+            },
+            _ => false,
+        }
+    }
+}
+
+impl SocketObj for SocketKcpListener {
+    fn close(&mut self) {
+        self.status = LinkStatus::LinkClosed;
+    }
+    fn get_token(&self) -> u32 { self.token }
+    fn is_same_kind(&self, kind: u32)-> bool { return self.token == kind; }
+    fn set_max_packet(&mut self, size: usize) { self.flow.max_packet = size; }
+    fn set_flow_control(&mut self, high: usize, low: usize, backpressure: bool) {
+        self.flow.high_water = high;
+        self.flow.low_water = low.min(high);
+        self.flow.backpressure = backpressure;
+    }
+    fn set_kcp_nodelay(&mut self, nodelay: i32, interval: i32, resend: i32, nc: i32) {
+        self.option.nodelay = nodelay;
+        self.option.interval = interval;
+        self.option.resend = resend;
+        self.option.nc = nc;
+    }
+    fn set_kcp_window(&mut self, sndwnd: u32, rcvwnd: u32) {
+        self.option.snd_wnd = sndwnd;
+        self.option.rcv_wnd = rcvwnd;
+    }
+    fn set_error_callback(&mut self, callback: ErrorFunction) { self.error_cb = callback; }
+    fn set_accept_callback(&mut self, callback: AcceptFunction) { self.accept_cb = callback; }
+    fn take_kcp_events(&mut self, events: &mut Vec<KcpEvent>) {
+        events.append(&mut self.events);
+    }
+    //创建会话后才应答SYN
+    fn kcp_accept(&mut self, conv: u32, addr: SocketAddr) -> Option<Box<SocketKcp>> {
+        let socket = match self.socket {
+            Some(ref socket) => Rc::clone(socket),
+            None => return None,
+        };
+        //同一批收到的重复SYN
+        if self.resend_ack(&socket, addr) {
+            return None;
+        }
+        let session = SocketKcp::accept(conv, Rc::clone(&socket), addr, &self.option, self.flow);
+        self.handshakes.insert(addr, (conv, luakit::steady_ms()));
+        let _ = socket.send_to(&handshake(KCP_HANDSHAKE_ACK, conv), addr);
+        Some(Box::new(session))
+    }
+    fn do_recv(&mut self) {
+        self.do_recv_quota(usize::MAX);
+    }
+    //交给会话的数据报计为派发的包，配额用完时标记recv_pending
+    fn do_recv_quota(&mut self, quota: usize) -> usize {
+        let socket = match self.socket {
+            Some(ref socket) => Rc::clone(socket),
+            None => return 0,
+        };
+        let mut dispatched = 0;
+        self.recv_pending = false;
+        let mut recv_buf = std::mem::take(&mut self.recv_buf);
+        while self.status == LinkStatus::LinkConnected {
+            if dispatched >= quota {
+                self.recv_pending = true;
+                break;
+            }
+            match socket.recv_from(&mut recv_buf) {
+                Ok((n, addr)) => {
+                    if n < 4 {
+                        continue;
+                    }
+                    let conv = read_u32(&recv_buf, 0);
+                    if conv == 0 {
+                        if n != KCP_HANDSHAKE_LEN {
+                            continue;
+                        }
+                        if recv_buf[4] == KCP_HANDSHAKE_SYN {
+                            self.on_syn(&socket, addr);
+                            continue;
+                        }
+                        //FIN携带会话conv，交给会话处理
+                        let conv = read_u32(&recv_buf, 5);
+                        self.events.push(KcpEvent::Input(conv, addr, recv_buf[..n].to_vec()));
+                        continue;
+                    }
+                    dispatched += 1;
+                    self.events.push(KcpEvent::Input(conv, addr, recv_buf[..n].to_vec()));
+                },
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(ref e) if e.kind() == ErrorKind::ConnectionReset => continue,
+                Err(e) => {
+                    self.on_error(&e.to_string());
+                    break;
+                }
+            }
+        }
+        self.recv_buf = recv_buf;
+        dispatched
+    }
+    fn has_pending(&self) -> bool {
+        self.recv_pending && self.status == LinkStatus::LinkConnected
+    }
+    fn update(&mut self, now: u64) -> bool {
+        if !self.handshakes.is_empty() {
+            self.handshakes.retain(|_, (_, time)| now < *time + KCP_SYN_WINDOW);
+        }
+        self.status == LinkStatus::LinkClosed
+    }
+}
@@ -1,43 +1,64 @@
 #![allow(non_snake_case)]
 #![allow(dead_code)]
 
-use std::rc::Weak;
-use std::cell::RefCell;
+use std::io::ErrorKind;
 use std::net::SocketAddr;
 
-use mio::net::TcpListener;
-
+use crate::socket_unix::{ ListenSocket, StreamSocket };
 use crate::socket_helper::get_fd;
-use crate::socket_mgr::{ SocketObj, SocketMgr, LinkStatus, ErrorFunction, AcceptFunction };
+use crate::socket_stream::FlowControl;
+use crate::socket_filter::{ Cidr, IpFilter };
+use crate::socket_mgr::{ SocketObj, SocketStat, LinkStatus, NodeEvent, ErrorFunction, AcceptFunction };
+
+//fd耗尽等资源错误后的重试间隔
+const ACCEPT_BACKOFF: u64 = 100;
+
+pub struct Accepted {
+    pub listener: u32,
+    pub socket: StreamSocket,
+    pub flow: FlowControl,
+    pub proxy: bool,
+}
 
 pub struct SocketListener {
     pub token: u32,
+    pub accepted: u64,
+    pub rejected: u64,
+    pub errors: u64,
+    pub backoff_time: u64,
+    pub proxy: bool,
     pub status: LinkStatus,
+    pub flow: FlowControl,
+    pub filter: IpFilter,
     pub error_cb: ErrorFunction,
     pub accept_cb: AcceptFunction,
-    pub socket: Option<TcpListener>,
-    pub sockmgr: Weak<RefCell<SocketMgr>>,
+    pub socket: Option<ListenSocket>,
+    accepts: Vec<Accepted>,
+    events: Vec<NodeEvent>,
 }
 
 impl SocketListener {
-    pub fn new(mgr: Weak<RefCell<SocketMgr>>) -> SocketListener {
+    pub fn new() -> SocketListener {
         SocketListener {
             token: 0,
+            accepted: 0,
+            rejected: 0,
+            errors: 0,
+            backoff_time: 0,
+            proxy: false,
             socket: None,
-            sockmgr: mgr,
             error_cb: |_|{},
             accept_cb: |_|{},
             status: LinkStatus::LinkInit,
+            flow: FlowControl::default(),
+            filter: IpFilter::default(),
+            accepts: Vec::new(),
+            events: Vec::new(),
         }
     }
 
     pub fn listen(&mut self, ip: String, port: u32) -> Result<u32, String> {
-        let addr_str = format!("{}:{}", ip, port);
-        let addr: SocketAddr = match addr_str.parse() {
-            Ok(addr) => addr,
-            Err(e) => return Err(e.to_string()),
-        };
-        match TcpListener::bind(addr) {
+        match ListenSocket::bind(&ip, port) {
             Ok(listener) => {
                 self.status = LinkStatus::LinkConnected;
                 self.token = get_fd(&listener);
@@ -50,40 +71,106 @@ impl SocketListener {
         }
     }
 
+    //与连接一样通过on_error通知lua，监听失效不会悄无声息
     fn on_error(&mut self, err: &str) {
         if self.status == LinkStatus::LinkConnected {
             self.status = LinkStatus::LinkClosed;
-            (self.error_cb)(err);
+            self.events.push(NodeEvent::Error(self.token, err.to_string()));
         }
     }
 
+    fn on_accept(&mut self, socket: StreamSocket, addr: Option<SocketAddr>) {
+        if let Some(addr) = addr {
+            if self.filter.check(&addr.ip(), luakit::steady_ms()).is_err() {
+                //drop即关闭
+                self.rejected += 1;
+                return;
+            }
+        }
+        self.accepted += 1;
+        self.accepts.push(Accepted { listener: self.token, socket, flow: self.flow, proxy: self.proxy });
+    }
 }
 
 impl SocketObj for SocketListener {
-    fn close(&mut self) { }
+    //socket文件立即删除，fd在下次wait移除对象时关闭
+    fn close(&mut self) {
+        if let Some(ref mut listener) = self.socket {
+            listener.unlink();
+        }
+        self.status = LinkStatus::LinkClosed;
+    }
     fn get_token(&self) -> u32 { self.token }
     fn is_same_kind(&self, kind: u32)-> bool { return self.token == kind; }
+    fn set_max_packet(&mut self, size: usize) { self.flow.max_packet = size; }
+    fn set_flow_control(&mut self, high: usize, low: usize, backpressure: bool) {
+        self.flow.high_water = high;
+        self.flow.low_water = low.min(high);
+        self.flow.backpressure = backpressure;
+    }
+    fn set_proxy_protocol(&mut self, enable: bool) { self.proxy = enable; }
+    fn add_filter(&mut self, cidr: Cidr, allow: bool) { self.filter.add(cidr, allow); }
+    fn clear_filter(&mut self) { self.filter.clear(); }
+    fn set_rate_limit(&mut self, count: u32, period: u64) { self.filter.set_rate_limit(count, period); }
     fn set_error_callback(&mut self, callback: ErrorFunction) { self.error_cb = callback; }
     fn set_accept_callback(&mut self, callback: AcceptFunction) { self.accept_cb = callback; }
+    fn take_accepts(&mut self, accepts: &mut Vec<Accepted>) {
+        accepts.append(&mut self.accepts);
+    }
+    fn take_events(&mut self, events: &mut Vec<NodeEvent>) {
+        events.append(&mut self.events);
+    }
+    fn stats(&self) -> Option<SocketStat> {
+        let (ip, port) = match self.socket {
+            Some(ref listener) => listener.local_addr(),
+            None => (String::new(), 0),
+        };
+        Some(SocketStat {
+            token: self.token,
+            status: self.status as u8,
+            ip: ip,
+            port: port,
+            accepted: self.accepted,
+            rejected: self.rejected,
+            errors: self.errors,
+            ..Default::default()
+        })
+    }
+    //边缘触发，需要accept到WouldBlock为止
     fn do_recv(&mut self) {
-        
-        if let Some(ref mut listener) = self.socket {
-            if self.status == LinkStatus::LinkConnected {
-                match listener.accept() {
-                    Ok((socket, _addr)) => {
-                        if let Some(mgr) = self.sockmgr.upgrade() {
-                            match mgr.borrow_mut().watch_accepted(get_fd(&socket), socket) {
-                                Err(e) => self.on_error(&e.to_string()),
-                                Ok(token) => (self.accept_cb)(token),
-                            }
-                        }
-                    }
-                    Err(e) => self.on_error(&e.to_string()),
-                }
-
+        while self.status == LinkStatus::LinkConnected {
+            let res = match self.socket {
+                Some(ref listener) => listener.accept(),
+                None => return,
+            };
+            match res {
+                Ok((socket, addr)) => self.on_accept(socket, addr),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(ref e) if e.kind() == ErrorKind::ConnectionAborted => self.errors += 1,
+                Err(ref e) if is_exhausted(e) => {
+                    //资源耗尽时暂停accept，由update到期后重新拉取
+                    self.errors += 1;
+                    self.backoff_time = luakit::steady_ms() + ACCEPT_BACKOFF;
+                    return;
+                },
+                Err(e) => {
+                    self.on_error(&e.to_string());
+                    return;
+                },
             }
         }
     }
-    
-    fn update(&mut self, _now: u64) -> bool { true }
-}
\ No newline at end of file
+    fn update(&mut self, now: u64) -> bool {
+        self.filter.update(now);
+        if self.backoff_time > 0 && now >= self.backoff_time {
+            self.backoff_time = 0;
+            self.do_recv();
+        }
+        self.status == LinkStatus::LinkClosed
+    }
+}
+
+fn is_exhausted(e: &std::io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EMFILE) | Some(libc::ENFILE) | Some(libc::ENOBUFS) | Some(libc::ENOMEM))
+}
@@ -5,19 +5,40 @@
 use std::cell::RefCell;
 use std::time::Duration;
 use std::rc::{ Rc, Weak };
-use std::collections::HashMap;
+use std::collections::{ HashMap, HashSet, VecDeque };
+use std::net::{ IpAddr, SocketAddr };
 
-use mio::net::TcpStream;
 use mio::{ Events, Interest, Poll, Token };
 
-use crate::socket_stream::SocketStream;
-use crate::socket_listener::SocketListener;
+use crate::socket_helper::get_fd;
+use crate::socket_unix::StreamSocket;
+use crate::socket_stream::{ SocketStream, FlowControl };
+use crate::socket_dgram::{ SocketDgram, Datagram, MulticastIface };
+use crate::socket_filter::Cidr;
+use crate::socket_session::{ CallTracker, CallState, StokenPool };
+use crate::socket_listener::{ SocketListener, Accepted };
+use crate::socket_kcp::{ SocketKcp, new_conv };
+use crate::socket_kcp_listener::{ SocketKcpListener, KcpEvent };
+use crate::socket_thread::{ IoThreads, IoEvent, SocketThread, WAKER_TOKEN };
 
 pub type AcceptFunction     = fn(token: u32);
 pub type ErrorFunction      = fn(error: &str);
 pub type PackageFunction    = fn(data: &[u8]);
 pub type ConnectFunction    = fn(ok: bool, reason: &str);
 
+//需要回调lua的连接事件，wait结束后由LuaSocketMgr统一派发，避免回调中重入socket_mgr
+pub enum NodeEvent {
+    Accept(u32, u32),
+    Connect(u32, String),
+    Package(u32, Vec<u8>),
+    Error(u32, String),
+    Backpressure(u32, bool),
+    //迟到的回包: token, session_id, 超时后多久到达(ms)
+    LateResponse(u32, u32, u64),
+    //PROXY协议解析出的真实对端地址: token, ip, port
+    Peer(u32, String, u16),
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum LinkStatus {
     LinkInit        = 0,
@@ -57,27 +78,102 @@ impl From<Prototype> for isize {
     }
 }
 
+//连接统计，时间均为steady_ms
+#[derive(Debug, Clone, Default)]
+pub struct SocketStat {
+    pub token: u32,
+    pub status: u8,
+    pub ip: String,
+    pub port: u16,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub packets_in: u64,
+    pub packets_out: u64,
+    pub send_queue: usize,
+    pub connect_time: u64,
+    pub lastrecv_time: u64,
+    pub accepted: u64,
+    pub rejected: u64,
+    pub errors: u64,
+}
+
 pub trait SocketObj {
     fn close(&mut self);
     fn do_recv(&mut self);
     fn do_send(&mut self) {}
+    //限定本次最多派发quota个包，返回实际派发数
+    fn do_recv_quota(&mut self, quota: usize) -> usize { self.do_recv(); 0 }
+    fn has_pending(&self) -> bool { false }
+    fn handoff(&mut self, event: IoEvent) {}
+    fn pending_packets(&self) -> usize { 0 }
     fn get_token(&self) -> u32;
     fn update(&mut self, now: u64) -> bool;
     fn is_same_kind(&self, kind: u32)-> bool;
     fn send(&mut self, data: &[u8]) {}
     fn sendv(&mut self, items: &Vec<&[u8]>) {}
     fn set_nodelay(&mut self, flag: bool) {}
+    fn sendto(&mut self, data: &[u8], addr: SocketAddr) -> bool { false }
+    fn set_broadcast(&mut self, flag: bool) -> bool { false }
+    fn set_multicast(&mut self, group: IpAddr, iface: MulticastIface, join: bool) -> Result<(), String> { Err("not support".to_string()) }
+    fn set_multicast_loop(&mut self, flag: bool) -> bool { false }
+    fn set_multicast_ttl(&mut self, ttl: u32) -> bool { false }
+    fn take_datagrams(&mut self, datagrams: &mut Vec<Datagram>) {}
     fn set_timeout(&mut self, duration: u64) {}
+    fn set_max_packet(&mut self, size: usize) {}
+    fn set_flow_control(&mut self, high: usize, low: usize, backpressure: bool) {}
+    fn set_kcp_nodelay(&mut self, nodelay: i32, interval: i32, resend: i32, nc: i32) {}
+    fn set_kcp_window(&mut self, sndwnd: u32, rcvwnd: u32) {}
+    fn take_kcp_events(&mut self, events: &mut Vec<KcpEvent>) {}
+    fn kcp_input(&mut self, data: &[u8], addr: SocketAddr) {}
+    fn kcp_accept(&mut self, conv: u32, addr: SocketAddr) -> Option<Box<SocketKcp>> { None }
+    fn take_accepts(&mut self, accepts: &mut Vec<Accepted>) {}
+    fn take_events(&mut self, events: &mut Vec<NodeEvent>) {}
+    fn stats(&self) -> Option<SocketStat> { None }
+    fn is_alive(&self) -> bool { true }
+    fn peer_addr(&self) -> Option<(String, u16)> { None }
+    fn add_filter(&mut self, cidr: Cidr, allow: bool) {}
+    fn set_proxy_protocol(&mut self, enable: bool) {}
+    fn clear_filter(&mut self) {}
+    fn set_rate_limit(&mut self, count: u32, period: u64) {}
     fn set_error_callback(&mut self, callback: ErrorFunction) {}
     fn set_accept_callback(&mut self, callback: AcceptFunction) {}
     fn set_connect_callback(&mut self, callback: ConnectFunction) {}
     fn set_package_callback(&mut self, callback: PackageFunction) {}
 }
 
+#[derive(Default, Clone, Copy)]
+pub struct DispatchBudget {
+    pub packets: usize,
+    pub time: u64,
+}
+
+impl DispatchBudget {
+    fn quota(&self) -> usize {
+        if self.packets == 0 { usize::MAX } else { self.packets }
+    }
+
+    fn deadline(&self, now: u64) -> u64 {
+        if self.time == 0 { u64::MAX } else { now + self.time }
+    }
+}
+
 pub struct SocketMgr {
     m_poll: Poll,
     m_events: Events,
     m_max_count: usize,
+    m_datagrams: Vec<Datagram>,
+    m_kcp_events: Vec<KcpEvent>,
+    m_accepts: Vec<Accepted>,
+    m_node_events: Vec<NodeEvent>,
+    m_rejected: u64,
+    m_calls: CallTracker,
+    m_stokens: StokenPool,
+    m_closed: Vec<u32>,
+    m_budget: DispatchBudget,
+    m_pendings: VecDeque<u32>,
+    m_deferred: usize,
+    m_threads: Option<IoThreads>,
+    m_handoffs: Vec<IoEvent>,
     self_ref: Weak<RefCell<SocketMgr>>,
     m_objects: HashMap<u32, Box<dyn SocketObj>>,
 }
@@ -88,6 +184,19 @@ impl SocketMgr {
             self_ref: Weak::new(),
             m_max_count: max_conn,
             m_objects: HashMap::new(),
+            m_datagrams: Vec::new(),
+            m_kcp_events: Vec::new(),
+            m_accepts: Vec::new(),
+            m_node_events: Vec::new(),
+            m_rejected: 0,
+            m_calls: CallTracker::default(),
+            m_stokens: StokenPool::default(),
+            m_closed: Vec::new(),
+            m_budget: DispatchBudget::default(),
+            m_pendings: VecDeque::new(),
+            m_deferred: 0,
+            m_threads: None,
+            m_handoffs: Vec::new(),
             m_poll: Poll::new().unwrap(),
             m_events: Events::with_capacity(max_conn),
         }));
@@ -97,27 +206,188 @@ impl SocketMgr {
 
     pub fn wait(&mut self, _now: u64, timeout: u64) -> u32 {
         let now = luakit::steady_ms();
-        self.m_objects.retain(|_, obj| {
-            !obj.update(now)
+        let accepts = &mut self.m_accepts;
+        let node_events = &mut self.m_node_events;
+        let closed_tokens = &mut self.m_closed;
+        self.m_objects.retain(|token, obj| {
+            let closed = obj.update(now);
+            //监听者退避重试时在update中accept
+            obj.take_accepts(accepts);
+            obj.take_events(node_events);
+            if closed {
+                closed_tokens.push(*token);
+            }
+            !closed
         });
+        for token in std::mem::take(&mut self.m_closed) {
+            self.m_calls.expire_token(token);
+            self.m_stokens.free(token);
+        }
+        self.m_calls.update(now);
         let mut count = 0;
         let escape = luakit::steady_ms() - now;
-        let timeout = if escape >= timeout {0} else {timeout - escape};
+        //有积压时不阻塞等待
+        let timeout = if escape >= timeout || !self.m_pendings.is_empty() {0} else {timeout - escape};
+        //上次积压的连接排在前面
+        let mut readys = std::mem::take(&mut self.m_pendings);
+        let mut ready_set: HashSet<u32> = readys.iter().copied().collect();
         if let Ok(_) = self.m_poll.poll(&mut self.m_events, Some(Duration::from_millis(timeout))) {
             for event in self.m_events.iter() {
+                //I/O线程的唤醒
+                if event.token() == WAKER_TOKEN {
+                    continue;
+                }
                 count += 1;
                 let token = usize::from(event.token()) as u32;
-                if let Some(obj) = self.m_objects.get_mut(&token) {
-                    if event.is_readable() { obj.do_recv(); }
-                    if event.is_writable() { obj.do_send(); }
+                if event.is_readable() && ready_set.insert(token) {
+                    readys.push_back(token);
+                }
+                if event.is_writable() {
+                    if let Some(obj) = self.m_objects.get_mut(&token) {
+                        obj.do_send();
+                    }
                 }
             }
         }
+        self.dispatch_handoffs(&mut readys, &mut ready_set);
+        self.dispatch_readys(readys, luakit::steady_ms());
+        self.dispatch_accepts();
+        self.dispatch_kcp_events();
+        for obj in self.m_objects.values_mut() {
+            obj.take_events(&mut self.m_node_events);
+        }
         count
     }
+
+    //按轮次在就绪连接间平分包配额，超出包数或时间预算的连接留到下次wait
+    fn dispatch_readys(&mut self, mut readys: VecDeque<u32>, now: u64) {
+        let mut quota = self.m_budget.quota();
+        let deadline = self.m_budget.deadline(now);
+        while !readys.is_empty() && quota > 0 && luakit::steady_ms() < deadline {
+            let share = (quota / readys.len()).max(1);
+            for _ in 0..readys.len() {
+                let token = match readys.pop_front() {
+                    Some(token) => token,
+                    None => break,
+                };
+                if let Some(obj) = self.m_objects.get_mut(&token) {
+                    let dispatched = obj.do_recv_quota(share.min(quota));
+                    obj.take_datagrams(&mut self.m_datagrams);
+                    obj.take_kcp_events(&mut self.m_kcp_events);
+                    obj.take_accepts(&mut self.m_accepts);
+                    quota = quota.saturating_sub(dispatched);
+                    if obj.has_pending() {
+                        readys.push_back(token);
+                    }
+                }
+                if quota == 0 || luakit::steady_ms() >= deadline {
+                    break;
+                }
+            }
+        }
+        self.m_deferred = readys.iter().filter_map(|token| self.m_objects.get(token)).map(|obj| obj.pending_packets()).sum();
+        self.m_pendings = readys;
+    }
+
+    //I/O线程交回的包先缓存在连接对象上，同样受派发预算约束
+    fn dispatch_handoffs(&mut self, readys: &mut VecDeque<u32>, ready_set: &mut HashSet<u32>) {
+        if let Some(ref threads) = self.m_threads {
+            threads.take_events(&mut self.m_handoffs);
+        }
+        for event in std::mem::take(&mut self.m_handoffs) {
+            let token = event.token();
+            let package = matches!(event, IoEvent::Package(..));
+            if let Some(obj) = self.m_objects.get_mut(&token) {
+                obj.handoff(event);
+                if package && ready_set.insert(token) {
+                    readys.push_back(token);
+                }
+            }
+        }
+    }
+
+    //开启后accept的连接交给count个I/O线程收发，只能设置一次
+    pub fn set_io_threads(&mut self, count: usize) -> Result<(), String> {
+        if self.m_threads.is_some() {
+            return Err("io threads already started".to_string());
+        }
+        if count > 0 {
+            self.m_threads = Some(IoThreads::new(count, self.m_poll.registry())?);
+        }
+        Ok(())
+    }
+
+    //packets为每次wait最多派发的包数，time为派发耗时上限(ms)，0表示不限
+    pub fn set_dispatch_budget(&mut self, packets: usize, time: u64) {
+        self.m_budget = DispatchBudget { packets, time };
+    }
+
+    //上次wait结束时积压未派发的包数
+    pub fn get_deferred(&self) -> usize {
+        self.m_deferred
+    }
+
+    //监听者只负责accept，注册以及容量检查放到poll之后，避免重入socket_mgr
+    fn dispatch_accepts(&mut self) {
+        for accepted in std::mem::take(&mut self.m_accepts) {
+            let Accepted { listener, socket, flow, proxy } = accepted;
+            if self.is_full() {
+                //直接丢弃socket，立即关闭连接
+                self.m_rejected += 1;
+                continue;
+            }
+            let token = get_fd(&socket);
+            let watched = match self.m_threads {
+                Some(ref threads) => {
+                    let peer = socket.peer_addr();
+                    let handle = threads.watch(token, socket, flow, proxy);
+                    self.m_objects.insert(token, Box::new(SocketThread::new(token, handle, flow, peer)));
+                    true
+                },
+                None => self.watch_accepted(token, socket, flow, proxy).is_ok(),
+            };
+            if watched {
+                self.m_node_events.push(NodeEvent::Accept(listener, token));
+            }
+        }
+    }
+
+    //kcp会话共用监听者的udp socket，由监听者收包后按conv分发
+    fn dispatch_kcp_events(&mut self) {
+        for event in std::mem::take(&mut self.m_kcp_events) {
+            match event {
+                KcpEvent::Syn(listener, addr) => {
+                    //容量已满时不应答，由客户端握手超时
+                    if self.is_full() {
+                        self.m_rejected += 1;
+                        continue;
+                    }
+                    let conv = loop {
+                        let conv = new_conv();
+                        if !self.m_objects.contains_key(&conv) {
+                            break conv;
+                        }
+                    };
+                    let session = match self.m_objects.get_mut(&listener) {
+                        Some(obj) => obj.kcp_accept(conv, addr),
+                        None => None,
+                    };
+                    if let Some(session) = session {
+                        self.m_objects.insert(conv, session);
+                        self.m_node_events.push(NodeEvent::Accept(listener, conv));
+                    }
+                },
+                KcpEvent::Input(conv, addr, data) => {
+                    if let Some(obj) = self.m_objects.get_mut(&conv) {
+                        obj.kcp_input(&data, addr);
+                    }
+                },
+            }
+        }
+    }
     
     pub fn listen(&mut self, ip: String, port: u32) -> Result<u32, String> {
-        let mut listener = SocketListener::new(self.self_ref.clone());
+        let mut listener = SocketListener::new();
         match listener.listen(ip, port) {
             Ok(fd) => {
                 if let Some(ref mut sock) = listener.socket {
@@ -143,11 +413,11 @@ impl SocketMgr {
         if self.is_full() {
             return Err("socket mgr is full".to_string());
         }
-        let mut connector = SocketStream::new(self.self_ref.clone());
+        let mut connector = SocketStream::new();
         match connector.connect(ip, port, timeout) {
             Ok(fd) => {
                 if let Some(ref mut sock) = connector.socket {
-                    match self.m_poll.registry().register(sock, Token(fd as usize), Interest::WRITABLE) {
+                    match self.m_poll.registry().register(sock, Token(fd as usize), Interest::READABLE | Interest::WRITABLE) {
                         Ok(_) => {
                             self.m_objects.insert(fd, Box::new(connector));
                             return Ok(fd);
@@ -165,25 +435,176 @@ impl SocketMgr {
         }
     }
 
-    pub fn watch_connected(&mut self, token: u32, socket: &mut TcpStream) -> Result<u32, String> {
-        match self.m_poll.registry().register(socket, Token(token as usize), Interest::READABLE) {
-            Ok(_) => return Ok(token),
-            Err(e) => return Err(e.to_string()),
+    pub fn bind_udp(&mut self, ip: String, port: u32) -> Result<u32, String> {
+        if self.is_full() {
+            return Err("socket mgr is full".to_string());
+        }
+        let mut dgram = SocketDgram::new();
+        match dgram.bind(ip, port) {
+            Ok(fd) => {
+                if let Some(ref mut sock) = dgram.socket {
+                    match self.m_poll.registry().register(sock, Token(fd as usize), Interest::READABLE | Interest::WRITABLE) {
+                        Ok(_) => {
+                            self.m_objects.insert(fd, Box::new(dgram));
+                            return Ok(fd);
+                        },
+                        Err(e) => {
+                            return Err(e.to_string());
+                        }
+                    }
+                }
+                Err("bind socket error".to_string())
+            },
+            Err(e) => {
+                Err(e.to_string())
+            }
+        }
+    }
+
+    pub fn listen_kcp(&mut self, ip: String, port: u32) -> Result<u32, String> {
+        let mut listener = SocketKcpListener::new();
+        match listener.listen(ip, port) {
+            Ok(mut sock) => {
+                let fd = listener.token;
+                match self.m_poll.registry().register(&mut sock, Token(fd as usize), Interest::READABLE) {
+                    Ok(_) => {
+                        listener.socket = Some(Rc::new(sock));
+                        self.m_objects.insert(fd, Box::new(listener));
+                        Ok(fd)
+                    },
+                    Err(e) => Err(e.to_string()),
+                }
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn connect_kcp(&mut self, ip: String, port: u32, timeout: u64) -> Result<u32, String> {
+        if self.is_full() {
+            return Err("socket mgr is full".to_string());
+        }
+        let mut connector = SocketKcp::connector();
+        match connector.connect(ip, port, timeout) {
+            Ok(mut sock) => {
+                let fd = connector.token;
+                match self.m_poll.registry().register(&mut sock, Token(fd as usize), Interest::READABLE) {
+                    Ok(_) => {
+                        connector.set_socket(Rc::new(sock));
+                        self.m_objects.insert(fd, Box::new(connector));
+                        Ok(fd)
+                    },
+                    Err(e) => Err(e.to_string()),
+                }
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    //连接的stoken由池分配，存活连接之间不重复，池耗尽时为0
+    pub fn alloc_stoken(&mut self, token: u32) -> u32 {
+        self.m_stokens.alloc(token)
+    }
+
+    //高16位为连接的stoken，低16位为1~0xffff循环的序号，跳过仍在等待的序号
+    //没有stoken或序号全部在等待中时返回None
+    pub fn build_session_id(&self, token: u32, stoken: u32, sindex: &mut u32) -> Option<u32> {
+        if stoken == 0 {
+            return None;
+        }
+        for _ in 0..0xffff {
+            *sindex = *sindex % 0xffff + 1;
+            if !self.m_calls.is_pending(token, stoken | *sindex) {
+                return Some(stoken | *sindex);
+            }
+        }
+        None
+    }
+
+    pub fn track_call(&mut self, token: u32, session_id: u32, timeout: u64) {
+        self.m_calls.track(token, session_id, luakit::steady_ms() + timeout);
+    }
+
+    //迟到的响应返回false并派发on_late_response，未跟踪的session照常返回true
+    pub fn finish_call(&mut self, token: u32, session_id: u32) -> bool {
+        match self.m_calls.finish(token, session_id, luakit::steady_ms()) {
+            CallState::Late(late) => {
+                self.m_node_events.push(NodeEvent::LateResponse(token, session_id, late));
+                false
+            },
+            _ => true,
         }
     }
 
-    pub fn watch_send(&mut self, token: u32, socket: &mut TcpStream) -> Result<u32, String> {
+    pub fn take_expired_calls(&mut self) -> Vec<(u32, u32, &'static str)> {
+        self.m_calls.take_expired()
+    }
+
+    pub fn stats(&self, token: u32) -> Option<SocketStat> {
+        self.m_objects.get(&token).and_then(|obj| obj.stats())
+    }
+
+    pub fn all_stats(&self) -> Vec<SocketStat> {
+        self.m_objects.values().filter_map(|obj| obj.stats()).collect()
+    }
+
+    pub fn peer_addr(&self, token: u32) -> Option<(String, u16)> {
+        self.m_objects.get(&token).and_then(|obj| obj.peer_addr())
+    }
+
+    pub fn get_rejected(&self) -> u64 {
+        self.m_rejected
+    }
+
+    pub fn get_count(&self) -> usize {
+        self.m_objects.len()
+    }
+
+    pub fn add_filter(&mut self, token: u32, cidr: Cidr, allow: bool) {
+        if let Some(obj) = self.m_objects.get_mut(&token) {
+            obj.add_filter(cidr, allow);
+        }
+    }
+
+    pub fn set_proxy_protocol(&mut self, token: u32, enable: bool) {
+        if let Some(obj) = self.m_objects.get_mut(&token) {
+            obj.set_proxy_protocol(enable);
+        }
+    }
+
+    pub fn clear_filter(&mut self, token: u32) {
+        if let Some(obj) = self.m_objects.get_mut(&token) {
+            obj.clear_filter();
+        }
+    }
+
+    pub fn set_rate_limit(&mut self, token: u32, count: u32, period: u64) {
+        if let Some(obj) = self.m_objects.get_mut(&token) {
+            obj.set_rate_limit(count, period);
+        }
+    }
+
+    pub fn take_node_events(&mut self) -> Vec<NodeEvent> {
+        std::mem::take(&mut self.m_node_events)
+    }
+
+    pub fn take_datagrams(&mut self) -> Vec<Datagram> {
+        std::mem::take(&mut self.m_datagrams)
+    }
+
+    pub fn watch_send(&mut self, token: u32, socket: &mut StreamSocket) -> Result<u32, String> {
         match self.m_poll.registry().register(socket, Token(token as usize), Interest::READABLE | Interest::WRITABLE) {
             Ok(_) => return Ok(token),
             Err(e) => return Err(e.to_string()),
         }
     }
 
-    pub fn watch_accepted(&mut self, token: u32, mut socket: TcpStream) -> Result<u32, String> {
+    pub fn watch_accepted(&mut self, token: u32, mut socket: StreamSocket, flow: FlowControl, proxy: bool) -> Result<u32, String> {
         match self.m_poll.registry().register(&mut socket, Token(token as usize), Interest::READABLE | Interest::WRITABLE) {
             Err(e) => return Err(e.to_string()),
             Ok(_) => {
-                let stream = SocketStream::load(socket, token, self.self_ref.clone());
+                let mut stream = SocketStream::load(socket, token);
+                stream.set_flow(flow);
+                stream.proxy_pending = proxy;
                 self.m_objects.insert(token, Box::new(stream));
                 Ok(token)
             }
@@ -203,6 +624,41 @@ impl SocketMgr {
     }
 
     
+    pub fn sendto(&mut self, token: u32, data: &[u8], addr: SocketAddr) -> bool {
+        if let Some(obj) = self.m_objects.get_mut(&token) {
+            return obj.sendto(data, addr);
+        }
+        false
+    }
+
+    pub fn set_broadcast(&mut self, token: u32, flag: bool) -> bool {
+        if let Some(obj) = self.m_objects.get_mut(&token) {
+            return obj.set_broadcast(flag);
+        }
+        false
+    }
+
+    pub fn set_multicast(&mut self, token: u32, group: IpAddr, iface: MulticastIface, join: bool) -> Result<(), String> {
+        if let Some(obj) = self.m_objects.get_mut(&token) {
+            return obj.set_multicast(group, iface, join);
+        }
+        Err("socket not found".to_string())
+    }
+
+    pub fn set_multicast_loop(&mut self, token: u32, flag: bool) -> bool {
+        if let Some(obj) = self.m_objects.get_mut(&token) {
+            return obj.set_multicast_loop(flag);
+        }
+        false
+    }
+
+    pub fn set_multicast_ttl(&mut self, token: u32, ttl: u32) -> bool {
+        if let Some(obj) = self.m_objects.get_mut(&token) {
+            return obj.set_multicast_ttl(ttl);
+        }
+        false
+    }
+
     pub fn broadcast(&mut self, kind: u32, data: &[u8]) {
         for (_, obj) in self.m_objects.iter_mut() {
             if obj.is_same_kind(kind) {
@@ -217,6 +673,13 @@ impl SocketMgr {
         }
     }
 
+    pub fn is_alive(&self, token: u32) -> bool {
+        match self.m_objects.get(&token) {
+            Some(obj) => obj.is_alive(),
+            None => false,
+        }
+    }
+
     pub fn get_object(&self, token: u32) -> Option<&Box<dyn SocketObj>> {
         self.m_objects.get(&token)
     }
@@ -233,6 +696,30 @@ impl SocketMgr {
         }
     }
 
+    pub fn set_max_packet(&mut self, token: u32, size: usize) {
+        if let Some(obj) = self.m_objects.get_mut(&token) {
+            obj.set_max_packet(size);
+        }
+    }
+
+    pub fn set_flow_control(&mut self, token: u32, high: usize, low: usize, backpressure: bool) {
+        if let Some(obj) = self.m_objects.get_mut(&token) {
+            obj.set_flow_control(high, low, backpressure);
+        }
+    }
+
+    pub fn set_kcp_nodelay(&mut self, token: u32, nodelay: i32, interval: i32, resend: i32, nc: i32) {
+        if let Some(obj) = self.m_objects.get_mut(&token) {
+            obj.set_kcp_nodelay(nodelay, interval, resend, nc);
+        }
+    }
+
+    pub fn set_kcp_window(&mut self, token: u32, sndwnd: u32, rcvwnd: u32) {
+        if let Some(obj) = self.m_objects.get_mut(&token) {
+            obj.set_kcp_window(sndwnd, rcvwnd);
+        }
+    }
+
     pub fn set_nodelay(&mut self, token: u32, flag: bool) {
         if let Some(obj) = self.m_objects.get_mut(&token) {
             obj.set_nodelay(flag);
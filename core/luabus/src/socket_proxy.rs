@@ -0,0 +1,191 @@
+#![allow(non_snake_case)]
+#![allow(dead_code)]
+
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr };
+
+use lua::ternary;
+
+const PROXY_V1_PREFIX: &[u8]    = b"PROXY ";
+const PROXY_V1_MAX: usize       = 107;
+const PROXY_V2_SIGN: &[u8]      = b"\r\n\r\n\0\r\nQUIT\n";
+const PROXY_V2_HEAD: usize      = 16;
+
+//PROXY头需在建立连接后该时间内收齐
+pub const PROXY_HEADER_TIMEOUT: u64 = 5000;
+
+pub enum ProxyResult {
+    //数据不足，等待更多数据
+    Pending,
+    //头部长度以及真实地址，LOCAL/UNKNOWN时地址为空
+    Done(usize, Option<SocketAddr>),
+    Invalid,
+}
+
+//解析HAProxy PROXY协议头，同时支持v1文本和v2二进制格式
+pub fn parse_proxy_header(data: &[u8]) -> ProxyResult {
+    if data.starts_with(PROXY_V2_SIGN) {
+        return parse_v2(data);
+    }
+    if data.starts_with(PROXY_V1_PREFIX) {
+        return parse_v1(data);
+    }
+    //数据不足以判断格式
+    if PROXY_V2_SIGN.starts_with(data) || PROXY_V1_PREFIX.starts_with(data) {
+        return ProxyResult::Pending;
+    }
+    ProxyResult::Invalid
+}
+
+fn parse_v1(data: &[u8]) -> ProxyResult {
+    let limit = data.len().min(PROXY_V1_MAX);
+    let end = match data[..limit].windows(2).position(|w| w == b"\r\n") {
+        Some(end) => end,
+        None => return ternary!(data.len() < PROXY_V1_MAX, ProxyResult::Pending, ProxyResult::Invalid),
+    };
+    let line = match std::str::from_utf8(&data[..end]) {
+        Ok(line) => line,
+        Err(_) => return ProxyResult::Invalid,
+    };
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.get(1) {
+        Some(&"UNKNOWN") => ProxyResult::Done(end + 2, None),
+        Some(&family) if (family == "TCP4" || family == "TCP6") && parts.len() == 6 => {
+            let ip: IpAddr = match parts[2].parse() {
+                Ok(ip) => ip,
+                Err(_) => return ProxyResult::Invalid,
+            };
+            //协议族必须与地址类型一致
+            if ip.is_ipv4() != (family == "TCP4") {
+                return ProxyResult::Invalid;
+            }
+            if parts[3].parse::<IpAddr>().map_or(true, |dst| dst.is_ipv4() != ip.is_ipv4()) {
+                return ProxyResult::Invalid;
+            }
+            let port: u16 = match parts[4].parse() {
+                Ok(port) => port,
+                Err(_) => return ProxyResult::Invalid,
+            };
+            ProxyResult::Done(end + 2, Some(SocketAddr::new(ip, port)))
+        },
+        _ => ProxyResult::Invalid,
+    }
+}
+
+fn parse_v2(data: &[u8]) -> ProxyResult {
+    if data.len() < PROXY_V2_HEAD {
+        return ProxyResult::Pending;
+    }
+    let ver_cmd = data[12];
+    if ver_cmd >> 4 != 2 {
+        return ProxyResult::Invalid;
+    }
+    let len = u16::from_be_bytes([data[14], data[15]]) as usize;
+    let total = PROXY_V2_HEAD + len;
+    if data.len() < total {
+        return ProxyResult::Pending;
+    }
+    //LOCAL命令为负载均衡自身的健康检查，仅支持LOCAL/PROXY两种命令
+    match ver_cmd & 0x0f {
+        0 => return ProxyResult::Done(total, None),
+        1 => {},
+        _ => return ProxyResult::Invalid,
+    }
+    let body = &data[PROXY_V2_HEAD..total];
+    let addr = match data[13] >> 4 {
+        1 if body.len() >= 12 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            Some(SocketAddr::new(IpAddr::V4(ip), u16::from_be_bytes([body[8], body[9]])))
+        },
+        2 if body.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&body[..16]);
+            Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), u16::from_be_bytes([body[32], body[33]])))
+        },
+        _ => None,
+    };
+    ProxyResult::Done(total, addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v2_header(cmd: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut data = PROXY_V2_SIGN.to_vec();
+        data.push(0x20 | cmd);
+        data.push(family);
+        data.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        data.extend_from_slice(body);
+        data
+    }
+
+    #[test]
+    fn v1_tcp4() {
+        let data = b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\nGET";
+        match parse_proxy_header(data) {
+            ProxyResult::Done(len, Some(addr)) => {
+                assert_eq!(len, data.len() - 3);
+                assert_eq!(addr, "192.168.0.1:56324".parse().unwrap());
+            },
+            _ => panic!("parse v1 failed"),
+        }
+    }
+
+    #[test]
+    fn v1_tcp6_and_unknown() {
+        let data = b"PROXY TCP6 ::1 ::2 1000 2000\r\n";
+        assert!(matches!(parse_proxy_header(data), ProxyResult::Done(_, Some(addr)) if addr == "[::1]:1000".parse().unwrap()));
+        let data = b"PROXY UNKNOWN\r\n";
+        assert!(matches!(parse_proxy_header(data), ProxyResult::Done(15, None)));
+    }
+
+    #[test]
+    fn v1_malformed() {
+        assert!(matches!(parse_proxy_header(b"PROXY TCP4 ::1 ::2 1000 2000\r\n"), ProxyResult::Invalid));
+        assert!(matches!(parse_proxy_header(b"PROXY TCP6 1.1.1.1 2.2.2.2 1000 2000\r\n"), ProxyResult::Invalid));
+        assert!(matches!(parse_proxy_header(b"PROXY TCP4 1.1.1.1 2.2.2.2 port 2000\r\n"), ProxyResult::Invalid));
+        assert!(matches!(parse_proxy_header(b"PROXY TCP4 1.1.1.1\r\n"), ProxyResult::Invalid));
+        assert!(matches!(parse_proxy_header(b"GET / HTTP/1.1\r\n"), ProxyResult::Invalid));
+        assert!(matches!(parse_proxy_header(&[b'A'; PROXY_V1_MAX]), ProxyResult::Invalid));
+        let mut long = PROXY_V1_PREFIX.to_vec();
+        long.resize(PROXY_V1_MAX, b' ');
+        assert!(matches!(parse_proxy_header(&long), ProxyResult::Invalid));
+    }
+
+    #[test]
+    fn v1_truncated() {
+        assert!(matches!(parse_proxy_header(b"PRO"), ProxyResult::Pending));
+        assert!(matches!(parse_proxy_header(b"PROXY TCP4 1.1.1.1 2.2.2.2 1000 2000"), ProxyResult::Pending));
+    }
+
+    #[test]
+    fn v2_proxy_and_local() {
+        let body = [10, 0, 0, 1, 10, 0, 0, 2, 0x1f, 0x90, 0, 80];
+        let data = v2_header(1, 0x11, &body);
+        assert!(matches!(parse_proxy_header(&data), ProxyResult::Done(28, Some(addr)) if addr == "10.0.0.1:8080".parse().unwrap()));
+        let mut body6 = [0u8; 36];
+        body6[15] = 1;
+        body6[32] = 0x1f;
+        body6[33] = 0x90;
+        let data = v2_header(1, 0x21, &body6);
+        assert!(matches!(parse_proxy_header(&data), ProxyResult::Done(52, Some(addr)) if addr == "[::1]:8080".parse().unwrap()));
+        let data = v2_header(0, 0x00, &[]);
+        assert!(matches!(parse_proxy_header(&data), ProxyResult::Done(16, None)));
+    }
+
+    #[test]
+    fn v2_malformed() {
+        let mut data = v2_header(2, 0x11, &[0; 12]);
+        assert!(matches!(parse_proxy_header(&data), ProxyResult::Invalid));
+        data[12] = 0x11;
+        assert!(matches!(parse_proxy_header(&data), ProxyResult::Invalid));
+    }
+
+    #[test]
+    fn v2_truncated() {
+        let data = v2_header(1, 0x11, &[0; 12]);
+        assert!(matches!(parse_proxy_header(&data[..8]), ProxyResult::Pending));
+        assert!(matches!(parse_proxy_header(&data[..14]), ProxyResult::Pending));
+        assert!(matches!(parse_proxy_header(&data[..20]), ProxyResult::Pending));
+    }
+}
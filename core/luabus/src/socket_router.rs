@@ -2,8 +2,9 @@
 #![allow(dead_code)]
 
 use std::mem;
+use std::collections::HashMap;
 use std::cell::RefCell;
-use std::rc::{ Rc, Weak };
+use std::rc::Rc;
 
 use crate::socket_mgr::SocketMgr;
 
@@ -14,6 +15,79 @@ pub enum RpcType {
     ForwardMaster,
     ForwardBroadcast,
     ForwardHash,
+    ForwardRegionMaster,
+    ForwardGroup,
+    RouteError,
+}
+
+const FLAG_REQ: u8 = 0x01;
+const FLAG_RES: u8 = 0x02;
+
+//路由失败原因，通过错误帧的target_id回给来源
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RouteError {
+    NoTarget        = 1,    // 目标不存在
+    DeadTarget      = 2,    // 目标连接已断开
+    NoMaster        = 3,    // 没有可用主节点
+}
+
+impl RouteError {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            RouteError::NoTarget => "target not found",
+            RouteError::DeadTarget => "target disconnected",
+            RouteError::NoMaster => "master not found",
+        }
+    }
+}
+
+//主节点选举策略
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MasterPolicy {
+    LowestId        = 0,    // id最小
+    Designated      = 1,    // 指定节点，不在线时退化为id最小
+    LeastLoaded     = 2,    // 上报负载最小
+}
+
+//hash路由模式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HashMode {
+    Modulo          = 0,    // 取模，节点变化时大部分映射改变
+    Consistent      = 1,    // 一致性hash环
+}
+
+impl From<isize> for HashMode {
+    fn from(val: isize) -> HashMode {
+        match val {
+            1 => HashMode::Consistent,
+            _ => HashMode::Modulo,
+        }
+    }
+}
+
+//每个节点在环上的虚拟节点数
+const VIRTUAL_NODES: u32 = 64;
+
+//FNV-1a后再做一次混淆，让相邻id在环上分散
+fn ring_hash(key: u64) -> u32 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in key.to_le_bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    (hash ^ (hash >> 33)) as u32
+}
+
+impl From<isize> for MasterPolicy {
+    fn from(val: isize) -> MasterPolicy {
+        match val {
+            1 => MasterPolicy::Designated,
+            2 => MasterPolicy::LeastLoaded,
+            _ => MasterPolicy::LowestId,
+        }
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -22,22 +96,75 @@ struct ServiceNode {
     token: u32,
     group: u16,
     region: u16,
+    weight: u32,
+}
+
+impl ServiceNode {
+    fn empty() -> ServiceNode {
+        ServiceNode { id: 0, token: 0, group: 0, region: 0, weight: 0 }
+    }
 }
 
 struct ServiceList {
     master: ServiceNode,
+    policy: MasterPolicy,
+    designated: u32,
+    hash_mode: HashMode,
+    ring: Vec<(u32, u32)>,
     nodes: Vec<ServiceNode>,
+    regions: HashMap<u16, ServiceNode>,
+}
+
+//按策略从候选节点中选出主节点
+fn elect(policy: MasterPolicy, designated: u32, nodes: &[&ServiceNode]) -> Option<ServiceNode> {
+    if policy == MasterPolicy::Designated {
+        if let Some(node) = nodes.iter().find(|node| node.id == designated) {
+            return Some(**node);
+        }
+    }
+    if policy == MasterPolicy::LeastLoaded {
+        return nodes.iter().min_by_key(|node| (node.weight, node.id)).map(|node| **node);
+    }
+    nodes.first().map(|node| **node)
 }
 
 #[repr(packed)]
+#[derive(Clone, Copy)]
 pub struct RouterHeaader {
-    len: u32,
-    context: u8,        //高4位为msg_id，低4位为flag
-    session_id: u32,
-    target_id: u32,
+    pub len: u32,
+    pub context: u8,        //高4位为msg_id，低4位为flag
+    pub session_id: u32,
+    pub target_id: u32,
 }
 
+pub const ROUTER_HEADER_LEN: usize = mem::size_of::<RouterHeaader>();
+
 impl RouterHeaader {
+    //从完整包中读出包头，长度不足时返回None
+    pub fn read(data: &[u8]) -> Option<RouterHeaader> {
+        if data.len() < ROUTER_HEADER_LEN {
+            return None;
+        }
+        Some(unsafe { std::ptr::read_unaligned(data.as_ptr() as *const RouterHeaader) })
+    }
+
+    pub fn rpc_type(&self) -> u8 {
+        self.context >> 4
+    }
+
+    pub fn flag(&self) -> u8 {
+        self.context & 0xf
+    }
+
+    pub fn new(context: u8, session_id: u32, target_id: u32, body_len: usize) -> RouterHeaader {
+        RouterHeaader {
+            len: (mem::size_of::<RouterHeaader>() - 4 + body_len) as u32,
+            context: context,
+            session_id: session_id,
+            target_id: target_id,
+        }
+    }
+
     pub fn as_bytes(&self) -> &[u8] {
         unsafe {
             std::slice::from_raw_parts(
@@ -70,8 +197,8 @@ impl TransferHeader {
 
 pub struct SocketRouter {
     router_count: u32,
+    router_total: u64,
     services: Vec<ServiceList>,
-    sockmgr: Weak<RefCell<SocketMgr>>,
 }
 
 fn get_service_id(node_id: u32) -> usize { 
@@ -79,19 +206,24 @@ fn get_service_id(node_id: u32) -> usize {
 }
 
 impl SocketRouter {
-    pub fn new(mgr: Weak<RefCell<SocketMgr>>) -> Rc<RefCell<SocketRouter>> {
+    pub fn new() -> Rc<RefCell<SocketRouter>> {
         Rc::new(RefCell::new(SocketRouter {
-            sockmgr: mgr,
             router_count: 0,
+            router_total: 0,
             services: (0 ..= u8::MAX)
                 .map(|_| ServiceList {
-                    master: ServiceNode { id: 0, token: 0, group: 0, region: 0 },
+                    master: ServiceNode::empty(),
+                    policy: MasterPolicy::LowestId,
+                    designated: 0,
+                    hash_mode: HashMode::Modulo,
+                    ring: Vec::new(),
                     nodes: Vec::new(),
+                    regions: HashMap::new(),
                 }).collect(),
         }))
     }
 
-    pub fn map_token(&mut self, node_id: u32, token: u32) -> u32 {
+    pub fn map_token(&mut self, node_id: u32, token: u32, group: u16, region: u16) -> u32 {
         let service_id = get_service_id(node_id);
         let list = &mut self.services[service_id];
         let nodes = &mut list.nodes;
@@ -99,14 +231,16 @@ impl SocketRouter {
         if idx < nodes.len() && nodes[idx].id == node_id {
             if token > 0 {
                 nodes[idx].token = token;
+                nodes[idx].group = group;
+                nodes[idx].region = region;
             } else {
                 nodes.remove(idx);
             }
-            return self.choose_master(service_id);
+            return self.refresh(service_id);
         }
-        let node = ServiceNode { id: node_id, token: token, group: 0, region: 0 };
+        let node = ServiceNode { id: node_id, token: token, group: group, region: region, weight: 0 };
         nodes.insert(idx, node);
-        return self.choose_master(service_id);
+        return self.refresh(service_id);
     }
 
     pub fn erase(&mut self, node_id: u32) {
@@ -116,103 +250,238 @@ impl SocketRouter {
         let idx = nodes.partition_point(|node| node.id < node_id);
         if idx < nodes.len() && nodes[idx].id == node_id {
             nodes.remove(idx);
-            self.choose_master(service_id);
+            self.refresh(service_id);
         }
     }
 
-    pub fn choose_master(&mut self, service_id: usize) -> u32 {
+    fn refresh(&mut self, service_id: usize) -> u32 {
+        self.build_ring(service_id);
+        self.choose_master(service_id)
+    }
+
+    //节点增删时重建hash环，只在一致性模式下维护
+    fn build_ring(&mut self, service_id: usize) {
         let list = &mut self.services[service_id];
-        let nodes = &mut list.nodes;
+        list.ring.clear();
+        if list.hash_mode != HashMode::Consistent {
+            return;
+        }
+        for node in list.nodes.iter() {
+            for replica in 0..VIRTUAL_NODES {
+                list.ring.push((ring_hash((node.id as u64) << 32 | replica as u64), node.id));
+            }
+        }
+        list.ring.sort_unstable();
+    }
+
+    pub fn set_hash_mode(&mut self, service_id: u8, mode: HashMode) {
+        self.services[service_id as usize].hash_mode = mode;
+        self.build_ring(service_id as usize);
+    }
+
+    fn hash_node(&self, service_id: usize, hash: u32) -> Option<ServiceNode> {
+        let list = &self.services[service_id];
+        let nodes = &list.nodes;
         if nodes.is_empty() {
-            list.master = ServiceNode { id: 0, token: 0, group: 0, region: 0 };
-            return 0;
+            return None;
+        }
+        if list.hash_mode == HashMode::Modulo || list.ring.is_empty() {
+            return Some(nodes[hash as usize % nodes.len()]);
         }
-        list.master = nodes[0].clone();
+        let key = ring_hash(hash as u64);
+        let pos = list.ring.partition_point(|(h, _)| *h < key);
+        let (_, node_id) = list.ring[pos % list.ring.len()];
+        let idx = nodes.partition_point(|node| node.id < node_id);
+        Some(nodes[idx])
+    }
+
+    pub fn set_master_policy(&mut self, service_id: u8, policy: MasterPolicy, designated: u32) -> u32 {
+        let list = &mut self.services[service_id as usize];
+        list.policy = policy;
+        list.designated = designated;
+        self.choose_master(service_id as usize)
+    }
+
+    //上报负载，LeastLoaded策略下会触发重新选举
+    pub fn set_node_weight(&mut self, node_id: u32, weight: u32) -> u32 {
+        let service_id = get_service_id(node_id);
+        let list = &mut self.services[service_id];
+        let idx = list.nodes.partition_point(|node| node.id < node_id);
+        if idx < list.nodes.len() && list.nodes[idx].id == node_id {
+            list.nodes[idx].weight = weight;
+            if list.policy == MasterPolicy::LeastLoaded {
+                return self.choose_master(service_id);
+            }
+        }
+        list.master.id
+    }
+
+    pub fn get_master(&self, service_id: u8, region: u16) -> u32 {
+        let list = &self.services[service_id as usize];
+        match list.regions.get(&region) {
+            Some(master) if region > 0 => master.id,
+            _ => list.master.id,
+        }
+    }
+
+    pub fn choose_master(&mut self, service_id: usize) -> u32 {
+        let list = &mut self.services[service_id];
+        let nodes: Vec<&ServiceNode> = list.nodes.iter().collect();
+        list.master = elect(list.policy, list.designated, &nodes).unwrap_or(ServiceNode::empty());
+        //各region单独选主
+        let mut regions: HashMap<u16, Vec<&ServiceNode>> = HashMap::new();
+        for node in list.nodes.iter() {
+            regions.entry(node.region).or_default().push(node);
+        }
+        list.regions = regions.into_iter()
+            .filter_map(|(region, nodes)| elect(list.policy, list.designated, &nodes).map(|master| (region, master)))
+            .collect();
         return list.master.id;
     }
 
-    pub fn do_forward_target(&mut self, header: &mut RouterHeaader, data: &[u8]) ->bool {
+    //转发前检查连接是否存活，socket_mgr由调用方传入，避免在wait中重复借用
+    fn is_alive(mgr: &SocketMgr, token: u32) -> bool {
+        token != 0 && mgr.is_alive(token)
+    }
+
+    fn send_forward(&mut self, mgr: &mut SocketMgr, token: u32, header: &mut RouterHeaader, data: &[u8]) -> bool {
+        let flag = header.context & 0xf;
+        header.context = (RpcType::RemoteCall as u8) << 4 | flag;
+        mgr.sendv(token, &vec![header.as_bytes(), data]);
+        self.count_route();
+        true
+    }
+
+    //转发失败时给来源回一个错误帧，target_id为错误码，包体为原因
+    fn forward_failed(&mut self, mgr: &mut SocketMgr, source: u32, header: &RouterHeaader, err: RouteError) -> bool {
+        if source == 0 || (header.context & FLAG_REQ) == 0 {
+            return false;
+        }
+        let reason = err.reason().as_bytes();
+        let context = (RpcType::RouteError as u8) << 4 | FLAG_RES;
+        let error = RouterHeaader::new(context, header.session_id, err as u32, reason.len());
+        mgr.sendv(source, &vec![error.as_bytes(), reason]);
+        false
+    }
+
+    pub fn do_forward_target(&mut self, mgr: &mut SocketMgr, header: &mut RouterHeaader, source: u32, data: &[u8]) ->bool {
         let target_id = header.target_id;
         let service_id = get_service_id(target_id);
-        let list = &mut self.services[service_id];
-        let nodes = &mut list.nodes;
+        let nodes = &self.services[service_id].nodes;
         let idx = nodes.partition_point(|node| node.id < target_id);
-        if idx >= nodes.len() && nodes[idx].id != target_id {
-            return false;
+        if idx >= nodes.len() || nodes[idx].id != target_id {
+            return self.forward_failed(mgr, source, header, RouteError::NoTarget);
         }
-        if let Some(sockmgr) = self.sockmgr.upgrade() {
-            let flag = header.context & 0xf;
-            header.context = (RpcType::RemoteCall as u8) << 4 | flag;
-            sockmgr.borrow_mut().sendv(nodes[idx].token, &vec![header.as_bytes(), data]);
-            self.router_count += 1;
-            return true;
+        let token = nodes[idx].token;
+        if !Self::is_alive(mgr, token) {
+            self.erase(target_id);
+            return self.forward_failed(mgr, source, header, RouteError::DeadTarget);
         }
-        false
+        self.send_forward(mgr, token, header, data)
     }
 
-    pub fn do_forward_master(&mut self, header: &mut RouterHeaader, data: &[u8]) ->bool {
-        let service_id = header.target_id as usize;
-        let token = self.services[service_id].master.token;
-        if token == 0 {
-            return false;
-        }
-        if let Some(sockmgr) = self.sockmgr.upgrade() {
-            let flag = header.context & 0xf;
-            header.context = (RpcType::RemoteCall as u8) << 4 | flag;
-            sockmgr.borrow_mut().sendv(token, &vec![header.as_bytes(), data]);
-            self.router_count += 1;
-            return true;
+    //主节点失效时剔除并重新选举，直到找到存活节点
+    pub fn do_forward_master(&mut self, mgr: &mut SocketMgr, header: &mut RouterHeaader, source: u32, data: &[u8]) ->bool {
+        let service_id = (header.target_id & 0xff) as usize;
+        loop {
+            let master = self.services[service_id].master;
+            if master.token == 0 {
+                return self.forward_failed(mgr, source, header, RouteError::NoMaster);
+            }
+            if Self::is_alive(mgr, master.token) {
+                return self.send_forward(mgr, master.token, header, data);
+            }
+            self.erase(master.id);
         }
-        false
     }
-    pub fn do_forward_hash(&mut self, header: &mut RouterHeaader, data: &[u8]) ->bool {
-        let hash = (header.target_id & 0xffff) as usize;
+
+    pub fn do_forward_hash(&mut self, mgr: &mut SocketMgr, header: &mut RouterHeaader, source: u32, data: &[u8]) ->bool {
+        let hash = header.target_id & 0xffff;
         let service_id = get_service_id(header.target_id);
-        let list = &mut self.services[service_id];
-        let nodes = &mut list.nodes;
-        let count = nodes.len();
-        if count == 0 {
-            return false;
-        }
-        let token = nodes[hash % count].token;
-        if token == 0 {
-            return false;
+        loop {
+            let node = match self.hash_node(service_id, hash) {
+                Some(node) if node.token != 0 => node,
+                _ => return self.forward_failed(mgr, source, header, RouteError::NoTarget),
+            };
+            if Self::is_alive(mgr, node.token) {
+                return self.send_forward(mgr, node.token, header, data);
+            }
+            self.erase(node.id);
         }
-        if let Some(sockmgr) = self.sockmgr.upgrade() {
-            let flag = header.context & 0xf;
-            header.context = (RpcType::RemoteCall as u8) << 4 | flag;
-            sockmgr.borrow_mut().sendv(token, &vec![header.as_bytes(), data]);
-            self.router_count += 1;
-            return true;
+    }
+
+    //target_id高16位为region，低8位为service_id，region无主时退化为全局主节点
+    pub fn do_forward_region_master(&mut self, mgr: &mut SocketMgr, header: &mut RouterHeaader, source: u32, data: &[u8]) ->bool {
+        let service_id = (header.target_id & 0xff) as usize;
+        let region = (header.target_id >> 16) as u16;
+        loop {
+            let list = &self.services[service_id];
+            let master = match list.regions.get(&region) {
+                Some(master) => *master,
+                None => list.master,
+            };
+            if master.token == 0 {
+                return self.forward_failed(mgr, source, header, RouteError::NoMaster);
+            }
+            if Self::is_alive(mgr, master.token) {
+                return self.send_forward(mgr, master.token, header, data);
+            }
+            self.erase(master.id);
         }
-        false
     }
 
-    pub fn do_forward_broadcast(&mut self, header: &mut RouterHeaader, source: u32, data: &[u8], broadcast_num: &mut u32) ->bool {
-        let service_id = header.target_id as usize;
-        let list = &mut self.services[service_id];
-        if list.nodes.is_empty() {
-            return false;
+    //target_id高16位为group，低8位为service_id
+    pub fn do_forward_group(&mut self, mgr: &mut SocketMgr, header: &mut RouterHeaader, source: u32, data: &[u8], broadcast_num: &mut u32) ->bool {
+        let service_id = (header.target_id & 0xff) as usize;
+        let group = (header.target_id >> 16) as u16;
+        self.do_broadcast(mgr, service_id, header, source, data, broadcast_num, |node| node.group == group)
+    }
+
+    pub fn do_forward_broadcast(&mut self, mgr: &mut SocketMgr, header: &mut RouterHeaader, source: u32, data: &[u8], broadcast_num: &mut u32) ->bool {
+        let service_id = header.target_id as usize & 0xff;
+        self.do_broadcast(mgr, service_id, header, source, data, broadcast_num, |_| true)
+    }
+
+    fn do_broadcast<F>(&mut self, mgr: &mut SocketMgr, service_id: usize, header: &mut RouterHeaader, source: u32, data: &[u8], broadcast_num: &mut u32, filter: F) ->bool
+        where F: Fn(&ServiceNode) -> bool {
+        let targets: Vec<ServiceNode> = self.services[service_id].nodes.iter()
+            .filter(|node| node.token != 0 && node.token != source && filter(node)).copied().collect();
+        if targets.is_empty() {
+            return self.forward_failed(mgr, source, header, RouteError::NoTarget);
         }
-        if let Some(sockmgr) = self.sockmgr.upgrade() {
-            let flag = header.context & 0xf;
-            header.context = (RpcType::RemoteCall as u8) << 4 | flag;
-            let send_data = vec![header.as_bytes(), data];
-            let nodes = &mut list.nodes;
-            for node in nodes.iter() {
-                if node.token != 0 && node.token != source {
-                    sockmgr.borrow_mut().sendv(node.token, &send_data);
-                    self.router_count += 1;
-                    *broadcast_num += 1;
-                }
+        let flag = header.context & 0xf;
+        header.context = (RpcType::RemoteCall as u8) << 4 | flag;
+        let mut deads = Vec::new();
+        let send_data = vec![header.as_bytes(), data];
+        for node in targets.iter() {
+            if !Self::is_alive(mgr, node.token) {
+                deads.push(node.id);
+                continue;
             }
+            mgr.sendv(node.token, &send_data);
+            self.count_route();
+            *broadcast_num += 1;
+        }
+        for node_id in deads {
+            self.erase(node_id);
         }
         return *broadcast_num > 0;
     }
 
-    pub fn get_route_count(&mut self) -> u32 { 
+    fn count_route(&mut self) {
+        self.router_count += 1;
+        self.router_total += 1;
+    }
+
+    //读取后清零，供按周期采样
+    pub fn get_route_count(&mut self) -> u32 {
         let old = self.router_count;
         self.router_count = 0;
         old
     }
+
+    //累计转发数，不受get_route_count影响
+    pub fn get_route_total(&self) -> u64 {
+        self.router_total
+    }
 }
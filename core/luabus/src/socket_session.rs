@@ -0,0 +1,150 @@
+#![allow(non_snake_case)]
+#![allow(dead_code)]
+
+use std::collections::{ BTreeSet, HashMap, HashSet };
+
+//超时后保留一段时间，用于识别迟到的响应
+const LATE_WINDOW: u64 = 60000;
+//stoken最高位为1，session_id不小于0x80000000，与thread_mgr的session_id区间不重叠
+const STOKEN_BASE: u32 = 0x8000;
+const STOKEN_COUNT: u32 = 0x8000;
+
+fn call_key(token: u32, session_id: u32) -> u64 {
+    (token as u64) << 32 | session_id as u64
+}
+
+pub enum CallState {
+    Finished,
+    Late(u64),
+    Unknown,
+}
+
+//原生的rpc等待表，按连接+session_id索引，截止时间在socket_mgr.wait中检查
+#[derive(Default)]
+pub struct CallTracker {
+    pending: HashMap<u64, u64>,
+    deadlines: BTreeSet<(u64, u64)>,
+    lates: HashMap<u64, u64>,
+    expired: Vec<(u32, u32, &'static str)>,
+}
+
+impl CallTracker {
+    pub fn is_pending(&self, token: u32, session_id: u32) -> bool {
+        self.pending.contains_key(&call_key(token, session_id))
+    }
+
+    pub fn track(&mut self, token: u32, session_id: u32, deadline: u64) {
+        let key = call_key(token, session_id);
+        if let Some(old) = self.pending.insert(key, deadline) {
+            self.deadlines.remove(&(old, key));
+        }
+        self.deadlines.insert((deadline, key));
+    }
+
+    pub fn finish(&mut self, token: u32, session_id: u32, now: u64) -> CallState {
+        let key = call_key(token, session_id);
+        if let Some(deadline) = self.pending.remove(&key) {
+            self.deadlines.remove(&(deadline, key));
+            return CallState::Finished;
+        }
+        match self.lates.remove(&key) {
+            Some(deadline) => CallState::Late(now.saturating_sub(deadline)),
+            None => CallState::Unknown,
+        }
+    }
+
+    //连接关闭时其所有等待立即失败
+    pub fn expire_token(&mut self, token: u32) {
+        let pending = &mut self.pending;
+        let expired = &mut self.expired;
+        self.deadlines.retain(|(_, key)| {
+            if (key >> 32) as u32 == token {
+                pending.remove(key);
+                expired.push((token, *key as u32, "rpc connection closed"));
+                return false;
+            }
+            true
+        });
+    }
+
+    pub fn update(&mut self, now: u64) {
+        while let Some(&(deadline, key)) = self.deadlines.iter().next() {
+            if deadline > now {
+                break;
+            }
+            self.deadlines.remove(&(deadline, key));
+            self.pending.remove(&key);
+            self.lates.insert(key, deadline);
+            self.expired.push(((key >> 32) as u32, key as u32, "rpc call timeout"));
+        }
+        if !self.lates.is_empty() {
+            self.lates.retain(|_, deadline| now < *deadline + LATE_WINDOW);
+        }
+    }
+
+    pub fn take_expired(&mut self) -> Vec<(u32, u32, &'static str)> {
+        std::mem::take(&mut self.expired)
+    }
+}
+
+//连接的stoken池，作为session_id的高16位
+#[derive(Default)]
+pub struct StokenPool {
+    index: u32,
+    stokens: HashMap<u32, u32>,
+    used: HashSet<u32>,
+}
+
+impl StokenPool {
+    pub fn alloc(&mut self, token: u32) -> u32 {
+        if let Some(stoken) = self.stokens.get(&token) {
+            return *stoken;
+        }
+        for _ in 0..STOKEN_COUNT {
+            self.index = (self.index + 1) % STOKEN_COUNT;
+            let stoken = (STOKEN_BASE | self.index) << 16;
+            if self.used.insert(stoken) {
+                self.stokens.insert(token, stoken);
+                return stoken;
+            }
+        }
+        0
+    }
+
+    pub fn free(&mut self, token: u32) {
+        if let Some(stoken) = self.stokens.remove(&token) {
+            self.used.remove(&stoken);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stoken_unique() {
+        let mut pool = StokenPool::default();
+        let first = pool.alloc(1);
+        assert!(first >= 0x80000000);
+        assert_eq!(pool.alloc(1), first);
+        //token低16位相同也不冲突
+        assert_ne!(pool.alloc(0x10001), first);
+        for token in 2..STOKEN_COUNT {
+            assert_ne!(pool.alloc(token), 0);
+        }
+        assert_eq!(pool.alloc(STOKEN_COUNT + 100), 0);
+        pool.free(1);
+        assert_eq!(pool.alloc(STOKEN_COUNT + 100), first);
+    }
+
+    #[test]
+    fn late_response() {
+        let mut calls = CallTracker::default();
+        calls.track(1, 7, 100);
+        calls.update(150);
+        assert_eq!(calls.take_expired(), vec![(1, 7, "rpc call timeout")]);
+        assert!(matches!(calls.finish(1, 7, 180), CallState::Late(80)));
+        assert!(matches!(calls.finish(1, 7, 190), CallState::Unknown));
+    }
+}
@@ -1,66 +1,119 @@
 #![allow(non_snake_case)]
 #![allow(dead_code)]
 
-use std::rc::Weak;
-use std::cell::RefCell;
-use std::net::SocketAddr;
+use std::io::{ ErrorKind, Read, Write };
 
-use mio::net::TcpStream;
+use luakit::LuaBuf;
 
-use crate::socket_helper::get_fd;
-use crate::socket_mgr::{ SocketObj, SocketMgr, LinkStatus, ConnectFunction, ErrorFunction, PackageFunction };
+use crate::socket_unix::StreamSocket;
+use crate::socket_proxy::{ parse_proxy_header, ProxyResult, PROXY_HEADER_TIMEOUT };
+use crate::socket_codec::load_packet;
+use crate::socket_helper::{ get_fd, SOCKET_RECV_LEN };
+use crate::socket_mgr::{ SocketObj, SocketStat, LinkStatus, NodeEvent };
+
+const SEND_HIGH_WATER: usize    = 8 * 1024 * 1024;  // 8M
+const SEND_LOW_WATER: usize     = 1024 * 1024;      // 1M
+
+//发送缓冲水位以及收包上限，backpressure开启时超过高水位暂停而不是断开
+#[derive(Debug, Clone, Copy)]
+pub struct FlowControl {
+    pub high_water: usize,
+    pub low_water: usize,
+    pub max_packet: usize,
+    pub backpressure: bool,
+}
+
+impl Default for FlowControl {
+    fn default() -> FlowControl {
+        FlowControl { high_water: SEND_HIGH_WATER, low_water: SEND_LOW_WATER, max_packet: 0, backpressure: false }
+    }
+}
 
 pub struct SocketStream {
     pub token: u32,
     pub timeout: u64,
+    pub paused: bool,
+    pub proxy_pending: bool,
+    pub recv_pending: bool,
+    pub quota: usize,
+    pub dispatched: usize,
     pub connect_time: u64,
     pub lastrecv_time: u64,
+    pub established_time: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub packets_in: u64,
+    pub packets_out: u64,
+    pub peer_ip: String,
+    pub peer_port: u16,
+    pub flow: FlowControl,
+    pub send_buffer: LuaBuf,
+    pub recv_buffer: LuaBuf,
     pub status: LinkStatus,
-    pub socket: Option<TcpStream>,
-    pub error_cb: ErrorFunction,
-    pub connect_cb: ConnectFunction,
-    pub package_cb: PackageFunction,
-    pub sockmgr: Weak<RefCell<SocketMgr>>,
+    pub socket: Option<StreamSocket>,
+    pub events: Vec<NodeEvent>,
 }
 
 impl SocketStream {
-    pub fn new(mgr: Weak<RefCell<SocketMgr>>) -> SocketStream {
+    pub fn new() -> SocketStream {
         SocketStream {
             token: 0,
             socket: None,
-            sockmgr: mgr,
             timeout: 0,
+            paused: false,
+            proxy_pending: false,
+            recv_pending: false,
+            quota: usize::MAX,
+            dispatched: 0,
             connect_time: 0,
             lastrecv_time: 0,
+            established_time: 0,
+            bytes_in: 0,
+            bytes_out: 0,
+            packets_in: 0,
+            packets_out: 0,
+            peer_ip: String::new(),
+            peer_port: 0,
+            flow: FlowControl::default(),
+            send_buffer: LuaBuf::new(),
+            recv_buffer: LuaBuf::new(),
             status: LinkStatus::LinkInit,
-            connect_cb: |_,_|{},
-            package_cb: |_|{},
-            error_cb: |_|{}
+            events: Vec::new(),
         }
     }
 
-    pub fn load(sock: TcpStream, token: u32, mgr: Weak<RefCell<SocketMgr>>) -> SocketStream {
+    //accept的连接已建立，直接进入LinkConnected
+    pub fn load(sock: StreamSocket, token: u32) -> SocketStream {
+        let now = luakit::steady_ms();
+        let (peer_ip, peer_port) = sock.peer_addr();
         SocketStream {
             token: token,
-            sockmgr: mgr,
             timeout: 0,
+            paused: false,
+            proxy_pending: false,
+            recv_pending: false,
+            quota: usize::MAX,
+            dispatched: 0,
             connect_time: 0,
-            lastrecv_time: 0,
+            lastrecv_time: now,
+            established_time: now,
+            bytes_in: 0,
+            bytes_out: 0,
+            packets_in: 0,
+            packets_out: 0,
+            peer_ip: peer_ip,
+            peer_port: peer_port,
             socket: Some(sock),
-            status: LinkStatus::LinkInit,
-            connect_cb: |_,_|{},
-            package_cb: |_|{},
-            error_cb: |_|{}
+            flow: FlowControl::default(),
+            send_buffer: LuaBuf::new(),
+            recv_buffer: LuaBuf::new(),
+            status: LinkStatus::LinkConnected,
+            events: Vec::new(),
         }
     }
 
     pub fn connect(&mut self, ip: String, port: u32, timeout: u64) -> Result<u32, String> {
-        let addr_str = format!("{}:{}", ip, port);
-        let addr: SocketAddr = match addr_str.parse() {
-            Ok(addr) => addr,
-            Err(e) => return Err(e.to_string()),
-        };
-        match TcpStream::connect(addr) {
+        match StreamSocket::connect(&ip, port) {
             Ok(stream) => {
                 self.status = LinkStatus::LinkConnecting;
                 self.token = get_fd(&stream);
@@ -74,51 +127,273 @@ impl SocketStream {
         }
     }
 
-    
-    fn send_impl(&mut self) {
+    pub fn set_flow(&mut self, flow: FlowControl) {
+        self.flow = flow;
+    }
 
+    //高水位: 开启背压则通知lua暂停，否则视为溢出断开
+    fn check_water(&mut self, len: usize) -> bool {
+        let size = self.send_buffer.size() + len;
+        if self.flow.high_water == 0 || size <= self.flow.high_water {
+            return true;
+        }
+        if self.flow.backpressure {
+            if !self.paused {
+                self.paused = true;
+                self.events.push(NodeEvent::Backpressure(self.token, true));
+            }
+            return true;
+        }
+        self.on_error("send buffer overflow");
+        false
     }
 
+    fn send_impl(&mut self) {
+        let mut error = None;
+        if let Some(ref mut stream) = self.socket {
+            while !self.send_buffer.empty() {
+                let slice = self.send_buffer.get_slice(None, None);
+                match stream.write(slice.contents()) {
+                    Ok(0) => {
+                        error = Some("connection lost".to_string());
+                        break;
+                    },
+                    Ok(n) => {
+                        self.bytes_out += n as u64;
+                        self.send_buffer.pop_size(n);
+                    },
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        error = Some(e.to_string());
+                        break;
+                    }
+                }
+            }
+        }
+        if let Some(err) = error {
+            self.on_error(&err);
+            return;
+        }
+        if self.paused && self.send_buffer.size() <= self.flow.low_water {
+            self.paused = false;
+            self.events.push(NodeEvent::Backpressure(self.token, false));
+        }
+    }
+
+    //边缘触发，需要读到WouldBlock为止
+    //配额用完时停止读取并标记recv_pending，由socket_mgr下次wait继续
     fn recv_impl(&mut self) {
+        self.recv_pending = false;
+        if !self.proxy_pending {
+            self.dispatch_package();
+        }
+        while self.status == LinkStatus::LinkConnected || self.status == LinkStatus::LinkClosing {
+            if self.quota == 0 {
+                self.recv_pending = true;
+                return;
+            }
+            let mut error = None;
+            let mut drained = false;
+            if let Some(ref mut stream) = self.socket {
+                if let Some(space) = self.recv_buffer.peek_space(SOCKET_RECV_LEN) {
+                    match stream.read(space) {
+                        Ok(0) => error = Some("connection lost".to_string()),
+                        Ok(n) => {
+                            self.bytes_in += n as u64;
+                            self.recv_buffer.pop_space(n);
+                        },
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => drained = true,
+                        Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                        Err(e) => error = Some(e.to_string()),
+                    }
+                } else {
+                    error = Some("recv buffer overflow".to_string());
+                }
+            }
+            if let Some(err) = error {
+                self.on_error(&err);
+                return;
+            }
+            if drained {
+                return;
+            }
+            self.lastrecv_time = luakit::steady_ms();
+            if self.proxy_pending && !self.parse_proxy() {
+                continue;
+            }
+            self.dispatch_package();
+        }
+    }
+
+    //PROXY协议头在codec之前剥离，并以其中地址作为对端地址
+    fn parse_proxy(&mut self) -> bool {
+        let slice = self.recv_buffer.get_slice(None, None);
+        match parse_proxy_header(slice.contents()) {
+            ProxyResult::Pending => false,
+            ProxyResult::Invalid => {
+                self.on_error("invalid proxy header");
+                false
+            },
+            ProxyResult::Done(len, addr) => {
+                if let Some(addr) = addr {
+                    self.peer_ip = addr.ip().to_string();
+                    self.peer_port = addr.port();
+                    self.events.push(NodeEvent::Peer(self.token, self.peer_ip.clone(), self.peer_port));
+                }
+                self.recv_buffer.pop_size(len);
+                self.proxy_pending = false;
+                true
+            },
+        }
+    }
+
+    fn dispatch_package(&mut self) {
+        while self.status == LinkStatus::LinkConnected && self.quota > 0 {
+            let slice = self.recv_buffer.get_slice(None, None);
+            let packet_len = load_packet(slice.contents(), self.flow.max_packet);
+            if packet_len < 0 {
+                self.on_error("packet size overflow");
+                return;
+            }
+            if packet_len == 0 {
+                break;
+            }
+            if let Some(data) = slice.peek(packet_len as usize, 0) {
+                self.packets_in += 1;
+                self.quota -= 1;
+                self.dispatched += 1;
+                self.events.push(NodeEvent::Package(self.token, data.to_vec()));
+            }
+            self.recv_buffer.pop_size(packet_len as usize);
+        }
+    }
+
+    //缓冲中已完整但未派发的包数
+    fn buffered_packets(&self) -> usize {
+        let slice = self.recv_buffer.get_slice(None, None);
+        let data = slice.contents();
+        let (mut offset, mut count) = (0, 0);
+        loop {
+            let packet_len = load_packet(&data[offset..], self.flow.max_packet);
+            if packet_len <= 0 {
+                break;
+            }
+            offset += packet_len as usize;
+            count += 1;
+        }
+        count
     }
 
     fn on_error(&mut self, err: &str) {
         if self.status == LinkStatus::LinkConnected {
             self.status = LinkStatus::LinkClosed;
-            (self.error_cb)(err);
+            self.events.push(NodeEvent::Error(self.token, err.to_string()));
         }
     }
 
     fn on_connect(&mut self, ok: bool,  err: &str) {
         if ok {
             self.lastrecv_time = luakit::steady_ms();
+            self.established_time = self.lastrecv_time;
             self.status = LinkStatus::LinkConnected;
+            if let Some(ref stream) = self.socket {
+                (self.peer_ip, self.peer_port) = stream.peer_addr();
+            }
         } else {
             self.status = LinkStatus::LinkClosed;
         }
-        (self.connect_cb)(ok, err);
+        self.events.push(NodeEvent::Connect(self.token, err.to_string()));
     }
 }
 
 impl SocketObj for SocketStream {
-    fn close(&mut self) { }
-    fn send(&mut self, _data: &[u8]) {}
-    fn sendv(&mut self, _items: &Vec<&[u8]>){}
+    //尽量发出缓冲中的数据，主动关闭不再回调on_error，fd在下次wait移除对象时关闭
+    fn close(&mut self) {
+        if self.status == LinkStatus::LinkConnected {
+            self.status = LinkStatus::LinkClosing;
+            self.send_impl();
+        }
+        self.status = LinkStatus::LinkClosed;
+    }
+    fn send(&mut self, data: &[u8]) {
+        self.sendv(&vec![data]);
+    }
+    fn sendv(&mut self, items: &Vec<&[u8]>) {
+        if self.status != LinkStatus::LinkConnected {
+            return;
+        }
+        let len = items.iter().map(|item| item.len()).sum();
+        if !self.check_water(len) {
+            return;
+        }
+        for item in items.iter() {
+            if !item.is_empty() && self.send_buffer.push_data(item) == 0 {
+                self.on_error("send buffer overflow");
+                return;
+            }
+        }
+        self.packets_out += 1;
+        self.send_impl();
+    }
     fn get_token(&self) -> u32 { self.token }
     fn is_same_kind(&self, kind: u32)-> bool { self.token == kind }
     fn set_timeout(&mut self, duration: u64){ self.timeout = duration; }
-    fn set_error_callback(&mut self, callback: ErrorFunction) { self.error_cb = callback; }
-    fn set_connect_callback(&mut self, callback: ConnectFunction) { self.connect_cb = callback; }
-    fn set_package_callback(&mut self, callback: PackageFunction) { self.package_cb = callback; }
+    fn set_max_packet(&mut self, size: usize) {
+        self.flow.max_packet = size;
+    }
+    fn set_flow_control(&mut self, high: usize, low: usize, backpressure: bool) {
+        self.flow.high_water = high;
+        self.flow.low_water = low.min(high);
+        self.flow.backpressure = backpressure;
+    }
+    fn take_events(&mut self, events: &mut Vec<NodeEvent>) {
+        events.append(&mut self.events);
+    }
+    fn stats(&self) -> Option<SocketStat> {
+        Some(SocketStat {
+            token: self.token,
+            status: self.status as u8,
+            ip: self.peer_ip.clone(),
+            port: self.peer_port,
+            bytes_in: self.bytes_in,
+            bytes_out: self.bytes_out,
+            packets_in: self.packets_in,
+            packets_out: self.packets_out,
+            send_queue: self.send_buffer.size(),
+            connect_time: self.established_time,
+            lastrecv_time: self.lastrecv_time,
+            ..Default::default()
+        })
+    }
+    fn is_alive(&self) -> bool {
+        self.status == LinkStatus::LinkConnected
+    }
+    fn peer_addr(&self) -> Option<(String, u16)> {
+        Some((self.peer_ip.clone(), self.peer_port))
+    }
     fn set_nodelay(&mut self, flag: bool){
         if let Some(ref mut stream) = self.socket {
             let _ = stream.set_nodelay(flag);
         }
     }
     fn do_recv(&mut self) {
+        self.do_recv_quota(usize::MAX);
+    }
+    fn do_recv_quota(&mut self, quota: usize) -> usize {
+        self.quota = quota;
+        self.dispatched = 0;
         if self.status == LinkStatus::LinkConnected || self.status == LinkStatus::LinkClosing {
             self.recv_impl();
         }
+        self.quota = usize::MAX;
+        self.dispatched
+    }
+    fn has_pending(&self) -> bool {
+        self.recv_pending && self.status == LinkStatus::LinkConnected
+    }
+    fn pending_packets(&self) -> usize {
+        if self.has_pending() { self.buffered_packets() } else { 0 }
     }
     fn do_send(&mut self) {
         if let Some(ref mut stream) = self.socket {
@@ -127,19 +402,18 @@ impl SocketObj for SocketStream {
                 return;
             }
             if self.status == LinkStatus::LinkConnecting {
-                match stream.peer_addr() {
-                    Ok(_) => {
-                        if let Some(mgr) = self.sockmgr.upgrade() {
-                            match mgr.borrow_mut().watch_connected(self.token, stream) {
-                                Err(e) => self.on_connect(false, &e.to_string()),
-                                Ok(_) => self.on_connect(true, "ok"),
-                            }
-                        }
-                    },
+                //connect时已同时关注读写，可写即连接完成
+                match stream.check_connected() {
+                    Ok(_) => self.on_connect(true, "ok"),
                     Err(e) => self.on_connect(false, &e.to_string()),
                 }
             }
         }
     }
-    fn update(&mut self, _now: u64) -> bool { true }
-}
\ No newline at end of file
+    fn update(&mut self, now: u64) -> bool {
+        if self.proxy_pending && now >= self.established_time + PROXY_HEADER_TIMEOUT {
+            self.on_error("proxy header timeout");
+        }
+        self.status == LinkStatus::LinkClosed
+    }
+}
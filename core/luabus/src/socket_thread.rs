@@ -0,0 +1,545 @@
+#![allow(non_snake_case)]
+#![allow(dead_code)]
+
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, AtomicU64, AtomicUsize, Ordering };
+use std::time::Duration;
+use std::net::SocketAddr;
+use std::collections::{ HashMap, VecDeque };
+use std::io::{ ErrorKind, Read, Write };
+use std::thread::{ self, JoinHandle };
+use std::sync::mpsc::{ channel, Receiver, Sender };
+
+use mio::{ Events, Interest, Poll, Registry, Token, Waker };
+
+use crate::socket_unix::StreamSocket;
+use crate::socket_stream::FlowControl;
+use crate::socket_codec::load_packet;
+use crate::socket_helper::SOCKET_RECV_LEN;
+use crate::socket_proxy::{ parse_proxy_header, ProxyResult, PROXY_HEADER_TIMEOUT };
+use crate::socket_mgr::{ SocketObj, SocketStat, LinkStatus, NodeEvent };
+
+//waker占用的token，不会与fd冲突
+pub const WAKER_TOKEN: Token    = Token(usize::MAX);
+
+const IO_POLL_TIME: u64         = 100;
+
+//I/O线程积压给lua线程的包数超过高水位时停止读取，lua线程消费到低水位后恢复
+const IO_RECV_HIGH: usize       = 4096;
+const IO_RECV_LOW: usize        = 1024;
+
+pub enum IoCommand {
+    Watch(u32, StreamSocket, FlowControl, bool, Arc<IoShared>),
+    Send(u32, Vec<u8>),
+    Flow(u32, FlowControl),
+    Nodelay(u32, bool),
+    Resume(u32),
+    Close(u32),
+    Stop,
+}
+
+//I/O线程交给lua线程的事件
+pub enum IoEvent {
+    Package(u32, Vec<u8>),
+    Proxy(u32, SocketAddr),
+    Error(u32, String),
+    Backpressure(u32, bool),
+}
+
+impl IoEvent {
+    pub fn token(&self) -> u32 {
+        match self {
+            IoEvent::Package(token, _) | IoEvent::Proxy(token, _) | IoEvent::Error(token, _) | IoEvent::Backpressure(token, _) => *token,
+        }
+    }
+}
+
+//I/O线程与lua线程共享的连接状态
+#[derive(Default)]
+pub struct IoShared {
+    //实际写入socket的字节数
+    bytes_out: AtomicU64,
+    //已交给lua线程但还未派发的包数
+    recv_pending: AtomicUsize,
+    //因积压暂停读取
+    recv_paused: AtomicBool,
+}
+
+//投递命令后唤醒对应的I/O线程
+#[derive(Clone)]
+pub struct IoHandle {
+    commands: Sender<IoCommand>,
+    waker: Arc<Waker>,
+}
+
+impl IoHandle {
+    fn post(&self, cmd: IoCommand) {
+        if self.commands.send(cmd).is_ok() {
+            let _ = self.waker.wake();
+        }
+    }
+}
+
+struct IoWorker {
+    handle: IoHandle,
+    thread: Option<JoinHandle<()>>,
+}
+
+//I/O线程池，每个线程独占一个poll，负责收发以及拆包
+//完整的包通过std::sync::mpsc通道交给lua线程，并唤醒lua线程的poll
+pub struct IoThreads {
+    workers: Vec<IoWorker>,
+    events: Receiver<IoEvent>,
+}
+
+impl IoThreads {
+    pub fn new(count: usize, registry: &Registry) -> Result<IoThreads, String> {
+        let notify = Arc::new(Waker::new(registry, WAKER_TOKEN).map_err(|e| e.to_string())?);
+        let (sender, events) = channel();
+        let mut workers = Vec::with_capacity(count);
+        for index in 0..count {
+            let poll = Poll::new().map_err(|e| e.to_string())?;
+            let waker = Arc::new(Waker::new(poll.registry(), WAKER_TOKEN).map_err(|e| e.to_string())?);
+            let (commands, receiver) = channel();
+            let mut worker = IoWorker { handle: IoHandle { commands, waker }, thread: None };
+            let mut looper = IoLooper {
+                poll: poll,
+                commands: receiver,
+                events: sender.clone(),
+                notify: notify.clone(),
+                streams: HashMap::new(),
+                closed: HashMap::new(),
+            };
+            let thread = thread::Builder::new().name(format!("luabus-io-{}", index)).spawn(move || looper.run());
+            worker.thread = Some(thread.map_err(|e| e.to_string())?);
+            workers.push(worker);
+        }
+        Ok(IoThreads { workers, events })
+    }
+
+    //按token分配线程，同一连接始终在同一线程
+    pub fn watch(&self, token: u32, socket: StreamSocket, flow: FlowControl, proxy: bool) -> (IoHandle, Arc<IoShared>) {
+        let shared = Arc::new(IoShared::default());
+        let worker = &self.workers[token as usize % self.workers.len()];
+        worker.handle.post(IoCommand::Watch(token, socket, flow, proxy, shared.clone()));
+        (worker.handle.clone(), shared)
+    }
+
+    pub fn take_events(&self, events: &mut Vec<IoEvent>) {
+        events.extend(self.events.try_iter());
+    }
+}
+
+impl Drop for IoThreads {
+    fn drop(&mut self) {
+        for worker in self.workers.iter() {
+            worker.handle.post(IoCommand::Stop);
+        }
+        for worker in self.workers.iter_mut() {
+            if let Some(thread) = worker.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+struct IoStream {
+    socket: StreamSocket,
+    flow: FlowControl,
+    shared: Arc<IoShared>,
+    proxy_pending: bool,
+    proxy_deadline: u64,
+    send_paused: bool,
+    recv: Vec<u8>,
+    send: Vec<u8>,
+}
+
+impl IoStream {
+    //返回是否产生了需要通知lua线程的事件
+    fn flush(&mut self, token: u32, events: &Sender<IoEvent>) -> Result<bool, String> {
+        while !self.send.is_empty() {
+            match self.socket.write(&self.send) {
+                Ok(0) => return Err("connection lost".to_string()),
+                Ok(n) => {
+                    self.shared.bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+                    self.send.drain(..n);
+                },
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+        let flow = self.flow;
+        if flow.high_water == 0 {
+            return Ok(false);
+        }
+        if !flow.backpressure {
+            if self.send.len() > flow.high_water {
+                return Err("send buffer overflow".to_string());
+            }
+            return Ok(false);
+        }
+        //超过高水位暂停，降到低水位恢复
+        if !self.send_paused && self.send.len() > flow.high_water {
+            self.send_paused = true;
+            return Ok(events.send(IoEvent::Backpressure(token, true)).is_ok());
+        }
+        if self.send_paused && self.send.len() <= flow.low_water {
+            self.send_paused = false;
+            return Ok(events.send(IoEvent::Backpressure(token, false)).is_ok());
+        }
+        Ok(false)
+    }
+
+    //边缘触发，读到WouldBlock为止；lua线程积压过多时暂停读取，等待Resume
+    fn recv(&mut self, token: u32, events: &Sender<IoEvent>) -> Result<bool, String> {
+        let mut buf = [0u8; SOCKET_RECV_LEN];
+        let mut received = false;
+        loop {
+            if self.shared.recv_pending.load(Ordering::Acquire) >= IO_RECV_HIGH {
+                self.shared.recv_paused.store(true, Ordering::Release);
+                //lua线程可能已在暂停前消费完，抢回暂停标记后继续读取
+                if self.shared.recv_pending.load(Ordering::Acquire) >= IO_RECV_HIGH || !self.shared.recv_paused.swap(false, Ordering::AcqRel) {
+                    break;
+                }
+            }
+            match self.socket.read(&mut buf) {
+                Ok(0) => return Err("connection lost".to_string()),
+                Ok(n) => {
+                    self.recv.extend_from_slice(&buf[..n]);
+                    received |= self.dispatch(token, events)?;
+                },
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+        Ok(received)
+    }
+
+    fn dispatch(&mut self, token: u32, events: &Sender<IoEvent>) -> Result<bool, String> {
+        if self.proxy_pending {
+            match parse_proxy_header(&self.recv) {
+                ProxyResult::Pending => return Ok(false),
+                ProxyResult::Invalid => return Err("invalid proxy header".to_string()),
+                ProxyResult::Done(len, addr) => {
+                    if let Some(addr) = addr {
+                        let _ = events.send(IoEvent::Proxy(token, addr));
+                    }
+                    self.recv.drain(..len);
+                    self.proxy_pending = false;
+                },
+            }
+        }
+        let mut offset = 0;
+        let mut received = false;
+        loop {
+            let packet_len = load_packet(&self.recv[offset..], self.flow.max_packet);
+            if packet_len < 0 {
+                return Err("packet size overflow".to_string());
+            }
+            if packet_len == 0 {
+                break;
+            }
+            let packet_len = packet_len as usize;
+            self.shared.recv_pending.fetch_add(1, Ordering::AcqRel);
+            let _ = events.send(IoEvent::Package(token, self.recv[offset..offset + packet_len].to_vec()));
+            offset += packet_len;
+            received = true;
+        }
+        self.recv.drain(..offset);
+        Ok(received)
+    }
+}
+
+struct IoLooper {
+    poll: Poll,
+    notify: Arc<Waker>,
+    events: Sender<IoEvent>,
+    commands: Receiver<IoCommand>,
+    streams: HashMap<u32, IoStream>,
+    //出错后保留socket，lua线程确认后才关闭，避免fd被复用时token冲突
+    closed: HashMap<u32, StreamSocket>,
+}
+
+impl IoLooper {
+    fn run(&mut self) {
+        let mut events = Events::with_capacity(1024);
+        loop {
+            if let Err(e) = self.poll.poll(&mut events, Some(Duration::from_millis(IO_POLL_TIME))) {
+                if e.kind() != ErrorKind::Interrupted {
+                    return;
+                }
+            }
+            let mut notify = false;
+            while let Ok(cmd) = self.commands.try_recv() {
+                match cmd {
+                    IoCommand::Stop => return,
+                    IoCommand::Watch(token, mut socket, flow, proxy, shared) => {
+                        match self.poll.registry().register(&mut socket, Token(token as usize), Interest::READABLE | Interest::WRITABLE) {
+                            Ok(_) => {
+                                let proxy_deadline = luakit::steady_ms() + PROXY_HEADER_TIMEOUT;
+                                let stream = IoStream {
+                                    socket, flow, shared, proxy_pending: proxy, proxy_deadline,
+                                    send_paused: false, recv: Vec::new(), send: Vec::new(),
+                                };
+                                self.streams.insert(token, stream);
+                            },
+                            Err(e) => {
+                                self.closed.insert(token, socket);
+                                notify |= self.on_error(token, e.to_string());
+                            },
+                        }
+                    },
+                    IoCommand::Send(token, data) => {
+                        let events = &self.events;
+                        let res = match self.streams.get_mut(&token) {
+                            Some(stream) => {
+                                stream.send.extend_from_slice(&data);
+                                stream.flush(token, events)
+                            },
+                            None => Ok(false),
+                        };
+                        match res {
+                            Ok(sent) => notify |= sent,
+                            Err(e) => notify |= self.on_error(token, e),
+                        }
+                    },
+                    IoCommand::Flow(token, flow) => {
+                        if let Some(stream) = self.streams.get_mut(&token) {
+                            stream.flow = flow;
+                        }
+                    },
+                    IoCommand::Nodelay(token, flag) => {
+                        if let Some(stream) = self.streams.get_mut(&token) {
+                            let _ = stream.socket.set_nodelay(flag);
+                        }
+                    },
+                    IoCommand::Resume(token) => {
+                        let events = &self.events;
+                        let res = match self.streams.get_mut(&token) {
+                            Some(stream) => stream.recv(token, events),
+                            None => Ok(false),
+                        };
+                        match res {
+                            Ok(received) => notify |= received,
+                            Err(e) => notify |= self.on_error(token, e),
+                        }
+                    },
+                    IoCommand::Close(token) => {
+                        //尽量发完剩余数据后关闭
+                        if let Some(mut stream) = self.streams.remove(&token) {
+                            let _ = stream.flush(token, &self.events);
+                            let _ = self.poll.registry().deregister(&mut stream.socket);
+                        }
+                        self.closed.remove(&token);
+                    },
+                }
+            }
+            for event in events.iter() {
+                if event.token() == WAKER_TOKEN {
+                    continue;
+                }
+                let token = usize::from(event.token()) as u32;
+                let events = &self.events;
+                let res = match self.streams.get_mut(&token) {
+                    Some(stream) => {
+                        let mut res = Ok(false);
+                        if event.is_writable() {
+                            res = stream.flush(token, events);
+                        }
+                        if event.is_readable() {
+                            if let Ok(sent) = res {
+                                res = stream.recv(token, events).map(|received| sent | received);
+                            }
+                        }
+                        res
+                    },
+                    None => continue,
+                };
+                match res {
+                    Ok(received) => notify |= received,
+                    Err(e) => notify |= self.on_error(token, e),
+                }
+            }
+            //PROXY头超时的连接直接断开
+            let now = luakit::steady_ms();
+            let expired: Vec<u32> = self.streams.iter()
+                .filter(|(_, stream)| stream.proxy_pending && now >= stream.proxy_deadline)
+                .map(|(token, _)| *token).collect();
+            for token in expired {
+                notify |= self.on_error(token, "proxy header timeout".to_string());
+            }
+            if notify {
+                let _ = self.notify.wake();
+            }
+        }
+    }
+
+    fn on_error(&mut self, token: u32, err: String) -> bool {
+        if let Some(mut stream) = self.streams.remove(&token) {
+            let _ = self.poll.registry().deregister(&mut stream.socket);
+            self.closed.insert(token, stream.socket);
+        }
+        self.events.send(IoEvent::Error(token, err)).is_ok()
+    }
+}
+
+//lua线程中代表I/O线程连接的对象，收发转为与I/O线程之间的消息
+pub struct SocketThread {
+    pub token: u32,
+    pub status: LinkStatus,
+    pub flow: FlowControl,
+    pub peer_ip: String,
+    pub peer_port: u16,
+    pub bytes_in: u64,
+    pub packets_in: u64,
+    pub packets_out: u64,
+    pub lastrecv_time: u64,
+    pub established_time: u64,
+    handle: IoHandle,
+    shared: Arc<IoShared>,
+    events: Vec<NodeEvent>,
+    packages: VecDeque<Vec<u8>>,
+}
+
+impl SocketThread {
+    pub fn new(token: u32, handle: (IoHandle, Arc<IoShared>), flow: FlowControl, peer: (String, u16)) -> SocketThread {
+        let now = luakit::steady_ms();
+        SocketThread {
+            token: token,
+            status: LinkStatus::LinkConnected,
+            flow: flow,
+            peer_ip: peer.0,
+            peer_port: peer.1,
+            bytes_in: 0,
+            packets_in: 0,
+            packets_out: 0,
+            lastrecv_time: now,
+            established_time: now,
+            handle: handle.0,
+            shared: handle.1,
+            events: Vec::new(),
+            packages: VecDeque::new(),
+        }
+    }
+}
+
+//对象移除后才通知I/O线程关闭socket，此前fd不会被新连接复用
+impl Drop for SocketThread {
+    fn drop(&mut self) {
+        self.handle.post(IoCommand::Close(self.token));
+    }
+}
+
+impl SocketObj for SocketThread {
+    fn close(&mut self) {
+        self.status = LinkStatus::LinkClosed;
+    }
+    fn get_token(&self) -> u32 { self.token }
+    fn is_same_kind(&self, kind: u32)-> bool { self.token == kind }
+    fn send(&mut self, data: &[u8]) {
+        self.sendv(&vec![data]);
+    }
+    fn sendv(&mut self, items: &Vec<&[u8]>) {
+        if self.status != LinkStatus::LinkConnected {
+            return;
+        }
+        let data = items.concat();
+        self.packets_out += 1;
+        self.handle.post(IoCommand::Send(self.token, data));
+    }
+    fn set_max_packet(&mut self, size: usize) {
+        self.flow.max_packet = size;
+        self.handle.post(IoCommand::Flow(self.token, self.flow));
+    }
+    fn set_flow_control(&mut self, high: usize, low: usize, backpressure: bool) {
+        self.flow.high_water = high;
+        self.flow.low_water = low.min(high);
+        self.flow.backpressure = backpressure;
+        self.handle.post(IoCommand::Flow(self.token, self.flow));
+    }
+    fn set_nodelay(&mut self, flag: bool) {
+        self.handle.post(IoCommand::Nodelay(self.token, flag));
+    }
+    fn handoff(&mut self, event: IoEvent) {
+        match event {
+            IoEvent::Package(_, data) => {
+                self.lastrecv_time = luakit::steady_ms();
+                self.bytes_in += data.len() as u64;
+                self.packages.push_back(data);
+            },
+            IoEvent::Proxy(_, addr) => {
+                self.peer_ip = addr.ip().to_string();
+                self.peer_port = addr.port();
+                self.events.push(NodeEvent::Peer(self.token, self.peer_ip.clone(), self.peer_port));
+            },
+            IoEvent::Error(_, err) => {
+                if self.status == LinkStatus::LinkConnected {
+                    self.status = LinkStatus::LinkClosed;
+                    self.events.push(NodeEvent::Error(self.token, err));
+                }
+            },
+            IoEvent::Backpressure(_, paused) => {
+                if self.status == LinkStatus::LinkConnected {
+                    self.events.push(NodeEvent::Backpressure(self.token, paused));
+                }
+            },
+        }
+    }
+    fn do_recv(&mut self) {
+        self.do_recv_quota(usize::MAX);
+    }
+    fn do_recv_quota(&mut self, quota: usize) -> usize {
+        let mut count = 0;
+        while count < quota && self.status == LinkStatus::LinkConnected {
+            match self.packages.pop_front() {
+                Some(data) => {
+                    count += 1;
+                    self.packets_in += 1;
+                    self.events.push(NodeEvent::Package(self.token, data));
+                },
+                None => break,
+            }
+        }
+        //积压降到低水位后恢复I/O线程的读取
+        let pending = self.shared.recv_pending.fetch_sub(count, Ordering::AcqRel) - count;
+        if pending <= IO_RECV_LOW && self.shared.recv_paused.swap(false, Ordering::AcqRel) {
+            self.handle.post(IoCommand::Resume(self.token));
+        }
+        count
+    }
+    fn has_pending(&self) -> bool {
+        !self.packages.is_empty() && self.status == LinkStatus::LinkConnected
+    }
+    fn pending_packets(&self) -> usize {
+        if self.has_pending() { self.packages.len() } else { 0 }
+    }
+    fn stats(&self) -> Option<SocketStat> {
+        Some(SocketStat {
+            token: self.token,
+            status: self.status as u8,
+            ip: self.peer_ip.clone(),
+            port: self.peer_port,
+            bytes_in: self.bytes_in,
+            bytes_out: self.shared.bytes_out.load(Ordering::Relaxed),
+            packets_in: self.packets_in,
+            packets_out: self.packets_out,
+            connect_time: self.established_time,
+            lastrecv_time: self.lastrecv_time,
+            ..Default::default()
+        })
+    }
+    fn is_alive(&self) -> bool {
+        self.status == LinkStatus::LinkConnected
+    }
+    fn peer_addr(&self) -> Option<(String, u16)> {
+        Some((self.peer_ip.clone(), self.peer_port))
+    }
+    fn take_events(&mut self, events: &mut Vec<NodeEvent>) {
+        events.append(&mut self.events);
+    }
+    fn update(&mut self, _now: u64) -> bool {
+        self.status == LinkStatus::LinkClosed
+    }
+}
@@ -26,7 +26,8 @@ impl SocketUdp {
         }
     }
 
-    pub fn close(&self) {
+    pub fn close(&mut self) {
+        self.socket = None;
     }
     
     pub fn set_no_block(&mut self) {
@@ -0,0 +1,246 @@
+#![allow(non_snake_case)]
+#![allow(dead_code)]
+
+use std::io::{ self, Read, Write };
+use std::net::SocketAddr;
+
+use mio::{ Interest, Registry, Token };
+use mio::event::Source;
+use mio::net::{ TcpListener, TcpStream };
+#[cfg(unix)]
+use mio::net::{ UnixListener, UnixStream };
+
+#[cfg(unix)]
+use std::os::unix::io::{ AsRawFd, RawFd };
+#[cfg(windows)]
+use std::os::windows::io::{ AsRawSocket, RawSocket };
+
+pub const UNIX_PREFIX: &str = "unix:";
+
+//unix:/path 形式的地址走unix domain socket
+pub fn unix_path(ip: &str) -> Option<&str> {
+    ip.strip_prefix(UNIX_PREFIX).filter(|path| !path.is_empty())
+}
+
+fn parse_addr(ip: &str, port: u32) -> io::Result<SocketAddr> {
+    format!("{}:{}", ip, port).parse().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+pub enum StreamSocket {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl StreamSocket {
+    pub fn connect(ip: &str, port: u32) -> io::Result<StreamSocket> {
+        #[cfg(unix)]
+        if let Some(path) = unix_path(ip) {
+            return UnixStream::connect(path).map(StreamSocket::Unix);
+        }
+        TcpStream::connect(parse_addr(ip, port)?).map(StreamSocket::Tcp)
+    }
+
+    //非阻塞connect完成后才能取到对端
+    pub fn check_connected(&self) -> io::Result<()> {
+        match self {
+            StreamSocket::Tcp(stream) => stream.peer_addr().map(|_| ()),
+            #[cfg(unix)]
+            StreamSocket::Unix(stream) => stream.peer_addr().map(|_| ()),
+        }
+    }
+
+    //unix socket返回对端路径，端口为0
+    pub fn peer_addr(&self) -> (String, u16) {
+        match self {
+            StreamSocket::Tcp(stream) => match stream.peer_addr() {
+                Ok(addr) => (addr.ip().to_string(), addr.port()),
+                Err(_) => (String::new(), 0),
+            },
+            #[cfg(unix)]
+            StreamSocket::Unix(stream) => match stream.peer_addr() {
+                Ok(addr) => (addr.as_pathname().map(|p| p.display().to_string()).unwrap_or_default(), 0),
+                Err(_) => (String::new(), 0),
+            },
+        }
+    }
+
+    pub fn set_nodelay(&self, flag: bool) -> io::Result<()> {
+        match self {
+            StreamSocket::Tcp(stream) => stream.set_nodelay(flag),
+            #[cfg(unix)]
+            StreamSocket::Unix(_) => Ok(()),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for StreamSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            StreamSocket::Tcp(stream) => stream.as_raw_fd(),
+            StreamSocket::Unix(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+#[cfg(windows)]
+impl AsRawSocket for StreamSocket {
+    fn as_raw_socket(&self) -> RawSocket {
+        match self {
+            StreamSocket::Tcp(stream) => stream.as_raw_socket(),
+        }
+    }
+}
+
+impl Read for StreamSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            StreamSocket::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            StreamSocket::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for StreamSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            StreamSocket::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            StreamSocket::Unix(stream) => stream.write(buf),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            StreamSocket::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            StreamSocket::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+impl Source for StreamSocket {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        match self {
+            StreamSocket::Tcp(stream) => stream.register(registry, token, interests),
+            #[cfg(unix)]
+            StreamSocket::Unix(stream) => stream.register(registry, token, interests),
+        }
+    }
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        match self {
+            StreamSocket::Tcp(stream) => stream.reregister(registry, token, interests),
+            #[cfg(unix)]
+            StreamSocket::Unix(stream) => stream.reregister(registry, token, interests),
+        }
+    }
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        match self {
+            StreamSocket::Tcp(stream) => stream.deregister(registry),
+            #[cfg(unix)]
+            StreamSocket::Unix(stream) => stream.deregister(registry),
+        }
+    }
+}
+
+pub enum ListenSocket {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, String),
+}
+
+impl ListenSocket {
+    pub fn bind(ip: &str, port: u32) -> io::Result<ListenSocket> {
+        #[cfg(unix)]
+        if let Some(path) = unix_path(ip) {
+            //残留的socket文件无人监听时清理掉
+            if std::path::Path::new(path).exists() && std::os::unix::net::UnixStream::connect(path).is_err() {
+                let _ = std::fs::remove_file(path);
+            }
+            return UnixListener::bind(path).map(|listener| ListenSocket::Unix(listener, path.to_string()));
+        }
+        TcpListener::bind(parse_addr(ip, port)?).map(ListenSocket::Tcp)
+    }
+
+    //unix socket没有对端IP
+    pub fn accept(&self) -> io::Result<(StreamSocket, Option<SocketAddr>)> {
+        match self {
+            ListenSocket::Tcp(listener) => listener.accept().map(|(stream, addr)| (StreamSocket::Tcp(stream), Some(addr))),
+            #[cfg(unix)]
+            ListenSocket::Unix(listener, _) => listener.accept().map(|(stream, _)| (StreamSocket::Unix(stream), None)),
+        }
+    }
+
+    pub fn local_addr(&self) -> (String, u16) {
+        match self {
+            ListenSocket::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => (addr.ip().to_string(), addr.port()),
+                Err(_) => (String::new(), 0),
+            },
+            #[cfg(unix)]
+            ListenSocket::Unix(_, path) => (path.clone(), 0),
+        }
+    }
+
+    //删除socket文件，只删一次，避免误删之后同路径新建的监听
+    pub fn unlink(&mut self) {
+        #[cfg(unix)]
+        if let ListenSocket::Unix(_, path) = self {
+            if !path.is_empty() {
+                let _ = std::fs::remove_file(path.as_str());
+                path.clear();
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for ListenSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            ListenSocket::Tcp(listener) => listener.as_raw_fd(),
+            ListenSocket::Unix(listener, _) => listener.as_raw_fd(),
+        }
+    }
+}
+
+#[cfg(windows)]
+impl AsRawSocket for ListenSocket {
+    fn as_raw_socket(&self) -> RawSocket {
+        match self {
+            ListenSocket::Tcp(listener) => listener.as_raw_socket(),
+        }
+    }
+}
+
+impl Source for ListenSocket {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        match self {
+            ListenSocket::Tcp(listener) => listener.register(registry, token, interests),
+            #[cfg(unix)]
+            ListenSocket::Unix(listener, _) => listener.register(registry, token, interests),
+        }
+    }
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        match self {
+            ListenSocket::Tcp(listener) => listener.reregister(registry, token, interests),
+            #[cfg(unix)]
+            ListenSocket::Unix(listener, _) => listener.reregister(registry, token, interests),
+        }
+    }
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        match self {
+            ListenSocket::Tcp(listener) => listener.deregister(registry),
+            #[cfg(unix)]
+            ListenSocket::Unix(listener, _) => listener.deregister(registry),
+        }
+    }
+}
+
+//未显式close时在释放时删除socket文件
+impl Drop for ListenSocket {
+    fn drop(&mut self) {
+        self.unlink();
+    }
+}
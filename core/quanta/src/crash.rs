@@ -0,0 +1,130 @@
+#![allow(non_snake_case)]
+#![allow(dead_code)]
+
+use std::ptr;
+use std::panic;
+use std::cell::Cell;
+use std::sync::atomic::{ AtomicBool, AtomicPtr, Ordering };
+
+use lua::{ to_char, lua_State };
+use libc::c_char as char;
+#[cfg(unix)]
+use libc::c_int as int;
+
+extern "C" {
+    fn fatal_logger(msg: *const char);
+    fn fatal_signal(msg: *const u8, len: usize);
+}
+
+#[cfg(unix)]
+const FATAL_SIGNALS: [int; 5] = [libc::SIGSEGV, libc::SIGABRT, libc::SIGBUS, libc::SIGFPE, libc::SIGILL];
+
+static FATALING: AtomicBool = AtomicBool::new(false);
+static MAIN_LUA: AtomicPtr<lua_State> = AtomicPtr::new(ptr::null_mut());
+#[cfg(unix)]
+static mut OLD_ACTIONS: [Option<libc::sigaction>; 5] = [None; 5];
+
+thread_local! {
+    static IS_MAIN: Cell<bool> = const { Cell::new(false) };
+}
+
+//记录主线程的虚拟机，只有在主线程崩溃时才获取lua堆栈
+pub fn set_lua(L: *mut lua_State) {
+    MAIN_LUA.store(L, Ordering::Relaxed);
+    IS_MAIN.with(|main| main.set(true));
+}
+
+fn lua_traceback() -> String {
+    let L = MAIN_LUA.load(Ordering::Relaxed);
+    if L.is_null() || !IS_MAIN.with(|main| main.get()) {
+        return "".to_string();
+    }
+    unsafe {
+        let top = lua::lua_gettop(L);
+        lua::luaL_traceback(L, L, ptr::null(), 1);
+        let traceback = lua::to_utf8(lua::lua_tolstring(L, -1));
+        lua::lua_settop(L, top);
+        traceback
+    }
+}
+
+//panic钩子: 带lua堆栈写入FATAL，并等待日志线程落盘
+//只处理第一次崩溃，避免落盘过程中再次崩溃导致递归
+fn fatal(reason: String) {
+    if FATALING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let traceback = lua_traceback();
+    let msg = match traceback.is_empty() {
+        true => format!("[quanta] {}", reason),
+        false => format!("[quanta] {}\n{}", reason, traceback),
+    };
+    unsafe { fatal_logger(to_char!(msg)) };
+}
+
+//信号处理运行在sigaltstack上，只能调用异步信号安全的函数
+//在栈上拼好消息后写stderr并msync已映射的日志，不分配内存、不加锁
+#[cfg(unix)]
+extern "C" fn on_signal(sig: int) {
+    if !FATALING.swap(true, Ordering::SeqCst) {
+        const PREFIX: &[u8] = b"[quanta] fatal signal ";
+        let mut buf = [0u8; 48];
+        buf[..PREFIX.len()].copy_from_slice(PREFIX);
+        let mut len = PREFIX.len();
+        let mut digits = [0u8; 10];
+        let (mut num, mut count) = (sig.unsigned_abs(), 0);
+        loop {
+            digits[count] = b'0' + (num % 10) as u8;
+            count += 1;
+            num /= 10;
+            if num == 0 {
+                break;
+            }
+        }
+        while count > 0 {
+            count -= 1;
+            buf[len] = digits[count];
+            len += 1;
+        }
+        buf[len] = b'\n';
+        unsafe { fatal_signal(buf.as_ptr(), len + 1) };
+    }
+    //恢复原处理并重新触发信号，保留core dump以及rust的栈溢出提示
+    unsafe {
+        if let Some(i) = FATAL_SIGNALS.iter().position(|s| *s == sig) {
+            if let Some(ref old) = OLD_ACTIONS[i] {
+                libc::sigaction(sig, old, ptr::null_mut());
+            }
+        }
+        libc::raise(sig);
+    }
+}
+
+#[cfg(unix)]
+fn install_signals() {
+    for (i, sig) in FATAL_SIGNALS.iter().enumerate() {
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            let mut old: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = on_signal as *const () as usize;
+            action.sa_flags = libc::SA_ONSTACK;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(*sig, &action, &mut old) == 0 {
+                OLD_ACTIONS[i] = Some(old);
+            }
+        }
+    }
+}
+
+//windows下只依赖panic钩子
+#[cfg(windows)]
+fn install_signals() {}
+
+pub fn install() {
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        fatal(format!("panic: {}", info));
+        hook(info);
+    }));
+    install_signals();
+}
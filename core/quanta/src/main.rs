@@ -7,6 +7,7 @@ extern crate luakit;
 extern crate signal_hook;
 
 mod test;
+mod crash;
 mod quanta;
 
 use std::env;
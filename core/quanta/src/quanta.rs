@@ -90,6 +90,8 @@ impl Quanta {
     pub fn setup(&mut self, argv: Vec<String>) ->bool {
         //初始化日志
         unsafe { init_logger() };
+        //崩溃时日志落盘
+        crate::crash::install();
         //加载配置
         self.load(argv)
     }
@@ -131,6 +133,7 @@ impl Quanta {
 
     pub fn init(&mut self) ->bool {
         //设置环境以及初始一些函数
+        crate::crash::set_lua(self.m_lua.L());
         let mut quanta = self.m_lua.new_table(Some("quanta"));
         quanta.set("master", true);
         quanta.set("thread", "quanta");
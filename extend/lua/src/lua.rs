@@ -10,6 +10,7 @@ use libc::c_int as int;
 use libc::c_void as void;
 use libc::c_char as char;
 use libc::c_uchar as uchar;
+use libc::c_ushort as ushort;
 use libc::size_t as size_t;
 
 use std::ffi::CStr;
@@ -115,6 +116,7 @@ pub struct lua_Debug {
     pub namewhat: *const char,
     pub what: *const char,
     pub source: *const char,
+    pub srclen: size_t,
     pub currentline: int,
     pub linedefined: int,
     pub lastlinedefined: int,
@@ -122,7 +124,10 @@ pub struct lua_Debug {
     pub nparams: uchar,
     pub isvararg: char,
     pub istailcall: char,
-    pub short_src: [char; 60], // i_ci: *CallInfo
+    pub ftransfer: ushort,
+    pub ntransfer: ushort,
+    pub short_src: [char; 60],
+    i_ci: *mut void,
 }
 
 pub struct LuaNil {}
@@ -271,6 +276,8 @@ extern "C" {
     pub fn luaL_loadbufferx(L: *mut lua_State, buff: *const char, sz: size_t, name: *const char, mode: *const char) -> int;
     pub fn luaL_loadfilex(L: *mut lua_State, file: *const char, mode: *const char) -> int;
 
+    pub fn luaL_traceback(L: *mut lua_State, L1: *mut lua_State, msg: *const char, level: int);
+
     pub fn luaL_ref(L: *mut lua_State, t: int) -> int;
     pub fn luaL_unref(L: *mut lua_State, t: int, iref: int);
 
@@ -517,6 +524,7 @@ impl default::Default for lua_Debug {
             namewhat: ptr::null(),
             what: ptr::null(),
             source: ptr::null(),
+            srclen: 0,
             currentline: 0,
             linedefined: 0,
             lastlinedefined: 0,
@@ -524,7 +532,10 @@ impl default::Default for lua_Debug {
             nparams: 0,
             isvararg: 0,
             istailcall: 0,
+            ftransfer: 0,
+            ntransfer: 0,
             short_src: [0; 60],
+            i_ci: ptr::null_mut(),
         }
     }
 }
@@ -8,24 +8,26 @@ extern crate luakit;
 extern crate dyn_fmt;
 
 mod logger;
+mod remote;
 
 use libc::c_int as int;
 use libc::c_char as char;
 
 use std::sync::Arc;
-use std::sync::Mutex;
+use std::ffi::CStr;
 use dyn_fmt::Arguments;
 use once_cell::sync::Lazy;
-use lua::{ ternary, to_string, lua_State };
-use logger::{ LogLevel, RollingType, LogService };
+use lua::{ ternary, to_char, to_string, lua_State };
+use remote::RemoteType;
+use logger::{ LogLevel, LogFormat, RollingType, CompressType, ConsoleMode, LogService };
 use luakit::{ Luakit, LuaRead, LuaPush, LuaPushFn };
 
 const LOG_FLAG_FORMAT: i32 = 1;
 const LOG_FLAG_PRETTY: i32 = 2;
 const LOG_FLAG_MONITOR: i32 = 4;
 
-static S_LOGGER: Lazy<Arc<Mutex<LogService>>> = Lazy::new(|| {
-    Arc::new(Mutex::new(LogService::new()))
+static S_LOGGER: Lazy<Arc<LogService>> = Lazy::new(|| {
+    Arc::new(LogService::new())
 });
 
 fn read_args(L: *mut lua_State, flag: int, index: int) -> String {
@@ -56,6 +58,64 @@ fn read_args(L: *mut lua_State, flag: int, index: int) -> String {
     }
 }
 
+//kv表中的键值作为结构化字段，键按FORMAT读取，避免luaL_tolstring压栈破坏lua_next
+fn read_fields(L: *mut lua_State, index: int) -> Vec<(String, String)> {
+    let mut fields = Vec::new();
+    unsafe {
+        if lua::lua_type(L, index) != lua::LUA_TTABLE {
+            return fields;
+        }
+        lua::lua_pushnil(L);
+        while lua::lua_next(L, index) != 0 {
+            let top = lua::lua_gettop(L);
+            fields.push((read_args(L, LOG_FLAG_FORMAT, top - 1), read_args(L, LOG_FLAG_FORMAT, top)));
+            lua::lua_pop(L, 1);
+        }
+    }
+    fields
+}
+
+//采集指定层级的调用位置: short_src:currentline
+fn read_source(L: *mut lua_State, depth: int) -> (String, i32) {
+    let mut ar = lua::lua_Debug::default();
+    unsafe {
+        if lua::lua_getstack(L, depth, &mut ar) == 0 || lua::lua_getinfo(L, to_char!("Sl"), &mut ar) == 0 {
+            return ("".to_string(), 0);
+        }
+        let source = CStr::from_ptr(ar.short_src.as_ptr()).to_string_lossy().to_string();
+        (source, ar.currentline)
+    }
+}
+
+//kv_index为0时没有字段表
+fn log_print(L: *mut lua_State, kv_index: int) -> int {
+    let level: u32 = LuaRead::lua_to_native(L, 1).unwrap();
+    if S_LOGGER.is_filter(level) {
+        return 0;
+    }
+    let base = ternary!(kv_index > 0, kv_index, 4);
+    let flag: i32 = LuaRead::lua_to_native(L, 2).unwrap();
+    let tag: String = LuaRead::lua_to_native(L, 3).unwrap();
+    let feature: String = LuaRead::lua_to_native(L, 4).unwrap();
+    let vfmt: String = LuaRead::lua_to_native(L, base + 1).unwrap();
+    if S_LOGGER.is_muted(&feature, &tag) || S_LOGGER.is_limited(&feature, &vfmt) {
+        return 0;
+    }
+    let argc = unsafe { lua::lua_gettop(L) - base - 1};
+    let mut luaargs: Vec<String> = Vec::new();
+    for i in 0..argc {
+        luaargs.push(read_args(L, flag, base + 2 + i));
+    }
+    let fields = ternary!(kv_index > 0, read_fields(L, kv_index), Vec::new());
+    let msg = Arguments::new(vfmt, &luaargs).to_string();
+    let (source, line) = ternary!(S_LOGGER.is_source(level), read_source(L, S_LOGGER.source_depth()), ("".to_string(), 0));
+    S_LOGGER.output_kv(level, &msg, tag, feature, &source, line, fields);
+    if (flag & LOG_FLAG_MONITOR) == LOG_FLAG_MONITOR {
+        return msg.native_to_lua(L);
+    }
+    0
+}
+
 #[no_mangle]
 pub extern "C" fn luaopen_lualog(L: *mut lua_State) -> int {
     let mut kit = Luakit::load(L);
@@ -74,76 +134,134 @@ pub extern "C" fn luaopen_lualog(L: *mut lua_State) -> int {
         "PRETTY", LOG_FLAG_PRETTY,
         "MONITOR", LOG_FLAG_MONITOR
     );
+    luakit::new_enum!(lualog, "LOG_FORMAT",
+        "TEXT", LogFormat::TEXT,
+        "JSON", LogFormat::JSON,
+        "LOGFMT", LogFormat::LOGFMT
+    );
     luakit::new_enum!(lualog, "ROLLING_TYPE",
+        "NONE", RollingType::NONE,
         "DAYLY", RollingType::DAYLY,
-        "HOURLY", RollingType::HOURLY
+        "HOURLY", RollingType::HOURLY,
+        "SIZE", RollingType::SIZE,
+        "MINUTE", RollingType::MINUTE
+    );
+    luakit::new_enum!(lualog, "COMPRESS_TYPE",
+        "NONE", CompressType::NONE,
+        "ZSTD", CompressType::ZSTD
+    );
+    luakit::new_enum!(lualog, "CONSOLE_MODE",
+        "OFF", ConsoleMode::OFF,
+        "PLAIN", ConsoleMode::PLAIN,
+        "COLOR", ConsoleMode::COLOR,
+        "AUTO", ConsoleMode::AUTO
     );
-    luakit::set_function!(lualog, "daemon", |status : bool | {
-        S_LOGGER.lock().unwrap().daemon(status);
+    luakit::new_enum!(lualog, "REMOTE_TYPE",
+        "SYSLOG_UDP", RemoteType::SYSLOG_UDP,
+        "SYSLOG_TCP", RemoteType::SYSLOG_TCP,
+        "LOKI", RemoteType::LOKI
+    );
+    luakit::set_function!(lualog, "set_console", |mode : u32 | {
+        S_LOGGER.writer().set_console(mode);
     });
     luakit::set_function!(lualog, "set_max_size", |size : usize | {
-        S_LOGGER.lock().unwrap().set_max_size(size);
+        S_LOGGER.writer().set_max_size(size);
     });
     luakit::set_function!(lualog, "set_clean_time", |time : u64 | {
-        S_LOGGER.lock().unwrap().set_clean_time(time);
+        S_LOGGER.writer().set_clean_time(time);
+    });
+    luakit::set_function!(lualog, "set_quota", |max_files : usize, max_total : usize | {
+        S_LOGGER.writer().set_quota(max_files, max_total);
+    });
+    luakit::set_function!(lualog, "stats", || {
+        S_LOGGER.stats()
     });
     luakit::set_function!(lualog, "filter", |lv : u32, on: bool | {
-        S_LOGGER.lock().unwrap().filter(lv, on);
+        S_LOGGER.filter(lv, on);
     });
     luakit::set_function!(lualog, "is_filter", |lv : u32 | {
-        S_LOGGER.lock().unwrap().is_filter(lv);
+        S_LOGGER.is_filter(lv)
+    });
+    luakit::set_function!(lualog, "filter_feature", |feature : String, on: bool | {
+        S_LOGGER.filter_feature(feature, on);
+    });
+    luakit::set_function!(lualog, "filter_tag", |tag : String, on: bool | {
+        S_LOGGER.filter_tag(tag, on);
+    });
+    luakit::set_function!(lualog, "set_rate_limit", |rate : u32, burst: u32 | {
+        S_LOGGER.set_rate_limit(rate, burst);
+    });
+    luakit::set_function!(lualog, "set_source", |lv : u32, on: bool | {
+        S_LOGGER.set_source(lv, on);
+    });
+    luakit::set_function!(lualog, "set_source_depth", |depth : i32 | {
+        S_LOGGER.set_source_depth(depth);
     });
     luakit::set_function!(lualog, "set_rolling_type", |tp : u32 | {
-        S_LOGGER.lock().unwrap().set_rolling_type(tp);
+        S_LOGGER.writer().set_rolling_type(tp);
+    });
+    luakit::set_function!(lualog, "set_rolling_minutes", |minutes : u32 | {
+        S_LOGGER.writer().set_rolling_minutes(minutes);
+    });
+    luakit::set_function!(lualog, "rotate", || {
+        S_LOGGER.writer().rotate();
+    });
+    luakit::set_function!(lualog, "set_compress", |compress : u32 | {
+        S_LOGGER.writer().set_compress(compress);
+    });
+    luakit::set_function!(lualog, "set_format", |format : u32 | {
+        S_LOGGER.writer().set_format(format);
+    });
+    luakit::set_function!(lualog, "set_dest_format", |feature : String, format : u32 | {
+        S_LOGGER.writer().set_dest_format(feature, format);
     });
     luakit::set_function!(lualog, "add_dest", |feature : String | {
-        S_LOGGER.lock().unwrap().add_dest(feature);
+        S_LOGGER.writer().add_dest(feature);
     });
     luakit::set_function!(lualog, "del_dest", |feature : String | {
-        S_LOGGER.lock().unwrap().del_dest(feature);
+        S_LOGGER.writer().del_dest(feature);
     });
     luakit::set_function!(lualog, "add_file_dest", |feature : String, fname : String | {
-        S_LOGGER.lock().unwrap().add_file_dest(feature, fname);
+        S_LOGGER.writer().add_file_dest(feature, fname);
     });
     luakit::set_function!(lualog, "set_dest_clean_time", |feature : String, time : u64 | {
-        S_LOGGER.lock().unwrap().set_dest_clean_time(feature, time);
+        S_LOGGER.writer().set_dest_clean_time(feature, time);
+    });
+    luakit::set_function!(lualog, "set_dest_quota", |feature : String, max_files : usize, max_total : usize | {
+        S_LOGGER.writer().set_dest_quota(feature, max_files, max_total);
     });
     luakit::set_function!(lualog, "ignore_prefix", |feature : String, prefix : bool | {
-        S_LOGGER.lock().unwrap().ignore_prefix(feature, prefix);
+        S_LOGGER.writer().ignore_prefix(feature, prefix);
     });
     luakit::set_function!(lualog, "ignore_suffix", |feature : String, suffix : bool | {
-        S_LOGGER.lock().unwrap().ignore_suffix(feature, suffix);
+        S_LOGGER.writer().ignore_suffix(feature, suffix);
     });    
     luakit::set_function!(lualog, "add_lvl_dest", |lv : u32 | {
-        S_LOGGER.lock().unwrap().add_lvl_dest(lv);
+        S_LOGGER.writer().add_lvl_dest(lv);
     });    
     luakit::set_function!(lualog, "del_lvl_dest", |lv : u32 | {
-        S_LOGGER.lock().unwrap().del_lvl_dest(lv);
+        S_LOGGER.writer().del_lvl_dest(lv);
+    });
+    //add_remote_dest(name, type, addr, level) -> ok, err
+    luakit::set_function!(lualog, "add_remote_dest", |name : String, kind : u32, addr : String, lv : u32 | {
+        match S_LOGGER.writer().add_remote_dest(name, kind, addr, lv) {
+            Ok(()) => (true, None),
+            Err(e) => (false, Some(e)),
+        }
+    });
+    luakit::set_function!(lualog, "del_remote_dest", |name : String | {
+        S_LOGGER.writer().del_remote_dest(name);
     });
     luakit::set_function!(lualog, "option", |path: String, service: String, index: String | {
         let logger = Arc::clone(&S_LOGGER);
-        S_LOGGER.lock().unwrap().option(logger, path, service, index);
+        S_LOGGER.option(logger, path, service, index);
     });
     lualog.set_function( "print", | L: *mut lua_State | -> int {
-        let level: u32 = LuaRead::lua_to_native(L, 1).unwrap();
-        if S_LOGGER.lock().unwrap().is_filter(level) {
-            return 0;
-        }
-        let flag: i32 = LuaRead::lua_to_native(L, 2).unwrap();
-        let tag: String = LuaRead::lua_to_native(L, 3).unwrap();
-        let feature: String = LuaRead::lua_to_native(L, 4).unwrap();
-        let vfmt: String = LuaRead::lua_to_native(L, 5).unwrap();
-        let argc = unsafe { lua::lua_gettop(L) - 5};
-        let mut luaargs: Vec<String> = Vec::new();
-        for i in 0..argc {
-            luaargs.push(read_args(L, flag, 6 + i));
-        }
-        let msg = Arguments::new(vfmt, &luaargs).to_string();
-        S_LOGGER.lock().unwrap().output(level, &msg, tag, feature, "", 0);
-        if (flag & LOG_FLAG_MONITOR) == LOG_FLAG_MONITOR {
-            return msg.native_to_lua(L);
-        }
-        0
+        log_print(L, 0)
+    });
+    //print_kv(level, flag, tag, feature, kv, fmt, ...)
+    lualog.set_function( "print_kv", | L: *mut lua_State | -> int {
+        log_print(L, 5)
     });
     lualog.set_function( "format", | L: *mut lua_State | -> int {
         let vfmt: String = LuaRead::lua_to_native(L, 1).unwrap();
@@ -160,25 +278,39 @@ pub extern "C" fn luaopen_lualog(L: *mut lua_State) -> int {
 
 #[no_mangle]
 pub unsafe extern "C" fn init_logger() {
-    S_LOGGER.lock().unwrap().start();
+    S_LOGGER.start();
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn stop_logger() {
-    S_LOGGER.lock().unwrap().stop();
+    S_LOGGER.stop();
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn option_logger(path: *const char, service: *const char, index: *const char) {
     let logger = Arc::clone(&S_LOGGER);
-    S_LOGGER.lock().unwrap().option(logger, to_string(path), to_string(service), to_string(index));
+    S_LOGGER.option(logger, to_string(path), to_string(service), to_string(index));
+}
+
+//崩溃处理中同步落盘
+#[no_mangle]
+pub unsafe extern "C" fn fatal_logger(msg: *const char) {
+    S_LOGGER.fatal(to_string(msg));
+}
+
+//信号处理中调用: 只写stderr并msync已映射的日志，不加锁不分配内存
+#[no_mangle]
+pub unsafe extern "C" fn fatal_signal(msg: *const u8, len: usize) {
+    #[cfg(unix)]
+    libc::write(libc::STDERR_FILENO, msg as *const libc::c_void, len);
+    logger::sync_mapped();
 }
 
 macro_rules! LOG_OUTPUT {
     ($name:ident, $level:expr, $tag:expr, $feature:expr, $source:expr, $line:expr) => {
         #[no_mangle]
         pub unsafe extern "C" fn $name(msg: *const char) {
-            S_LOGGER.lock().unwrap().output($level as u32, &to_string(msg), $tag.to_string(), $feature.to_string(), $source, $line);
+            S_LOGGER.output($level as u32, &to_string(msg), $tag.to_string(), $feature.to_string(), $source, $line);
         }
     };
 }
@@ -3,29 +3,104 @@
 #![allow(dead_code)]
 
 use std::process;
+use std::io::{ self, IsTerminal, Write };
 use lua::ternary;
-use dashmap::DashMap;
+use dashmap::{ DashMap, DashSet };
 use std::path::PathBuf;
-use std::time::Duration;
-use std::sync::{ Arc, Mutex };
-use std::fs::{ self, OpenOptions };
+use std::time::{ Duration, Instant, SystemTime };
+use std::collections::{ HashMap, VecDeque };
+use std::sync::{ Arc, Mutex, MutexGuard };
+use std::sync::atomic::{ AtomicI32, AtomicU32, AtomicU64, AtomicUsize, Ordering };
+use std::sync::mpsc::{ channel, sync_channel, Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError };
+use std::fs::{ self, File, OpenOptions };
 use std::thread::{ self, JoinHandle };
+use crate::remote::RemoteDest;
 use memmap2::{ MmapOptions, MmapMut };
-use chrono::{ Local, DateTime, Datelike, Timelike };
+use chrono::{ Local, DateTime, Timelike, SecondsFormat };
 
 const QUEUE_SIZE: usize = 10000;
+const WAIT_TIME: u64    = 5;
 const PAGE_SIZE: usize  = 65536;
 const MAX_SIZE: usize   = 1024 * 1024 * 16;
 const CLEAN_TIME: u64   = 7 * 24 * 3600;
+const CLEAN_PERIOD: i64 = 60;
+const ROLLING_MINUTES: u32 = 60;
+const SOURCE_DEPTH: i32 = 1;
+const LIMIT_PERIOD: u64 = 1000;
+const LIMIT_IDLE: u64   = 60000;
+const FATAL_WAIT: u64   = 1000;
+const SYNC_MARKER: &str = "sync";
+const DEVOPS_FEATURE: &str = "devops";
 
-const LEVEL_NAMES: [&str; 7] = ["UNKNW", "DEBUG", "INFO", "WARN", "DUMP", "ERROR", "FATAL"];
-const LEVEL_COLORS: [&str; 7] = ["\x1b[32m", "\x1b[37m", "\x1b[32m", "\x1b[33m", "\x1b[33m", "\x1b[31m", "\x1b[32m"];
+pub(crate) const LEVEL_NAMES: [&str; 7] = ["UNKNW", "DEBUG", "INFO", "WARN", "DUMP", "ERROR", "FATAL"];
+const LEVEL_COLORS: [&str; 7] = ["\x1b[32m", "\x1b[37m", "\x1b[32m", "\x1b[33m", "\x1b[33m", "\x1b[31m", "\x1b[35m"];
+const COLOR_RESET: &str = "\x1b[0m";
+const ZSTD_LEVEL: i32 = 3;
+const MAPPED_SLOTS: usize = 64;
+
+//信号处理中不能加锁，已映射的缓冲登记在固定槽位，崩溃时直接msync
+static MAPPED_ADDRS: [AtomicUsize; MAPPED_SLOTS] = [const { AtomicUsize::new(0) }; MAPPED_SLOTS];
+static MAPPED_LENS: [AtomicUsize; MAPPED_SLOTS] = [const { AtomicUsize::new(0) }; MAPPED_SLOTS];
+
+fn register_mapped(map: &MmapMut) -> Option<usize> {
+    let addr = map.as_ptr() as usize;
+    for (slot, saddr) in MAPPED_ADDRS.iter().enumerate() {
+        if saddr.compare_exchange(0, addr, Ordering::AcqRel, Ordering::Relaxed).is_ok() {
+            MAPPED_LENS[slot].store(map.len(), Ordering::Release);
+            return Some(slot);
+        }
+    }
+    None
+}
+
+fn unregister_mapped(slot: usize) {
+    MAPPED_LENS[slot].store(0, Ordering::Release);
+    MAPPED_ADDRS[slot].store(0, Ordering::Release);
+}
+
+//只调用msync，可在信号处理中使用；与日志线程解除映射竞争时msync返回错误，忽略即可
+#[cfg(unix)]
+pub fn sync_mapped() {
+    for (slot, saddr) in MAPPED_ADDRS.iter().enumerate() {
+        let addr = saddr.load(Ordering::Acquire);
+        let len = MAPPED_LENS[slot].load(Ordering::Acquire);
+        if addr != 0 && len > 0 {
+            unsafe { libc::msync(addr as *mut libc::c_void, len, libc::MS_SYNC) };
+        }
+    }
+}
+
+#[cfg(windows)]
+pub fn sync_mapped() {}
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum RollingType {
     NONE,
     DAYLY,
     HOURLY,
+    SIZE,       //只按大小滚动
+    MINUTE,     //每N分钟滚动
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    TEXT,
+    JSON,
+    LOGFMT,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompressType {
+    NONE,
+    ZSTD,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConsoleMode {
+    OFF,
+    PLAIN,
+    COLOR,
+    AUTO,       //终端时彩色，否则无色
 }
 
 pub enum LogLevel {
@@ -37,6 +112,36 @@ pub enum LogLevel {
     FATAL,
 }
 
+pub(crate) fn json_escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+//logfmt的值含空白、引号或等号时加引号
+fn logfmt_escape(text: &str) -> String {
+    if !text.is_empty() && !text.contains(|c: char| c.is_whitespace() || c == '"' || c == '=') {
+        return text.to_string();
+    }
+    json_escape(text)
+}
+
+pub(crate) fn build_kv_text(log: &LogMessage) -> String {
+    log.fields.iter().map(|(k, v)| format!(" {}={}", k, logfmt_escape(v))).collect()
+}
+
 trait LogDest {
     fn build_prefix(&mut self, log: &LogMessage, iprefix: bool) -> String {
         if !iprefix {
@@ -45,21 +150,50 @@ trait LogDest {
         "".to_string()
     }
     fn build_suffix(&mut self, log: &LogMessage, isuffix: bool) -> String {
-        ternary!(isuffix, "".to_string(), format!("[{}:{}] ", log.source, log.line))
+        ternary!(isuffix || log.source.is_empty(), "".to_string(), format!(" [{}:{}]", log.source, log.line))
     }
     fn ignore_prefix(&mut self, prefix: bool){}
     fn ignore_suffix(&mut self, suffix: bool){}
     fn set_clean_time(&mut self, clean_time: u64){}
-    fn raw_write(&mut self, msg: String, lvl: u32) {
-        println!("{}{}", LEVEL_COLORS[lvl as usize], msg);
-    }
+    fn set_quota(&mut self, max_files: usize, max_total: usize){}
+    fn set_format(&mut self, format: u32){}
+    fn set_compress(&mut self, compress: u32){}
+    fn raw_write(&mut self, msg: String, lvl: u32);
     fn write(&mut self, log: &LogMessage);
 }
 
-struct StdDest;
+//ERROR及以上输出到stderr，写失败(如管道关闭)时忽略
+//stdout和stderr可能分别重定向，各自记录是否着色
+struct StdDest {
+    out_color: bool,
+    err_color: bool,
+}
+
+impl StdDest {
+    fn new(mode: u32) -> StdDest {
+        let (out_color, err_color) = match mode {
+            m if m == ConsoleMode::COLOR as u32 => (true, true),
+            m if m == ConsoleMode::AUTO as u32 => (io::stdout().is_terminal(), io::stderr().is_terminal()),
+            _ => (false, false),
+        };
+        StdDest { out_color: out_color, err_color: err_color }
+    }
+}
+
 impl LogDest for StdDest {
+    fn raw_write(&mut self, msg: String, lvl: u32) {
+        let is_err = lvl >= LogLevel::ERROR as u32;
+        let text = match ternary!(is_err, self.err_color, self.out_color) {
+            true => format!("{}{}{}\n", LEVEL_COLORS[lvl as usize], msg, COLOR_RESET),
+            false => format!("{}\n", msg),
+        };
+        let _ = match is_err {
+            true => io::stderr().lock().write_all(text.as_bytes()),
+            false => io::stdout().lock().write_all(text.as_bytes()),
+        };
+    }
     fn write(&mut self, log: &LogMessage) {
-        let logtxt = format!("{} {}{}", self.build_prefix(log, false), log.msg, self.build_suffix(log, true));
+        let logtxt = format!("{} {}{}{}", self.build_prefix(log, false), log.msg, build_kv_text(log), self.build_suffix(log, false));
         self.raw_write(logtxt, log.level);
     }
 }
@@ -69,14 +203,25 @@ struct FileDest {
     max_size: usize,
     alloc_size: usize,
     clean_time: u64,
+    clean_stamp: i64,
+    max_files: usize,
+    max_total: usize,
     feature: String,
     log_path: PathBuf,
     file_path: PathBuf,
     rolling_type: u32,
+    rolling_minutes: u32,
+    fixed: bool,
+    format: u32,
+    compress: u32,
+    closed: VecDeque<PathBuf>,
+    service: String,
+    index: String,
     ignore_suffix: bool,
     ignore_prefix: bool,
     time: DateTime<Local>,
     mapbuf: Option<MmapMut>,
+    map_slot: Option<usize>,
 }
 
 impl FileDest {
@@ -84,16 +229,27 @@ impl FileDest {
         FileDest {
             size: 0,
             mapbuf: None,
+            map_slot: None,
             alloc_size: 0,
             time: Local::now(),
             max_size: MAX_SIZE,
             clean_time: CLEAN_TIME,
+            clean_stamp: 0,
+            max_files: 0,
+            max_total: 0,
             rolling_type: RollingType::DAYLY as u32,
+            rolling_minutes: ROLLING_MINUTES,
+            fixed: false,
+            format: LogFormat::TEXT as u32,
+            compress: CompressType::NONE as u32,
+            closed: VecDeque::new(),
+            service: "".to_string(),
+            index: "".to_string(),
             file_path: PathBuf::from(""),
             log_path: PathBuf::from(""),
             feature: "".to_string(),
             ignore_prefix: false,
-            ignore_suffix: true,
+            ignore_suffix: false,
         }
     }
 
@@ -114,7 +270,52 @@ impl FileDest {
     }
 
     fn unmap_file(&mut self,){
-        self.mapbuf = None;
+        self.take_map();
+    }
+
+    fn take_map(&mut self) -> Option<MmapMut> {
+        if let Some(slot) = self.map_slot.take() {
+            unregister_mapped(slot);
+        }
+        self.mapbuf.take()
+    }
+
+    //关闭时截掉预分配的尾部，避免文件以NUL填充结尾
+    fn close_file(&mut self) {
+        if self.take_map().is_some() {
+            if let Ok(file) = OpenOptions::new().write(true).open(&self.file_path) {
+                let _ = file.set_len(self.size as u64);
+            }
+        }
+    }
+
+    //msync已映射的内容
+    fn sync_file(&mut self) {
+        if let Some(ref map) = self.mapbuf {
+            let _ = map.flush();
+        }
+    }
+
+    //滚动关闭的文件等待压缩
+    fn close_rolled(&mut self) {
+        if self.mapbuf.is_some() {
+            self.close_file();
+            if self.compress != CompressType::NONE as u32 {
+                self.closed.push_back(self.file_path.clone());
+            }
+        }
+    }
+
+    //下次写入时新建文件，指定文件名的目标不参与
+    pub fn rotate(&mut self) {
+        if !self.fixed {
+            self.close_rolled();
+        }
+    }
+
+    //取出待压缩的文件，交给压缩线程
+    fn take_closed(&mut self, paths: &mut Vec<PathBuf>) {
+        paths.extend(self.closed.drain(..));
     }
 
     fn map_file(&mut self,){
@@ -124,7 +325,10 @@ impl FileDest {
                 file.set_len(self.alloc_size as u64).expect("Failed to set file size");
                 let mres = unsafe { MmapOptions::new().map_mut(&file) };
                 match mres {
-                    Ok(map) => {self.mapbuf = Some(map)},
+                    Ok(map) => {
+                        self.map_slot = register_mapped(&map);
+                        self.mapbuf = Some(map);
+                    },
                     Err(e) => {
                         println!("Failed to map file: {}, err: {}", self.file_path.to_str().unwrap(), e);
                     }
@@ -143,13 +347,105 @@ impl FileDest {
         let ltime = log.time;
         let ftime = self.time;
         if self.rolling_type == RollingType::DAYLY as u32 {
-            return ftime.day() != ltime.day() && ftime.month() != ltime.month() && ftime.year() != ltime.year();
+            return ftime.date_naive() != ltime.date_naive();
+        }
+        if self.rolling_type == RollingType::HOURLY as u32 {
+            return ftime.date_naive() != ltime.date_naive() || ftime.hour() != ltime.hour();
+        }
+        if self.rolling_type == RollingType::MINUTE as u32 {
+            //按整N分钟对齐
+            let period = self.rolling_minutes.max(1) as i64 * 60;
+            let offset = ltime.offset().local_minus_utc() as i64;
+            return (ftime.timestamp() + offset) / period != (ltime.timestamp() + offset) / period;
         }
-        return ftime.hour() != ltime.hour() && ftime.day() != ltime.day() && ftime.month() != ltime.month() && ftime.year() != ltime.year();
+        false
     }
     
+    fn build_json(&self, log: &LogMessage) -> String {
+        let mut fields = vec![
+            ("ts", json_escape(&log.time.to_rfc3339_opts(SecondsFormat::Millis, false))),
+            ("level", json_escape(LEVEL_NAMES[log.level as usize])),
+            ("tag", json_escape(&log.tag)),
+            ("feature", json_escape(&log.feature)),
+            ("service", json_escape(&self.service)),
+            ("index", json_escape(&self.index)),
+            ("pid", process::id().to_string()),
+            ("thread", json_escape(&log.thread)),
+        ];
+        if !log.source.is_empty() {
+            fields.push(("source", json_escape(&format!("{}:{}", log.source, log.line))));
+        }
+        fields.push(("message", json_escape(&log.msg)));
+        let mut body: Vec<String> = fields.into_iter().map(|(k, v)| format!("\"{}\":{}", k, v)).collect();
+        //print_kv的字段放在fields下，避免与内置键重名
+        if !log.fields.is_empty() {
+            let kvs: Vec<String> = log.fields.iter().map(|(k, v)| format!("{}:{}", json_escape(k), json_escape(v))).collect();
+            body.push(format!("\"fields\":{{{}}}", kvs.join(",")));
+        }
+        format!("{{{}}}\n", body.join(","))
+    }
+
+    fn build_logfmt(&self, log: &LogMessage) -> String {
+        let mut text = format!("ts={} level={} tag={} feature={} service={} index={} pid={} thread={}",
+            log.time.to_rfc3339_opts(SecondsFormat::Millis, false), LEVEL_NAMES[log.level as usize], logfmt_escape(&log.tag),
+            logfmt_escape(&log.feature), logfmt_escape(&self.service), logfmt_escape(&self.index), process::id(), logfmt_escape(&log.thread));
+        if !log.source.is_empty() {
+            text.push_str(&format!(" source={}:{}", logfmt_escape(&log.source), log.line));
+        }
+        text.push_str(&format!(" msg={}{}\n", logfmt_escape(&log.msg), build_kv_text(log)));
+        text
+    }
+
+    //滚动产生的文件名为 feature-时间.毫秒.p进程号.log，压缩后追加.zst
+    fn is_rolling_file(&self, name: &str) -> bool {
+        match name.strip_prefix(self.feature.as_str()).and_then(|s| s.strip_prefix('-')) {
+            Some(rest) => rest.starts_with(|c: char| c.is_ascii_digit()),
+            None => false,
+        }
+    }
+
+    //定期清理: 删除超过clean_time的文件，以及超出数量/总大小配额的最旧文件
+    pub fn clean(&mut self, now: i64) -> Option<String> {
+        if self.log_path.as_os_str().is_empty() || now < self.clean_stamp + CLEAN_PERIOD {
+            return None;
+        }
+        self.clean_stamp = now;
+        let mut files: Vec<(SystemTime, u64, PathBuf)> = fs::read_dir(&self.log_path).ok()?.filter_map(|entry| {
+            let entry = entry.ok()?;
+            let path = entry.path();
+            if path == self.file_path || !self.is_rolling_file(entry.file_name().to_str()?) {
+                return None;
+            }
+            let meta = entry.metadata().ok()?;
+            ternary!(meta.is_file(), Some((meta.modified().ok()?, meta.len(), path)), None)
+        }).collect();
+        //从新到旧累计配额，当前文件计入其中
+        files.sort_by(|a, b| b.0.cmp(&a.0));
+        let expire = SystemTime::now().checked_sub(Duration::from_secs(self.clean_time));
+        let (mut count, mut total) = (1, self.size as u64);
+        let (mut removed, mut freed) = (0, 0);
+        for (mtime, len, path) in files {
+            let expired = self.clean_time > 0 && expire.map_or(false, |expire| mtime < expire);
+            let over_count = self.max_files > 0 && count + 1 > self.max_files;
+            let over_total = self.max_total > 0 && total + len > self.max_total as u64;
+            if expired || over_count || over_total {
+                if fs::remove_file(&path).is_ok() {
+                    removed += 1;
+                    freed += len;
+                }
+                continue;
+            }
+            count += 1;
+            total += len;
+        }
+        if removed == 0 {
+            return None;
+        }
+        Some(format!("[logger] purge {} files ({} bytes) of {} in {}", removed, freed, self.feature, self.log_path.display()))
+    }
+
     pub fn create(&mut self, file_name: String) {
-        self.unmap_file();
+        self.close_rolled();
         self.size = 0;
         self.time = Local::now();
         self.alloc_size = PAGE_SIZE;
@@ -158,6 +454,12 @@ impl FileDest {
     }
 }
 
+impl Drop for FileDest {
+    fn drop(&mut self) {
+        self.close_file();
+    }
+}
+
 impl LogDest for FileDest {
     fn ignore_prefix(&mut self, prefix: bool){
         self.ignore_prefix = prefix;
@@ -168,6 +470,16 @@ impl LogDest for FileDest {
     fn set_clean_time(&mut self, clean_time: u64){
         self.clean_time = clean_time;
     }
+    fn set_quota(&mut self, max_files: usize, max_total: usize){
+        self.max_files = max_files;
+        self.max_total = max_total;
+    }
+    fn set_format(&mut self, format: u32){
+        self.format = format;
+    }
+    fn set_compress(&mut self, compress: u32){
+        self.compress = compress;
+    }
     fn raw_write(&mut self, msg: String, lvl: u32) {
         let msize = msg.len();
         if self.size + msize > self.alloc_size {
@@ -184,7 +496,11 @@ impl LogDest for FileDest {
         }
     }
     fn write(&mut self, log: &LogMessage) {
-        let logtxt: String = format!("{} {}{}\n", self.build_prefix(log, self.ignore_prefix), log.msg, self.build_suffix(log, self.ignore_suffix));
+        let logtxt: String = match self.format {
+            f if f == LogFormat::JSON as u32 => self.build_json(log),
+            f if f == LogFormat::LOGFMT as u32 => self.build_logfmt(log),
+            _ => format!("{} {}{}{}\n", self.build_prefix(log, self.ignore_prefix), log.msg, build_kv_text(log), self.build_suffix(log, self.ignore_suffix)),
+        };
         if self.mapbuf.is_none() || self.check_full() || self.rolling_eval(log) {
             let _ = fs::create_dir_all(&self.log_path);
             self.create(self.new_log_file_name(log));
@@ -193,188 +509,215 @@ impl LogDest for FileDest {
     }
 }
 
-struct LogMessage {
+//与lssl相同使用zstd
+fn compress_file(path: &PathBuf, target: &PathBuf) -> io::Result<()> {
+    let mut input = File::open(path)?;
+    let output = File::create(target)?;
+    let mut encoder = zstd::Encoder::new(output, ZSTD_LEVEL)?;
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?.sync_all()
+}
+
+//压缩线程，大文件压缩不占用writer锁，失败信息回传给日志线程记录到devops
+struct Compressor {
+    sender: Sender<PathBuf>,
+    errors: Receiver<String>,
+    thread: JoinHandle<()>,
+}
+
+impl Compressor {
+    fn new() -> Option<Compressor> {
+        let (sender, receiver) = channel::<PathBuf>();
+        let (error_sender, errors) = channel();
+        let thread = thread::Builder::new().name("lualog-compress".to_string()).spawn(move || {
+            //压缩成功后删除原文件
+            for path in receiver {
+                let mut target = path.clone().into_os_string();
+                target.push(".zst");
+                let target = PathBuf::from(target);
+                match compress_file(&path, &target) {
+                    Ok(()) => { let _ = fs::remove_file(&path); },
+                    Err(e) => {
+                        let _ = fs::remove_file(&target);
+                        let _ = error_sender.send(format!("[logger] compress {} failed: {}", path.display(), e));
+                    },
+                }
+            }
+        }).ok()?;
+        Some(Compressor { sender: sender, errors: errors, thread: thread })
+    }
+
+    //关闭通道，等待剩余文件压缩完成
+    fn close(self) {
+        drop(self.sender);
+        let _ = self.thread.join();
+    }
+}
+
+pub(crate) struct LogMessage {
     pub line: i32,
     pub level: u32,
-    pub grow: bool,
     pub tag: String,
     pub msg: String,
     pub source: String,
     pub feature: String,
+    pub thread: String,
+    pub fields: Vec<(String, String)>,
     pub time: DateTime<Local>,
 }
 
 impl LogMessage {
-    pub fn new(grow: bool) -> LogMessage {
-        LogMessage {
-            line: 0,
-            level: 0,
-            grow: grow,
-            time: Local::now(),
-            tag: "".to_string(),
-            msg: "".to_string(),
-            source: "".to_string(),
-            feature: "".to_string(),
-        }
+    pub fn new() -> LogMessage {
+        LogMessage::build(0, "".to_string(), "".to_string(), "".to_string(), "", 0)
     }
-    
-    pub fn option(&mut self, level: u32, msg: String, tag: String, feature: String, source: &str, line: i32) {
-            self.line = line;
-            self.tag = tag;
-            self.msg = msg;
-            self.level = level;
-            self.feature = feature;
-            self.time = Local::now();
-            self.source = source.to_string();
-    }
-
-    pub fn set_grow(&mut self, grow: bool) {
-        self.grow = grow;
-    }
-}
-
-struct LogMessagePool {
-    mutex: Mutex<()>,
-    free_messages: Vec<LogMessage>,
-    alloc_messages: Vec<LogMessage>,
-}
 
-impl LogMessagePool  {
-    pub fn new() -> LogMessagePool  {
-        LogMessagePool {
-            mutex: Mutex::new(()),
-            free_messages: Vec::new(),
-            alloc_messages: Vec::new(),
+    pub fn build(level: u32, msg: String, tag: String, feature: String, source: &str, line: i32) -> LogMessage {
+        LogMessage {
+            line: line,
+            tag: tag,
+            msg: msg,
+            level: level,
+            feature: feature,
+            time: Local::now(),
+            source: source.to_string(),
+            thread: thread::current().name().unwrap_or("").to_string(),
+            fields: Vec::new(),
         }
     }
 
-    pub fn init(&mut self) {
-        for _ in 0..QUEUE_SIZE {
-            self.alloc_messages.push(LogMessage::new(false));
-        }
+    //level为0且带标记的消息要求日志线程落盘，不带标记的表示退出
+    pub fn sync() -> LogMessage {
+        LogMessage::build(0, SYNC_MARKER.to_string(), "".to_string(), "".to_string(), "", 0)
     }
 
-    pub fn allocate(&mut self) -> LogMessage {
-        if self.alloc_messages.is_empty() {
-            let _g = self.mutex.lock();
-            std::mem::swap(&mut self.alloc_messages, &mut self.free_messages);
-        }
-        if self.alloc_messages.is_empty() {
-            return LogMessage::new(true);
-        }
-        let _g = self.mutex.lock();
-        self.alloc_messages.pop().unwrap()
+    pub fn is_sync(&self) -> bool {
+        self.level == 0 && self.msg == SYNC_MARKER
     }
 
-    pub fn release(&mut self, msg : LogMessage) {
-        if !msg.grow {
-            let _g = self.mutex.lock();
-            self.free_messages.push(msg);
-        }
+    pub fn with_fields(mut self, fields: Vec<(String, String)>) -> LogMessage {
+        self.fields = fields;
+        self
     }
 }
 
-type MessageList = Vec<LogMessage>;
-struct LogMessageQueue {
-    mutex: Mutex<()>,
-    read_messages: Vec<LogMessage>,
-    write_messages: Vec<LogMessage>,
+//按(feature, 格式串)限流的令牌桶
+struct TokenBucket {
+    tokens: f64,
+    stamp: Instant,
+    suppressed: u64,
 }
 
-impl LogMessageQueue  {
-    pub fn new() -> LogMessageQueue  {
-        LogMessageQueue {
-            mutex: Mutex::new(()),
-            read_messages: Vec::new(),
-            write_messages: Vec::new(),
-        }
+impl TokenBucket {
+    fn new(burst: u32) -> TokenBucket {
+        TokenBucket { tokens: burst as f64, stamp: Instant::now(), suppressed: 0 }
     }
 
-    pub fn put(&mut self, logmsg: LogMessage) {
-        let _g = self.mutex.lock();
-        self.write_messages.push(logmsg);
-    }
-
-    pub fn timed_getv(&mut self) ->&Vec<LogMessage> {
-        {
-            self.read_messages.clear();
-            let _g = self.mutex.lock();
-            std::mem::swap(&mut self.read_messages, &mut self.write_messages);
-        }
-        if self.read_messages.is_empty() {
-            thread::sleep(Duration::from_millis(5));
+    fn acquire(&mut self, rate: u32, burst: u32) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.stamp).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate as f64).min(burst as f64);
+        self.stamp = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return true;
         }
-        &self.read_messages
+        self.suppressed += 1;
+        false
     }
 }
 
-pub struct LogService {
+//日志线程持有的输出状态，配置接口与日志线程共用此锁
+pub struct LogWriter {
+
     path: String,
     service: String,
+    service_name: String,
+    index: String,
+    format: u32,
+    compress: u32,
     rolling_type: u32,
+    rolling_minutes: u32,
+    rotating: bool,
     main_dest: FileDest,
-    logmsgque: LogMessageQueue,
-    messagepool: LogMessagePool,
     dest_lvls: DashMap<u32, FileDest>,
     dest_features: DashMap<String, FileDest>,
-    thread_handle: Option<JoinHandle<()>>,
     std_dest: StdDest,
-    filter_bits: i32,
+    console: u32,
+    remote_dests: HashMap<String, RemoteDest>,
+    compressor: Option<Compressor>,
+    written: u64,
     clean_time: u64,
     max_size: usize,
-    daemon: bool,
+    max_files: usize,
+    max_total: usize,
 }
 
-impl LogService {
-    pub fn new() -> LogService {
-        LogService {
-            std_dest: StdDest,
+impl LogWriter {
+    pub fn new() -> LogWriter {
+        LogWriter {
+            std_dest: StdDest::new(ConsoleMode::AUTO as u32),
+            console: ConsoleMode::AUTO as u32,
+            remote_dests: HashMap::new(),
+            compressor: None,
             path: "".to_string(),
             service: "".to_string(),
+            service_name: "".to_string(),
+            index: "".to_string(),
+            format: LogFormat::TEXT as u32,
+            compress: CompressType::NONE as u32,
             dest_lvls: DashMap::new(),
             dest_features: DashMap::new(),
-            logmsgque: LogMessageQueue::new(),
-            messagepool: LogMessagePool::new(),
             rolling_type: RollingType::DAYLY as u32,
+            rolling_minutes: ROLLING_MINUTES,
+            rotating: false,
             main_dest: FileDest::new(),
             clean_time: CLEAN_TIME,
-            thread_handle: None,
             max_size: MAX_SIZE,
-            filter_bits: -1,
-            daemon: false,
+            max_files: 0,
+            max_total: 0,
+            written: 0,
+        }
+    }
+
+    pub fn close_dests(&mut self) {
+        self.main_dest.close_file();
+        for mut dest in self.dest_lvls.iter_mut() {
+            dest.close_file();
         }
+        for mut dest in self.dest_features.iter_mut() {
+            dest.close_file();
+        }
+        self.queue_closed();
     }
 
-    pub fn start(&mut self) {
-        self.messagepool.init();
+    //取出压缩线程，由调用方在释放writer锁后等待其压缩完已滚动的文件
+    fn take_compressor(&mut self) -> Option<Compressor> {
+        self.compressor.take()
     }
 
-    pub fn stop(&mut self) {
-        self.logmsgque.put(LogMessage::new(false));
-        if self.thread_handle.is_some() {
-            self.thread_handle.take().unwrap().join().unwrap();
-        }
+    //取出网络目标，由调用方在释放writer锁后关闭
+    pub fn take_remote_dests(&mut self) -> Vec<RemoteDest> {
+        self.remote_dests.drain().map(|(_, dest)| dest).collect()
     }
 
-    pub fn option(&mut self, logger: Arc<Mutex<LogService>>, path: String, service: String, index: String) {
-        self.path = path.clone();
+    pub fn option(&mut self, path: String, service: String, index: String) {
+        self.path = path;
         self.add_main_dest(&service, &index);
-        if self.thread_handle.is_none() {
-            self.thread_handle = Some(thread::spawn(|| {
-                LogService::run(logger);
-            }));
-        }
     }
 
     pub fn add_main_dest(&mut self, service: &str, index: &str) {
         let _ = fs::create_dir_all(&self.path);
         self.service = format!("{}-{}", service, index);
+        self.service_name = service.to_string();
+        self.index = index.to_string();
         let logpath = self.build_path(&self.service);
-        self.main_dest.setup(logpath, service, self.max_size, self.rolling_type, self.clean_time);
+        self.main_dest = self.new_dest(logpath, service, self.rolling_type);
     }
 
-    pub fn daemon(&mut self, status: bool) {
-        self.daemon = status;
+    //AUTO按stdout/stderr各自是否为终端决定是否着色
+    pub fn set_console(&mut self, mode: u32) {
+        self.console = mode;
+        self.std_dest = StdDest::new(mode);
     }
 
     pub fn set_max_size(&mut self, size: usize,) {
@@ -385,19 +728,61 @@ impl LogService {
         self.clean_time = time;
     }
     
+    //单个目标保留的文件数以及总大小，0表示不限
+    pub fn set_quota(&mut self, max_files: usize, max_total: usize) {
+        self.max_files = max_files;
+        self.max_total = max_total;
+    }
+
     pub fn set_rolling_type(&mut self, rt: u32) {
         self.rolling_type = rt;
     }
 
-    pub fn filter(&mut self, lv: u32, on: bool) {
-        match on {
-            true => self.filter_bits |= 1 << (lv - 1),
-            false => self.filter_bits &= !(1 << (lv - 1)),
+    pub fn set_rolling_minutes(&mut self, minutes: u32) {
+        self.rolling_minutes = minutes.max(1);
+    }
+
+    //由日志线程在下次update时执行
+    pub fn rotate(&mut self) {
+        self.rotating = true;
+    }
+
+    fn new_dest(&self, path: PathBuf, feature: &str, rtype: u32) -> FileDest {
+        let mut dest = FileDest::new();
+        dest.setup(path, feature, self.max_size, rtype, self.clean_time);
+        dest.set_quota(self.max_files, self.max_total);
+        dest.rolling_minutes = self.rolling_minutes;
+        dest.set_format(self.format);
+        dest.set_compress(self.compress);
+        dest.service = self.service_name.clone();
+        dest.index = self.index.clone();
+        dest
+    }
+
+    //之后创建的文件目标的默认格式，主目标同时生效
+    pub fn set_format(&mut self, format: u32) {
+        self.format = format;
+        self.main_dest.set_format(format);
+    }
+
+    //滚动后的文件压缩方式，已存在的滚动目标同时生效
+    pub fn set_compress(&mut self, compress: u32) {
+        self.compress = compress;
+        self.main_dest.set_compress(compress);
+        for mut dest in self.dest_lvls.iter_mut() {
+            dest.set_compress(compress);
+        }
+        for mut dest in self.dest_features.iter_mut() {
+            if !dest.fixed {
+                dest.set_compress(compress);
+            }
         }
     }
 
-    pub fn is_filter(&self, lv: u32) -> bool {
-        return 0 == (self.filter_bits & (1 << (lv - 1))); 
+    pub fn set_dest_format(&mut self, feature: String, format: u32) {
+        if let Some(mut dest) = self.dest_features.get_mut(&feature) {
+           dest.set_format(format)
+        }
     }
 
     pub fn set_dest_clean_time(&mut self, feature: String, clean_time: u64) {
@@ -406,6 +791,12 @@ impl LogService {
         }
     }
 
+    pub fn set_dest_quota(&mut self, feature: String, max_files: usize, max_total: usize) {
+        if let Some(mut dest) = self.dest_features.get_mut(&feature) {
+           dest.set_quota(max_files, max_total)
+        }
+    }
+
     pub fn ignore_prefix(&mut self, feature: String, prefix: bool) {
         if let Some(mut dest) = self.dest_features.get_mut(&feature) {
            dest.ignore_prefix(prefix);
@@ -430,9 +821,8 @@ impl LogService {
     
     pub fn add_dest(&mut self, feature: String) {
         if !self.dest_features.contains_key(&feature) {
-            let mut dest = FileDest::new();
             let path = self.build_path(&feature);
-            dest.setup(path, &feature, self.max_size, self.rolling_type, self.clean_time);
+            let dest = self.new_dest(path, &feature, self.rolling_type);
             self.dest_features.insert(feature, dest);
         }
     }
@@ -441,8 +831,8 @@ impl LogService {
         if !self.dest_features.contains_key(&feature) {
             let path = self.build_path(&self.service);
             let _ = fs::create_dir_all(&path);
-            let mut dest = FileDest::new();
-            dest.setup(path, &feature, self.max_size, RollingType::NONE as u32, self.clean_time);
+            let mut dest = self.new_dest(path, &feature, RollingType::NONE as u32);
+            dest.fixed = true;
             dest.create(fname);
             dest.ignore_prefix(true);
             self.dest_features.insert(feature.clone(), dest);
@@ -455,8 +845,7 @@ impl LogService {
             feature.make_ascii_lowercase();
             let mut path = self.build_path(&self.service);
             path.push(&feature);
-            let mut dest = FileDest::new();
-            dest.setup(path, &feature, self.max_size, self.rolling_type, self.clean_time);
+            let dest = self.new_dest(path, &feature, self.rolling_type);
             self.dest_lvls.insert(level, dest);
         }
     }
@@ -469,43 +858,397 @@ impl LogService {
         self.dest_lvls.remove(&log_lvl);
     }
 
-    pub fn output(&mut self, level: u32, msg: &String, tag: String, feature: String, source: &str, line: i32) {
-        if !self.is_filter(level) {
-            let mut message = self.messagepool.allocate();
-            message.option(level, msg.to_string(), tag, feature, source, line);
-            self.logmsgque.put(message);
+    //网络目标接收level及以上的所有日志，同名覆盖
+    pub fn add_remote_dest(&mut self, name: String, kind: u32, addr: String, level: u32) -> Result<(), String> {
+        let dest = RemoteDest::new(kind, &addr, level, &self.service_name, &self.index)?;
+        self.remote_dests.insert(name, dest);
+        Ok(())
+    }
+
+    //发送线程在后台发完剩余日志后退出
+    pub fn del_remote_dest(&mut self, name: String) {
+        self.remote_dests.remove(&name);
+    }
+
+    fn sync_dests(&mut self) {
+        self.main_dest.sync_file();
+        for mut dest in self.dest_lvls.iter_mut() {
+            dest.sync_file();
+        }
+        for mut dest in self.dest_features.iter_mut() {
+            dest.sync_file();
+        }
+    }
+
+    fn rotate_dests(&mut self) {
+        self.rotating = false;
+        self.main_dest.rotate();
+        for mut dest in self.dest_lvls.iter_mut() {
+            dest.rotate();
+        }
+        for mut dest in self.dest_features.iter_mut() {
+            dest.rotate();
+        }
+    }
+
+    fn dispatch(&mut self, log: &LogMessage) {
+        self.written += 1;
+        if self.console != ConsoleMode::OFF as u32 {
+            self.std_dest.write(log);
+        }
+        if let Some(mut dest) = self.dest_lvls.get_mut(&log.level) {
+            dest.write(log);
+        }
+        if let Some(mut dest) = self.dest_features.get_mut(&log.feature) {
+            dest.write(log);
+        } else {
+            self.main_dest.write(log);
+        }
+        for dest in self.remote_dests.values_mut() {
+            dest.write(log);
         }
     }
 
-    pub fn update(&mut self) -> bool {
-        let logmsgs = self.logmsgque.timed_getv();
-        for log in logmsgs.iter() {
+    //队列满被丢弃的日志数记录到devops
+    fn overflow(&mut self, dropped: u64) {
+        let msg = format!("[logger] queue overflow, dropped {} messages", dropped);
+        self.dispatch(&LogMessage::build(LogLevel::WARN as u32, msg, "".to_string(), DEVOPS_FEATURE.to_string(), "", 0));
+    }
+
+    //收到level为0的消息表示退出
+    fn update(&mut self, logs: &Vec<LogMessage>) -> bool {
+        if self.rotating {
+            self.rotate_dests();
+        }
+        for log in logs.iter() {
+            if log.is_sync() {
+                self.sync_dests();
+                continue;
+            }
             if log.level == 0 {
                 return false
             }
-            if !self.daemon {
-                self.std_dest.write(&log);
+            self.dispatch(log);
+        }
+        self.clean_dests();
+        self.compress_dests();
+        true
+    }
+
+    //清理结果记录到devops
+    fn clean_dests(&mut self) {
+        let now = Local::now().timestamp();
+        let mut purges = Vec::new();
+        purges.extend(self.main_dest.clean(now));
+        for mut dest in self.dest_lvls.iter_mut() {
+            purges.extend(dest.clean(now));
+        }
+        for mut dest in self.dest_features.iter_mut() {
+            purges.extend(dest.clean(now));
+        }
+        for purge in purges {
+            self.dispatch(&LogMessage::build(LogLevel::INFO as u32, purge, "".to_string(), DEVOPS_FEATURE.to_string(), "", 0));
+        }
+    }
+
+    //滚动关闭的文件投递给压缩线程
+    fn queue_closed(&mut self) {
+        let mut paths = Vec::new();
+        self.main_dest.take_closed(&mut paths);
+        for mut dest in self.dest_lvls.iter_mut() {
+            dest.take_closed(&mut paths);
+        }
+        for mut dest in self.dest_features.iter_mut() {
+            dest.take_closed(&mut paths);
+        }
+        if paths.is_empty() {
+            return;
+        }
+        if self.compressor.is_none() {
+            self.compressor = Compressor::new();
+        }
+        if let Some(ref compressor) = self.compressor {
+            for path in paths {
+                let _ = compressor.sender.send(path);
+            }
+        }
+    }
+
+    //压缩失败记录到devops
+    fn compress_dests(&mut self) {
+        self.queue_closed();
+        let errors: Vec<String> = match self.compressor {
+            Some(ref compressor) => compressor.errors.try_iter().collect(),
+            None => return,
+        };
+        for error in errors {
+            self.dispatch(&LogMessage::build(LogLevel::WARN as u32, error, "".to_string(), DEVOPS_FEATURE.to_string(), "", 0));
+        }
+    }
+}
+
+//生产者只做过滤和投递，不与日志线程竞争锁
+//队列有界，满时丢弃并计数，由日志线程统一上报
+pub struct LogService {
+    filter_bits: AtomicI32,
+    source_bits: AtomicI32,
+    source_depth: AtomicI32,
+    limit_rate: AtomicU32,
+    limit_burst: AtomicU32,
+    synced: AtomicU64,
+    buckets: DashMap<(String, String), TokenBucket>,
+    muted_tags: DashSet<String>,
+    muted_features: DashSet<String>,
+    dropped: AtomicU64,
+    sender: SyncSender<LogMessage>,
+    receiver: Mutex<Option<Receiver<LogMessage>>>,
+    thread_handle: Mutex<Option<JoinHandle<()>>>,
+    writer: Mutex<LogWriter>,
+}
+
+impl LogService {
+    pub fn new() -> LogService {
+        let (sender, receiver) = sync_channel(QUEUE_SIZE);
+        LogService {
+            sender: sender,
+            dropped: AtomicU64::new(0),
+            filter_bits: AtomicI32::new(-1),
+            source_bits: AtomicI32::new(0),
+            source_depth: AtomicI32::new(SOURCE_DEPTH),
+            limit_rate: AtomicU32::new(0),
+            limit_burst: AtomicU32::new(0),
+            synced: AtomicU64::new(0),
+            buckets: DashMap::new(),
+            muted_tags: DashSet::new(),
+            muted_features: DashSet::new(),
+            receiver: Mutex::new(Some(receiver)),
+            thread_handle: Mutex::new(None),
+            writer: Mutex::new(LogWriter::new()),
+        }
+    }
+
+    pub fn writer(&self) -> MutexGuard<'_, LogWriter> {
+        self.writer.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn start(&self) {
+        self.dropped.store(0, Ordering::Relaxed);
+    }
+
+    pub fn stop(&self) {
+        let handle = self.thread_handle.lock().unwrap().take();
+        if let Some(handle) = handle {
+            //退出消息不能丢，阻塞等待队列空位
+            let _ = self.sender.send(LogMessage::new());
+            let _ = handle.join();
+        }
+        let (remotes, compressor) = {
+            let mut writer = self.writer();
+            writer.close_dests();
+            (writer.take_remote_dests(), writer.take_compressor())
+        };
+        for dest in remotes {
+            dest.close();
+        }
+        if let Some(compressor) = compressor {
+            compressor.close();
+        }
+    }
+
+    //崩溃时调用: 写入FATAL记录，等待日志线程消费完队列并msync，最多等待FATAL_WAIT毫秒
+    //日志线程自身崩溃或未启动时在当前线程直接写入
+    pub fn fatal(&self, msg: String) {
+        let message = LogMessage::build(LogLevel::FATAL as u32, msg, "".to_string(), "".to_string(), "", 0);
+        let running = match self.thread_handle.try_lock() {
+            Ok(handle) => handle.as_ref().map_or(false, |h| h.thread().id() != thread::current().id() && !h.is_finished()),
+            Err(_) => false,
+        };
+        if !running {
+            if let Ok(mut writer) = self.writer.try_lock() {
+                writer.dispatch(&message);
+                writer.sync_dests();
+            }
+            return;
+        }
+        let deadline = Instant::now() + Duration::from_millis(FATAL_WAIT);
+        let synced = self.synced.load(Ordering::Acquire);
+        if !self.send_until(message, deadline) || !self.send_until(LogMessage::sync(), deadline) {
+            return;
+        }
+        while Instant::now() < deadline && self.synced.load(Ordering::Acquire) == synced {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn send_until(&self, message: LogMessage, deadline: Instant) -> bool {
+        let mut message = message;
+        loop {
+            match self.sender.try_send(message) {
+                Ok(()) => return true,
+                Err(TrySendError::Full(msg)) if Instant::now() < deadline => {
+                    message = msg;
+                    thread::sleep(Duration::from_millis(1));
+                },
+                Err(_) => return false,
             }
-            if let Some(mut dest) = self.dest_lvls.get_mut(&log.level) {
-                dest.write(&log);
+        }
+    }
+
+    pub fn option(&self, logger: Arc<LogService>, path: String, service: String, index: String) {
+        self.writer().option(path, service, index);
+        if let Some(receiver) = self.receiver.lock().unwrap().take() {
+            let handle = thread::spawn(move || {
+                LogService::run(logger, receiver);
+            });
+            *self.thread_handle.lock().unwrap() = Some(handle);
+        }
+    }
+
+    pub fn filter(&self, lv: u32, on: bool) {
+        match on {
+            true => self.filter_bits.fetch_or(1 << (lv - 1), Ordering::Relaxed),
+            false => self.filter_bits.fetch_and(!(1 << (lv - 1)), Ordering::Relaxed),
+        };
+    }
+
+    pub fn is_filter(&self, lv: u32) -> bool {
+        return 0 == (self.filter_bits.load(Ordering::Relaxed) & (1 << (lv - 1)));
+    }
+
+    //on为false时屏蔽该feature的日志
+    pub fn filter_feature(&self, feature: String, on: bool) {
+        if on {
+            self.muted_features.remove(&feature);
+        } else {
+            self.muted_features.insert(feature);
+        }
+    }
+
+    pub fn filter_tag(&self, tag: String, on: bool) {
+        if on {
+            self.muted_tags.remove(&tag);
+        } else {
+            self.muted_tags.insert(tag);
+        }
+    }
+
+    pub fn is_muted(&self, feature: &str, tag: &str) -> bool {
+        (!self.muted_features.is_empty() && self.muted_features.contains(feature))
+            || (!self.muted_tags.is_empty() && self.muted_tags.contains(tag))
+    }
+
+    //每个(feature, 格式串)每秒rate条，允许burst条突发，rate为0关闭
+    pub fn set_rate_limit(&self, rate: u32, burst: u32) {
+        self.limit_rate.store(rate, Ordering::Relaxed);
+        self.limit_burst.store(burst.max(rate).max(1), Ordering::Relaxed);
+        if rate == 0 {
+            self.buckets.clear();
+        }
+    }
+
+    pub fn is_limited(&self, feature: &str, fmt: &str) -> bool {
+        let rate = self.limit_rate.load(Ordering::Relaxed);
+        if rate == 0 {
+            return false;
+        }
+        let burst = self.limit_burst.load(Ordering::Relaxed);
+        let mut bucket = self.buckets.entry((feature.to_string(), fmt.to_string())).or_insert_with(|| TokenBucket::new(burst));
+        !bucket.acquire(rate, burst)
+    }
+
+    //汇总被限流的日志，同时清理长期空闲的令牌桶
+    fn take_suppressed(&self) -> Vec<LogMessage> {
+        let mut logs = Vec::new();
+        let idle = Duration::from_millis(LIMIT_IDLE);
+        self.buckets.retain(|(feature, fmt), bucket| {
+            if bucket.suppressed > 0 {
+                let msg = format!("[logger] suppressed {} messages: {}", bucket.suppressed, fmt);
+                logs.push(LogMessage::build(LogLevel::WARN as u32, msg, "".to_string(), feature.clone(), "", 0));
+                bucket.suppressed = 0;
+                return true;
             }
-            if let Some(mut dest) = self.dest_features.get_mut(&log.feature) {
-                dest.write(&log);
-            } else {
-                self.main_dest.write(&log);
+            bucket.stamp.elapsed() < idle
+        });
+        logs
+    }
+
+    //按等级开启调用位置采集
+    pub fn set_source(&self, lv: u32, on: bool) {
+        match on {
+            true => self.source_bits.fetch_or(1 << (lv - 1), Ordering::Relaxed),
+            false => self.source_bits.fetch_and(!(1 << (lv - 1)), Ordering::Relaxed),
+        };
+    }
+
+    pub fn is_source(&self, lv: u32) -> bool {
+        return 0 != (self.source_bits.load(Ordering::Relaxed) & (1 << (lv - 1)));
+    }
+
+    //lua_getstack的层级，0为log.print自身
+    pub fn set_source_depth(&self, depth: i32) {
+        self.source_depth.store(depth.max(1), Ordering::Relaxed);
+    }
+
+    pub fn source_depth(&self) -> i32 {
+        self.source_depth.load(Ordering::Relaxed)
+    }
+
+    pub fn output(&self, level: u32, msg: &String, tag: String, feature: String, source: &str, line: i32) {
+        self.output_kv(level, msg, tag, feature, source, line, Vec::new());
+    }
+
+    pub fn output_kv(&self, level: u32, msg: &String, tag: String, feature: String, source: &str, line: i32, fields: Vec<(String, String)>) {
+        if !self.is_filter(level) {
+            let message = LogMessage::build(level, msg.to_string(), tag, feature, source, line).with_fields(fields);
+            if self.sender.try_send(message).is_err() {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
             }
         }
-        true
     }
 
-    pub fn run(log_service: Arc<Mutex<LogService>>) {
-        thread::sleep(Duration::from_millis(100));
+    pub fn stats(&self) -> HashMap<&'static str, u64> {
+        let mut stats = HashMap::new();
+        stats.insert("dropped", self.dropped.load(Ordering::Relaxed));
+        let writer = self.writer();
+        stats.insert("written", writer.written);
+        stats.insert("remote_sent", writer.remote_dests.values().map(|dest| dest.sent()).sum());
+        stats.insert("remote_dropped", writer.remote_dests.values().map(|dest| dest.dropped()).sum());
+        stats
+    }
+
+    fn run(log_service: Arc<LogService>, receiver: Receiver<LogMessage>) {
+        let mut reported = 0;
+        let mut limit_time = Instant::now();
         loop {
-            if let Ok(mut logger) = log_service.lock() {
-                if !logger.update() {
-                    break;
+            let mut logs = Vec::new();
+            match receiver.recv_timeout(Duration::from_millis(WAIT_TIME)) {
+                Ok(log) => {
+                    logs.push(log);
+                    logs.extend(receiver.try_iter().take(QUEUE_SIZE));
+                },
+                Err(RecvTimeoutError::Timeout) => {},
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            let mut writer = log_service.writer();
+            let dropped = log_service.dropped.load(Ordering::Relaxed);
+            if dropped > reported {
+                writer.overflow(dropped - reported);
+                reported = dropped;
+            }
+            if limit_time.elapsed() >= Duration::from_millis(LIMIT_PERIOD) {
+                limit_time = Instant::now();
+                for log in log_service.take_suppressed() {
+                    writer.dispatch(&log);
                 }
             }
+            let syncs = logs.iter().filter(|log| log.is_sync()).count() as u64;
+            let running = writer.update(&logs);
+            if syncs > 0 {
+                log_service.synced.fetch_add(syncs, Ordering::Release);
+            }
+            if !running {
+                break;
+            }
         }
     }
 }
@@ -0,0 +1,477 @@
+#![allow(non_snake_case)]
+#![allow(dead_code)]
+#![allow(non_camel_case_types)]
+
+use std::process;
+use std::io::{ Read, Write };
+use std::collections::{ BTreeMap, VecDeque };
+use std::time::{ Duration, Instant };
+use std::thread::{ self, JoinHandle };
+use std::sync::{ Arc, Condvar, Mutex, MutexGuard };
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::net::{ SocketAddr, TcpStream, ToSocketAddrs, UdpSocket };
+use lua::ternary;
+use chrono::SecondsFormat;
+
+use crate::logger::{ LogMessage, LEVEL_NAMES, json_escape, build_kv_text };
+
+//发送失败后保留的最大条数，超出丢弃最旧的
+const MAX_PENDING: usize    = 10000;
+const LOKI_BATCH: usize     = 500;
+const LOKI_PERIOD: u64      = 1000;
+const LOKI_PATH: &str       = "/loki/api/v1/push";
+const IO_TIMEOUT: u64       = 500;
+const RETRY_MIN: u64        = 1000;
+const RETRY_MAX: u64        = 30000;
+//有积压时的检查间隔
+const SINK_TICK: u64        = 100;
+//local0
+const SYSLOG_FACILITY: u32  = 16;
+//RFC 5424 示例用企业号
+const SYSLOG_SDID: &str     = "quanta@32473";
+//按LogLevel索引: UNKNW/DEBUG/INFO/WARN/DUMP/ERROR/FATAL
+const SYSLOG_SEVERITY: [u32; 7] = [6, 7, 6, 4, 5, 3, 2];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RemoteType {
+    SYSLOG_UDP = 1,
+    SYSLOG_TCP,
+    LOKI,
+}
+
+impl RemoteType {
+    fn from(val: u32) -> Option<RemoteType> {
+        match val {
+            1 => Some(RemoteType::SYSLOG_UDP),
+            2 => Some(RemoteType::SYSLOG_TCP),
+            3 => Some(RemoteType::LOKI),
+            _ => None,
+        }
+    }
+}
+
+struct RemoteRecord {
+    level: u32,
+    feature: String,
+    time_ns: i64,
+    text: String,
+}
+
+enum RemoteLink {
+    None,
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+}
+
+//日志线程与发送线程共享的队列，closed后发送线程做最后一次发送并退出
+struct RemoteQueue {
+    records: VecDeque<RemoteRecord>,
+    closed: bool,
+}
+
+struct RemoteShared {
+    queue: Mutex<RemoteQueue>,
+    signal: Condvar,
+    dropped: AtomicU64,
+    sent: AtomicU64,
+}
+
+impl RemoteShared {
+    fn lock(&self) -> MutexGuard<'_, RemoteQueue> {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn close(&self) {
+        self.lock().closed = true;
+        self.signal.notify_one();
+    }
+}
+
+fn get_hostname() -> String {
+    let mut buf = [0u8; 256];
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if ret != 0 {
+        return "-".to_string();
+    }
+    let len = buf.iter().position(|c| *c == 0).unwrap_or(buf.len());
+    sd_name(&String::from_utf8_lossy(&buf[..len]), 255)
+}
+
+//syslog头部字段只允许可见ascii
+fn sd_name(text: &str, max: usize) -> String {
+    let name: String = text.chars().filter(|c| c.is_ascii_graphic() && !matches!(c, '=' | ']' | '"')).take(max).collect();
+    ternary!(name.is_empty(), "-".to_string(), name)
+}
+
+fn sd_escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '"' | '\\' | ']') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+//网络日志目标，日志线程只格式化后入队，DNS、连接和发送都在独立的发送线程
+//发送失败保留缓冲，按退避间隔重试
+pub struct RemoteDest {
+    kind: RemoteType,
+    level: u32,
+    service: String,
+    index: String,
+    hostname: String,
+    shared: Arc<RemoteShared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl RemoteDest {
+    //loki地址格式: [http://]host:port[/path]，地址在发送线程中解析
+    pub fn new(kind: u32, addr: &str, level: u32, service: &str, index: &str) -> Result<RemoteDest, String> {
+        let kind = RemoteType::from(kind).ok_or(format!("invalid remote type: {}", kind))?;
+        let (mut host, mut path) = (addr.to_string(), String::new());
+        if kind == RemoteType::LOKI {
+            if addr.starts_with("https://") {
+                return Err("https is not supported".to_string());
+            }
+            let addr = addr.strip_prefix("http://").unwrap_or(addr);
+            let (h, p) = addr.split_at(addr.find('/').unwrap_or(addr.len()));
+            host = h.to_string();
+            path = ternary!(p.len() > 1, p.to_string(), LOKI_PATH.to_string());
+        }
+        match host.rsplit_once(':') {
+            Some((name, port)) if !name.is_empty() && port.parse::<u16>().is_ok() => {},
+            _ => return Err(format!("invalid remote addr: {}", host)),
+        }
+        let shared = Arc::new(RemoteShared {
+            queue: Mutex::new(RemoteQueue { records: VecDeque::new(), closed: false }),
+            signal: Condvar::new(),
+            dropped: AtomicU64::new(0),
+            sent: AtomicU64::new(0),
+        });
+        let hostname = get_hostname();
+        let sink = RemoteSink {
+            kind: kind,
+            host: host,
+            path: path,
+            service: service.to_string(),
+            index: index.to_string(),
+            hostname: hostname.clone(),
+            link: RemoteLink::None,
+            pending: VecDeque::new(),
+            flush_time: Instant::now(),
+            retry_time: None,
+            retry_delay: RETRY_MIN,
+            shared: shared.clone(),
+        };
+        let thread = thread::Builder::new().name("lualog-remote".to_string()).spawn(move || sink.run()).map_err(|e| e.to_string())?;
+        Ok(RemoteDest {
+            kind: kind,
+            level: level,
+            service: service.to_string(),
+            index: index.to_string(),
+            hostname: hostname,
+            shared: shared,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn sent(&self) -> u64 {
+        self.shared.sent.load(Ordering::Relaxed)
+    }
+
+    pub fn write(&mut self, log: &LogMessage) {
+        if log.level < self.level {
+            return;
+        }
+        let text = match self.kind {
+            RemoteType::LOKI => format!("{}{}", log.msg, build_kv_text(log)),
+            _ => self.build_syslog(log),
+        };
+        let record = RemoteRecord {
+            text: text,
+            level: log.level,
+            feature: log.feature.clone(),
+            time_ns: log.time.timestamp_nanos_opt().unwrap_or(0),
+        };
+        let mut queue = self.shared.lock();
+        if queue.records.len() >= MAX_PENDING {
+            queue.records.pop_front();
+            self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        queue.records.push_back(record);
+        //loki攒够一批再唤醒，syslog逐条发送
+        if self.kind != RemoteType::LOKI || queue.records.len() >= LOKI_BATCH {
+            self.shared.signal.notify_one();
+        }
+    }
+
+    //通知发送线程做最后一次发送，并等待其退出，需在释放writer锁后调用
+    pub fn close(mut self) {
+        self.shared.close();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+
+    //<PRI>1 TIMESTAMP HOSTNAME APP-NAME PROCID MSGID [SD] MSG
+    fn build_syslog(&self, log: &LogMessage) -> String {
+        let pri = SYSLOG_FACILITY * 8 + SYSLOG_SEVERITY[log.level as usize];
+        let mut sd = format!("[{} index=\"{}\" level=\"{}\"", SYSLOG_SDID, sd_escape(&self.index), LEVEL_NAMES[log.level as usize]);
+        if !log.tag.is_empty() {
+            sd.push_str(&format!(" tag=\"{}\"", sd_escape(&log.tag)));
+        }
+        for (key, value) in log.fields.iter() {
+            let key: String = sd_name(key, 32);
+            sd.push_str(&format!(" {}=\"{}\"", key, sd_escape(value)));
+        }
+        sd.push(']');
+        format!("<{}>1 {} {} {} {} {} {} {}", pri, log.time.to_rfc3339_opts(SecondsFormat::Micros, false), self.hostname,
+            sd_name(&self.service, 48), process::id(), sd_name(&log.feature, 32), sd, log.msg)
+    }
+}
+
+//直接删除时不等待，发送线程在后台发完剩余日志后退出
+impl Drop for RemoteDest {
+    fn drop(&mut self) {
+        self.shared.close();
+    }
+}
+
+//发送线程，独占网络连接
+struct RemoteSink {
+    kind: RemoteType,
+    host: String,
+    path: String,
+    service: String,
+    index: String,
+    hostname: String,
+    link: RemoteLink,
+    pending: VecDeque<RemoteRecord>,
+    flush_time: Instant,
+    retry_time: Option<Instant>,
+    retry_delay: u64,
+    shared: Arc<RemoteShared>,
+}
+
+impl RemoteSink {
+    fn run(mut self) {
+        loop {
+            let closed = {
+                let mut queue = self.shared.lock();
+                if queue.records.is_empty() && !queue.closed {
+                    let wait = ternary!(self.pending.is_empty(), LOKI_PERIOD, SINK_TICK);
+                    queue = self.shared.signal.wait_timeout(queue, Duration::from_millis(wait)).map(|(queue, _)| queue).unwrap_or_else(|e| e.into_inner().0);
+                }
+                self.pending.extend(queue.records.drain(..));
+                queue.closed
+            };
+            while self.pending.len() > MAX_PENDING {
+                self.pending.pop_front();
+                self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            self.flush(closed);
+            if closed {
+                return;
+            }
+        }
+    }
+
+    //force为true时忽略批量周期和重试间隔，用于退出前的最后一次发送
+    fn flush(&mut self, force: bool) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let now = Instant::now();
+        if !force {
+            if self.retry_time.map_or(false, |retry| now < retry) {
+                return;
+            }
+            if self.kind == RemoteType::LOKI && self.pending.len() < LOKI_BATCH
+                && now < self.flush_time + Duration::from_millis(LOKI_PERIOD) {
+                return;
+            }
+        }
+        self.flush_time = now;
+        let res = match self.kind {
+            RemoteType::LOKI => self.send_loki(),
+            _ => self.send_syslog(),
+        };
+        match res {
+            Ok(()) => {
+                self.retry_time = None;
+                self.retry_delay = RETRY_MIN;
+            },
+            Err(_) => {
+                self.link = RemoteLink::None;
+                self.retry_time = Some(now + Duration::from_millis(self.retry_delay));
+                self.retry_delay = (self.retry_delay * 2).min(RETRY_MAX);
+            },
+        }
+    }
+
+    fn resolve(&self) -> Result<SocketAddr, String> {
+        let mut addrs = self.host.to_socket_addrs().map_err(|e| e.to_string())?;
+        addrs.next().ok_or(format!("resolve {} failed", self.host))
+    }
+
+    fn connect(&self) -> Result<TcpStream, String> {
+        let timeout = Duration::from_millis(IO_TIMEOUT);
+        let stream = TcpStream::connect_timeout(&self.resolve()?, timeout).map_err(|e| e.to_string())?;
+        let _ = stream.set_nodelay(true);
+        stream.set_read_timeout(Some(timeout)).map_err(|e| e.to_string())?;
+        stream.set_write_timeout(Some(timeout)).map_err(|e| e.to_string())?;
+        Ok(stream)
+    }
+
+    //tcp使用RFC 6587的octet-counting分帧
+    fn send_syslog(&mut self) -> Result<(), String> {
+        if let RemoteLink::None = self.link {
+            self.link = match self.kind {
+                RemoteType::SYSLOG_TCP => RemoteLink::Tcp(self.connect()?),
+                _ => {
+                    let addr = self.resolve()?;
+                    let bind = ternary!(addr.is_ipv4(), "0.0.0.0:0", "[::]:0");
+                    let socket = UdpSocket::bind(bind).map_err(|e| e.to_string())?;
+                    socket.connect(addr).map_err(|e| e.to_string())?;
+                    RemoteLink::Udp(socket)
+                },
+            };
+        }
+        while let Some(record) = self.pending.front() {
+            let res = match self.link {
+                RemoteLink::Udp(ref socket) => socket.send(record.text.as_bytes()).map(|_| ()),
+                RemoteLink::Tcp(ref mut stream) => stream.write_all(format!("{} {}", record.text.len(), record.text).as_bytes()),
+                RemoteLink::None => return Ok(()),
+            };
+            res.map_err(|e| e.to_string())?;
+            self.pending.pop_front();
+            self.shared.sent.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    fn send_loki(&mut self) -> Result<(), String> {
+        while !self.pending.is_empty() {
+            let count = self.pending.len().min(LOKI_BATCH);
+            let body = self.build_loki(count);
+            let status = self.post(&body)?;
+            //4xx为数据本身的问题，重试无意义，丢弃该批
+            if status == 429 || !(200..500).contains(&status) {
+                return Err(format!("loki push failed: {}", status));
+            }
+            if status >= 300 {
+                self.shared.dropped.fetch_add(count as u64, Ordering::Relaxed);
+            } else {
+                self.shared.sent.fetch_add(count as u64, Ordering::Relaxed);
+            }
+            self.pending.drain(..count);
+        }
+        Ok(())
+    }
+
+    //标签: service/index/feature/level/host，同标签的日志归为一个stream
+    fn build_loki(&self, count: usize) -> String {
+        let mut streams: BTreeMap<(u32, &str), Vec<String>> = BTreeMap::new();
+        for record in self.pending.iter().take(count) {
+            let value = format!("[\"{}\",{}]", record.time_ns, json_escape(&record.text));
+            streams.entry((record.level, record.feature.as_str())).or_default().push(value);
+        }
+        let streams: Vec<String> = streams.into_iter().map(|((level, feature), values)| {
+            let feature = ternary!(feature.is_empty(), self.service.as_str(), feature);
+            format!("{{\"stream\":{{\"service\":{},\"index\":{},\"feature\":{},\"level\":{},\"host\":{}}},\"values\":[{}]}}",
+                json_escape(&self.service), json_escape(&self.index), json_escape(feature),
+                json_escape(&LEVEL_NAMES[level as usize].to_lowercase()), json_escape(&self.hostname), values.join(","))
+        }).collect();
+        format!("{{\"streams\":[{}]}}", streams.join(","))
+    }
+
+    //最小的HTTP/1.1 POST，每批一个短连接，只解析状态行
+    fn post(&self, body: &str) -> Result<u32, String> {
+        let mut stream = self.connect()?;
+        let request = format!("POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.path, self.host, body.len());
+        stream.write_all(request.as_bytes()).map_err(|e| e.to_string())?;
+        stream.write_all(body.as_bytes()).map_err(|e| e.to_string())?;
+        let mut buf = Vec::new();
+        let mut chunk = [0u8; 512];
+        while !buf.contains(&b'\n') {
+            match stream.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+        let line = String::from_utf8_lossy(&buf);
+        line.split_whitespace().nth(1).and_then(|code| code.parse().ok()).ok_or(format!("invalid http response: {}", line.lines().next().unwrap_or("")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn build_log(level: u32, msg: &str) -> LogMessage {
+        let mut log = LogMessage::build(level, msg.to_string(), "tag1".to_string(), "login".to_string(), "", 0);
+        log.fields.push(("uid".to_string(), "1001".to_string()));
+        log
+    }
+
+    #[test]
+    fn bad_addr() {
+        assert!(RemoteDest::new(1, "127.0.0.1", 1, "test", "1").is_err());
+        assert!(RemoteDest::new(1, "127.0.0.1:99999", 1, "test", "1").is_err());
+        assert!(RemoteDest::new(3, "https://127.0.0.1:3100", 1, "test", "1").is_err());
+        assert!(RemoteDest::new(9, "127.0.0.1:514", 1, "test", "1").is_err());
+    }
+
+    #[test]
+    fn syslog_udp() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_read_timeout(Some(Duration::from_secs(3))).unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+        let mut dest = RemoteDest::new(RemoteType::SYSLOG_UDP as u32, &addr, 2, "test", "1").unwrap();
+        dest.write(&build_log(1, "skip"));
+        dest.write(&build_log(5, "hello"));
+        let mut buf = [0u8; 2048];
+        let n = server.recv(&mut buf).unwrap();
+        let text = String::from_utf8_lossy(&buf[..n]).to_string();
+        //local0.err = 16 * 8 + 3
+        assert!(text.starts_with("<131>1 "), "{}", text);
+        assert!(text.contains(&format!(" test {} login [quanta@32473 index=\"1\" level=\"ERROR\" tag=\"tag1\" uid=\"1001\"] hello", process::id())), "{}", text);
+        dest.close();
+    }
+
+    #[test]
+    fn loki_push() {
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+        let mut dest = RemoteDest::new(RemoteType::LOKI as u32, &format!("http://{}", addr), 1, "test", "1").unwrap();
+        dest.write(&build_log(2, "hello"));
+        //close强制发送剩余日志
+        let closer = thread::spawn(move || dest.close());
+        let (mut stream, _) = server.accept().unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(3))).unwrap();
+        let mut request = Vec::new();
+        let mut chunk = [0u8; 4096];
+        while !String::from_utf8_lossy(&request).ends_with("]}]}") {
+            let n = stream.read(&mut chunk).unwrap();
+            assert!(n > 0);
+            request.extend_from_slice(&chunk[..n]);
+        }
+        stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").unwrap();
+        drop(stream);
+        closer.join().unwrap();
+        let request = String::from_utf8_lossy(&request).to_string();
+        assert!(request.starts_with(&format!("POST {} HTTP/1.1\r\n", LOKI_PATH)), "{}", request);
+        let body = request.split("\r\n\r\n").nth(1).unwrap();
+        assert!(body.starts_with("{\"streams\":[{\"stream\":{\"service\":\"test\",\"index\":\"1\",\"feature\":\"login\",\"level\":\"info\",\"host\":"), "{}", body);
+        assert!(body.ends_with(",\"hello uid=1001\"]]}]}"), "{}", body);
+    }
+}
@@ -5,6 +5,7 @@ extern crate lua;
 extern crate luakit;
 
 mod luapb;
+mod pbcodec;
 
 use lua::lua_State;
 use libc::c_int as int;
@@ -14,7 +15,8 @@ use std::fs::File;
 use std::cell::RefCell;
 use std::collections::HashMap;
 
-use luakit::{ LuaPushFn, LuaPushLuaFn, Luakit, PtrBox, Slice };
+use luakit::{ LuaPush, LuaPushFn, LuaPushFnMut, LuaPushLuaFn, LuaPushLuaFnMut, Luakit, PtrBox, Slice };
+use pbcodec::PbCodec;
 use luapb::{ find_enum, find_message, encode_message, decode_message, read_file_descriptor_set, PbMessage};
 
 thread_local! {
@@ -161,7 +163,13 @@ pub extern "C" fn luaopen_luapb(L: *mut lua_State) -> int {
     luakit::set_function!(luapb, "enums", luapb::pb_enums);
     luakit::set_function!(luapb, "fields", luapb::pb_fields);
     luakit::set_function!(luapb, "messages", luapb::pb_messages);
-    //  luakit::set_function("pbcodec", pb_codec);
+    luakit::set_function!(luapb, "pbcodec", || Box::new(PbCodec::new()));
+    luakit::new_class!(PbCodec, luapb, "pbcodec",
+        "check", PbCodec::check,
+        "encode", PbCodec::encode_pb,
+        "decode", PbCodec::decode_pb,
+        "set_max_packet", PbCodec::set_max_packet
+    );
     luakit::set_function!(luapb, "bind_cmd", |cmd_id: u32, name: String, fullname : String| {
         let message = find_message(&fullname);
         if !message.is_null() {
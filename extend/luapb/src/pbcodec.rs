@@ -0,0 +1,277 @@
+#![allow(non_snake_case)]
+#![allow(dead_code)]
+
+use std::mem;
+use std::ops::{ Deref, DerefMut };
+
+use lua::{ ternary, lua_State };
+use libc::c_int as int;
+
+use luakit::{ BaseCodec, Codec, CodecError, LuaGc, LuaPush, Slice };
+
+use crate::pbmsg_from_stack;
+use crate::pbmsg_from_cmdid;
+use crate::luapb::{ encode_message, decode_message };
+
+//客户端协议包头，len为len字段之后的数据长度，与luabus的分包一致
+//session_id高16位为连接的stoken，包头只带低16位，收包后由lua用stoken还原
+#[repr(C, packed)]
+#[derive(Clone, Copy, Default)]
+pub struct PbHeader {
+    pub len: u32,
+    pub cmd_id: u16,
+    pub flag: u8,
+    pub ptype: u8,
+    pub crc8: u8,
+    pub session_id: u16,
+}
+
+const HEADER_LEN: usize = mem::size_of::<PbHeader>();
+const MAX_PACKET_SIZE: i32 = 0xffffff;
+
+impl PbHeader {
+    //高16位的stoken不上行
+    fn new(session_id: i64, cmd_id: u32, flag: u8, ptype: u8, crc8: u8) -> PbHeader {
+        PbHeader { len: 0, cmd_id: cmd_id as u16, flag: flag, ptype: ptype, crc8: crc8, session_id: (session_id & 0xffff) as u16 }
+    }
+
+    fn read(data: &[u8]) -> Option<PbHeader> {
+        if data.len() < HEADER_LEN {
+            return None;
+        }
+        Some(unsafe { std::ptr::read_unaligned(data.as_ptr() as *const PbHeader) })
+    }
+
+    fn bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self as *const PbHeader as *const u8, HEADER_LEN) }
+    }
+}
+
+//返回完整包长度，0表示数据不足，-1表示非法包
+fn packet_len(data: &[u8], max_packet: i32) -> i32 {
+    if data.len() < 4 {
+        return 0;
+    }
+    let len = u32::from_ne_bytes([data[0], data[1], data[2], data[3]]);
+    if (len as usize + 4) < HEADER_LEN || len as i64 > max_packet as i64 {
+        return -1;
+    }
+    if len as usize + 4 > data.len() {
+        return 0;
+    }
+    len as i32 + 4
+}
+
+//按包体长度填写len，超出max_packet时失败
+fn fill_len(header: &mut PbHeader, body_len: usize, max_packet: i32) -> Result<(), String> {
+    let len = body_len + HEADER_LEN - 4;
+    if len as i64 > max_packet as i64 {
+        return Err(format!("pb packet too large: {}", len));
+    }
+    header.len = len as u32;
+    Ok(())
+}
+
+//解析包头，返回包头和整包长度
+fn parse_packet(data: &[u8]) -> Result<(PbHeader, usize), CodecError> {
+    let header = PbHeader::read(data).ok_or(CodecError::InvalidLength)?;
+    let total = header.len as usize + 4;
+    if total < HEADER_LEN || total > data.len() {
+        return Err(CodecError::InvalidLength);
+    }
+    Ok((header, total))
+}
+
+//与script/constant.lua中的FlagMask保持一致，加密或压缩的包体不做pb解码
+const FLAG_ENCRYPT: u8  = 0x04;
+const FLAG_ZIP: u8      = 0x08;
+
+pub struct PbCodec {
+    base: BaseCodec,
+    max_packet: i32,
+}
+
+impl LuaGc for PbCodec {}
+
+impl PbCodec {
+    pub fn new() -> Self {
+        Self { base: BaseCodec::new(), max_packet: MAX_PACKET_SIZE }
+    }
+
+    //返回完整包长度，0表示数据不足，-1表示非法包
+    pub fn load_packet(&self, slice: &Slice) -> i32 {
+        packet_len(slice.contents(), self.max_packet)
+    }
+
+    //lua: encode(session_id, cmd_id/cmd_name, flag, type, crc8, body) -> data | nil, err
+    pub fn encode_pb(&mut self, L: *mut lua_State) -> int {
+        self.base.error("".to_string());
+        let data = self.encode(L, 1);
+        if self.base.failed() {
+            return luakit::variadic_return!(L, lua::LUA_NIL, self.base.err().to_string());
+        }
+        data.native_to_lua(L)
+    }
+
+    //lua: decode(data) -> recv_len, session_id, cmd_id, flag, type, crc8, body[, err]
+    pub fn decode_pb(&mut self, L: *mut lua_State, data: &[u8]) -> int {
+        let buff = luakit::get_buff();
+        buff.clean();
+        buff.push_data(data);
+        match self.decode(L) {
+            Ok(argc) => argc,
+            Err(e) => luakit::variadic_return!(L, lua::LUA_NIL, e.to_string()),
+        }
+    }
+
+    pub fn check(&self, data: &[u8]) -> i32 {
+        self.load_packet(&Slice::attach(data))
+    }
+
+    pub fn set_max_packet(&mut self, size: i32) {
+        self.max_packet = ternary!(size > 0, size, MAX_PACKET_SIZE);
+    }
+}
+
+impl Deref for PbCodec {
+    type Target = BaseCodec;
+    fn deref(&self) -> &Self::Target {
+        &self.base
+    }
+}
+
+impl DerefMut for PbCodec {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.base
+    }
+}
+
+impl Codec for PbCodec {
+    //包体为table时按协议号对应的消息编码，为string时视为已编码的数据
+    fn encode(&mut self, L: *mut lua_State, index: i32) -> Vec<u8> {
+        //非0表示需要从名字解析协议号
+        let mut cmd_id: u32 = u32::MAX;
+        let mut msg = match unsafe { pbmsg_from_stack(L, index + 1, &mut cmd_id) } {
+            Ok(msg) => msg,
+            Err(e) => {
+                self.base.error(e);
+                return Vec::new();
+            },
+        };
+        let mut header = PbHeader::new(lua::lua_tointeger(L, index) as i64, cmd_id, lua::lua_tointeger(L, index + 2) as u8,
+            lua::lua_tointeger(L, index + 3) as u8, lua::lua_tointeger(L, index + 4) as u8);
+        let buff = luakit::get_buff();
+        buff.clean();
+        buff.hold_place(HEADER_LEN);
+        unsafe {
+            match lua::lua_type(L, index + 5) {
+                lua::LUA_TSTRING => { buff.push_data(lua::lua_tolstring(L, index + 5)); },
+                lua::LUA_TTABLE => {
+                    lua::lua_pushvalue(L, index + 5);
+                    let res = encode_message(L, buff, &mut msg);
+                    lua::lua_pop(L, 1);
+                    if let Err(e) = res {
+                        self.base.error(e);
+                        return Vec::new();
+                    }
+                },
+                _ => {},
+            }
+        }
+        if let Err(e) = fill_len(&mut header, buff.size() - HEADER_LEN, self.max_packet) {
+            self.base.error(e);
+            return Vec::new();
+        }
+        buff.copy(0, header.bytes());
+        buff.string().as_bytes().to_vec()
+    }
+
+    //从全局缓冲读取一个整包，直接压栈给on_call_pb
+    //包体解码失败时body为nil，并在末尾追加错误信息
+    fn decode(&mut self, L: *mut lua_State) -> Result<i32, CodecError> {
+        let buff = luakit::get_buff();
+        let slice = buff.get_slice(None, None);
+        let data = slice.contents();
+        let (header, total) = parse_packet(data)?;
+        let mut body = Slice::attach(&data[HEADER_LEN..total]);
+        unsafe { lua::lua_checkstack(L, 8) };
+        let top = unsafe { lua::lua_gettop(L) };
+        let (session_id, cmd_id) = (header.session_id, header.cmd_id);
+        total.native_to_lua(L);
+        session_id.native_to_lua(L);
+        cmd_id.native_to_lua(L);
+        header.flag.native_to_lua(L);
+        header.ptype.native_to_lua(L);
+        header.crc8.native_to_lua(L);
+        if header.flag & (FLAG_ENCRYPT | FLAG_ZIP) != 0 {
+            body.contents().native_to_lua(L);
+            return Ok(unsafe { lua::lua_gettop(L) } - top);
+        }
+        let mut msg = pbmsg_from_cmdid(cmd_id as u32);
+        if msg.is_null() {
+            unsafe { lua::lua_pushnil(L) };
+            format!("invalid pb cmd: {}", cmd_id).native_to_lua(L);
+            return Ok(unsafe { lua::lua_gettop(L) } - top);
+        }
+        if let Err(e) = unsafe { decode_message(L, &mut body, &mut msg) } {
+            unsafe { lua::lua_settop(L, top + 6) };
+            unsafe { lua::lua_pushnil(L) };
+            e.native_to_lua(L);
+        }
+        Ok(unsafe { lua::lua_gettop(L) } - top)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_packet(session_id: i64, body: &[u8]) -> Vec<u8> {
+        let mut header = PbHeader::new(session_id, 1001, 0x01, 2, 7);
+        fill_len(&mut header, body.len(), MAX_PACKET_SIZE).unwrap();
+        let mut packet = header.bytes().to_vec();
+        packet.extend_from_slice(body);
+        packet
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let packet = build_packet(0x00070009, b"hello");
+        assert_eq!(packet.len(), HEADER_LEN + 5);
+        assert_eq!(packet_len(&packet, MAX_PACKET_SIZE), packet.len() as i32);
+        let (header, total) = parse_packet(&packet).unwrap();
+        let (len, cmd_id, session_id) = (header.len, header.cmd_id, header.session_id);
+        //len不含自身的4字节
+        assert_eq!(len as usize, packet.len() - 4);
+        assert_eq!(total, packet.len());
+        assert_eq!(cmd_id, 1001);
+        assert_eq!((header.flag, header.ptype, header.crc8), (0x01, 2, 7));
+        //只保留低16位
+        assert_eq!(session_id, 0x0009);
+        assert_eq!(&packet[HEADER_LEN..total], b"hello");
+    }
+
+    #[test]
+    fn short_packet() {
+        let packet = build_packet(1, b"hello");
+        assert_eq!(packet_len(&packet[..3], MAX_PACKET_SIZE), 0);
+        assert_eq!(packet_len(&packet[..packet.len() - 1], MAX_PACKET_SIZE), 0);
+        assert!(parse_packet(&packet[..packet.len() - 1]).is_err());
+        assert!(parse_packet(&packet[..HEADER_LEN - 1]).is_err());
+        //len小于包头
+        let mut bad = packet.clone();
+        bad[..4].copy_from_slice(&2u32.to_ne_bytes());
+        assert_eq!(packet_len(&bad, MAX_PACKET_SIZE), -1);
+        assert!(parse_packet(&bad).is_err());
+    }
+
+    #[test]
+    fn oversized_packet() {
+        let packet = build_packet(1, &[0u8; 64]);
+        assert_eq!(packet_len(&packet, 64), -1);
+        assert_eq!(packet_len(&packet, (packet.len() - 4) as i32), packet.len() as i32);
+        let mut header = PbHeader::new(1, 1001, 0, 0, 0);
+        assert!(fill_len(&mut header, 64, 64).is_err());
+        assert!(fill_len(&mut header, 64, 64 + HEADER_LEN as i32 - 4).is_ok());
+    }
+}
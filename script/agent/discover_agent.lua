@@ -14,6 +14,7 @@ local qfailed       = quanta.failed
 local shotfix       = signal.hotfix
 
 local event_mgr     = quanta.get("event_mgr")
+local socket_mgr    = quanta.get("socket_mgr")
 local thread_mgr    = quanta.get("thread_mgr")
 
 local SUCCESS       = quanta.enum("KernCode", "SUCCESS")
@@ -38,7 +39,10 @@ function DiscoverAgent:__init()
         event_mgr:add_listener(self, "rpc_service_hotfix")
         event_mgr:add_listener(self, "rpc_server_shutdown")
         event_mgr:add_listener(self, "rpc_set_logger_level")
+        event_mgr:add_listener(self, "rpc_set_logger_filter")
+        event_mgr:add_listener(self, "rpc_set_logger_rate")
         event_mgr:add_listener(self, "rpc_show_snapshot")
+        event_mgr:add_listener(self, "rpc_show_socket_stats")
         --消息
         event_mgr:add_trigger(self, "on_router_connected")
     end
@@ -142,6 +146,21 @@ function DiscoverAgent:rpc_set_logger_level(level)
     log_filter(level)
 end
 
+--target_type: 1 feature, 2 tag
+function DiscoverAgent:rpc_set_logger_filter(target_type, target, on)
+    log_debug("[DiscoverAgent][rpc_set_logger_filter] type: {}, target: {}, on: {}", target_type, target, on)
+    if target_type == 1 then
+        logger.filter_feature(target, on)
+    else
+        logger.filter_tag(target, on)
+    end
+end
+
+function DiscoverAgent:rpc_set_logger_rate(rate, burst)
+    log_debug("[DiscoverAgent][rpc_set_logger_rate] rate: {}, burst: {}", rate, burst)
+    logger.rate_limit(rate, burst)
+end
+
 function DiscoverAgent:rpc_show_snapshot()
     local snapshots = {}
     snapshots.object = class_review()
@@ -152,6 +171,13 @@ function DiscoverAgent:rpc_show_snapshot()
     return SUCCESS, snapshots
 end
 
+--网络统计: 汇总字段以及nodes中按token的连接明细
+function DiscoverAgent:rpc_show_socket_stats()
+    local stats = socket_mgr.stats()
+    log_debug("[DiscoverAgent][rpc_show_socket_stats] count: {}, errors: {}", stats.count, stats.errors)
+    return SUCCESS, stats
+end
+
 quanta.discover = DiscoverAgent()
 
 return DiscoverAgent
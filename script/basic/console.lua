@@ -38,7 +38,7 @@ quanta.console = function(ch)
             end
         end
         if ch == 13 or #console_buf > 255 then
-            logger.daemon(false)
+            logger.pause_console(false)
             if #console_buf > 0 then
                 exec_command(console_buf)
             end
@@ -49,7 +49,7 @@ quanta.console = function(ch)
     else
         if ch == 13 then
             console_input = true
-            logger.daemon(true)
+            logger.pause_console(true)
             stdout:write("input> ")
         end
     end
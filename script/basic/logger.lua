@@ -10,9 +10,11 @@ local lfilter       = log.filter
 
 local LOG_FLAG      = log.LOG_FLAG
 local LOG_LEVEL     = log.LOG_LEVEL
+local CONSOLE_MODE  = log.CONSOLE_MODE
 local THREAD_NAME   = quanta.thread
 
 local MONITORS      = quanta.init("MONITORS")
+local CONSOLE       = quanta.init("CONSOLE", { mode = CONSOLE_MODE.AUTO })
 
 logger = {}
 logfeature = {}
@@ -21,17 +23,29 @@ function logger.init()
     --配置日志信息
     log.set_max_size(environ.number("QUANTA_LOG_SIZE", 16777216))
     log.set_clean_time(environ.number("QUANTA_LOG_TIME", 648000))
+    log.set_quota(environ.number("QUANTA_LOG_FILES", 0), environ.number("QUANTA_LOG_TOTAL", 0))
     log.set_rolling_type(environ.number("QUANTA_LOG_ROLL", 0))
+    log.set_format(environ.number("QUANTA_LOG_FORMAT", 0))
+    log.set_compress(environ.number("QUANTA_LOG_COMPRESS", 0))
     --设置日志过滤
     logger.filter(environ.number("QUANTA_LOG_LVL"))
+    --调用位置采集: 0关闭
+    logger.source(environ.number("QUANTA_LOG_SOURCE", 0))
+    logger.rate_limit(environ.number("QUANTA_LOG_RATE", 0))
     --添加输出目标
     log.add_lvl_dest(LOG_LEVEL.ERROR)
-    --设置daemon
-    log.daemon(environ.status("QUANTA_DAEMON"))
+    --控制台输出: 0关闭,1无色,2彩色,3自动
+    logger.console(environ.number("QUANTA_LOG_CONSOLE", CONSOLE_MODE.AUTO))
 end
 
-function logger.daemon(daemon)
-    log.daemon(daemon)
+function logger.console(mode)
+    CONSOLE.mode = mode
+    log.set_console(mode)
+end
+
+--控制台输入时暂停输出
+function logger.pause_console(pause)
+    log.set_console(pause and CONSOLE_MODE.OFF or CONSOLE.mode)
 end
 
 function logger.add_monitor(monitor, level)
@@ -60,6 +74,29 @@ function logger.filter(level)
     end
 end
 
+--开关指定feature/tag的日志
+function logger.filter_feature(feature, on)
+    log.filter_feature(feature, on)
+end
+
+function logger.filter_tag(tag, on)
+    log.filter_tag(tag, on)
+end
+
+--同一feature和格式串每秒最多rate条, rate为0关闭
+function logger.rate_limit(rate, burst)
+    log.set_rate_limit(rate, burst or rate)
+end
+
+--采集level及以上等级日志的调用位置
+--调用栈: lprint <- pcall <- logger_output <- logger.xxx <- 业务代码
+function logger.source(level)
+    log.set_source_depth(4)
+    for lvl = LOG_LEVEL.DEBUG, LOG_LEVEL.FATAL do
+        log.set_source(lvl, level > 0 and lvl >= level)
+    end
+end
+
 local function logger_output(flag, feature, lvl, lvl_name, fmt, ...)
     local monitors = MONITORS[lvl]
     if monitors then
@@ -50,6 +50,7 @@ local EXIT_SIGNAL = {
 }
 
 local SIG_HOTFIX = SYS_SIGNAL.SIGUSR1
+local SIG_ROTATE = SYS_SIGNAL.SIGHUP
 
 signal = {}
 signal.init = function()
@@ -57,6 +58,7 @@ signal.init = function()
         quanta.register_signal(sig)
     end
     quanta.register_signal(SIG_HOTFIX)
+    quanta.register_signal(SIG_ROTATE)
     quanta.ignore_signal(SYS_SIGNAL.SIGPIPE)
     quanta.ignore_signal(SYS_SIGNAL.SIGCHLD)
 end
@@ -90,6 +92,14 @@ signal.reload = function(signalv)
     return breload
 end
 
+signal.rotate = function(signalv)
+    local brotate = (signalv & (1 << SIG_ROTATE) ~= 0)
+    if brotate then
+        set_signal(SIG_ROTATE, false)
+    end
+    return brotate
+end
+
 signal.quit = function()
     set_signal(SYS_SIGNAL.SIGQUIT, true)
 end
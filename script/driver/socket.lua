@@ -59,6 +59,10 @@ function Socket:listen(ip, port)
     self.listener.on_accept = function(session)
         qxpcall(self.on_socket_accept, "on_socket_accept: {}", self, session, ip, port)
     end
+    self.listener.on_error = function(token, err)
+        log_err("[Socket][listen] listener {}:{} closed: {}", ip, port, err)
+        self.listener = nil
+    end
     return true
 end
 
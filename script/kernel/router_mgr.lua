@@ -247,6 +247,22 @@ function RouterMgr:send_master(service_id, rpc, ...)
     return self:forward_target(self:hash_router(service_id), "call_master", rpc, 0, service_id, ...)
 end
 
+--发送给指定service在region内的master
+function RouterMgr:call_region_master(service_id, region, rpc, ...)
+    local session_id = thread_mgr:build_session_id()
+    return self:forward_target(self:hash_router(service_id), "call_region_master", rpc, session_id, service_id, region, ...)
+end
+
+--发送给指定service在region内的master
+function RouterMgr:send_region_master(service_id, region, rpc, ...)
+    return self:forward_target(self:hash_router(service_id), "call_region_master", rpc, 0, service_id, region, ...)
+end
+
+--通过router广播给指定service的group
+function RouterMgr:broadcast_group(service_id, group, rpc, ...)
+    return self:forward_target(self:hash_router(service_id), "call_group", rpc, 0, service_id, group, ...)
+end
+
 --生成针对服务的访问接口
 function RouterMgr:build_service_method(service, service_id)
     local method_list = {
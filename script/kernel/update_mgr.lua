@@ -8,6 +8,7 @@ local log_warn      = logger.warn
 local sig_get       = signal.get
 local sig_check     = signal.check
 local sig_reload    = signal.reload
+local sig_rotate    = signal.rotate
 local collectgarbage= collectgarbage
 
 local event_mgr     = quanta.get("event_mgr")
@@ -167,6 +168,10 @@ function UpdateMgr:check_signal()
         --输出状态
         quanta.report("reload")
     end
+    if sig_rotate(signal) then
+        log_info("[UpdateMgr][check_signal]log rotate for signal !")
+        log.rotate()
+    end
     if sig_check(signal) then
         log_info("[UpdateMgr][check_signal]service quit for signal !")
         self:quit()
@@ -1,6 +1,7 @@
 --net_client.lua
 
 local log_err           = logger.err
+local log_warn          = logger.warn
 local qdefer            = quanta.defer
 local qxpcall           = quanta.xpcall
 local make_timer        = quanta.make_timer
@@ -66,9 +67,19 @@ function NetClient:connect(block)
     socket.on_call_pb = function(recv_len, session_id, cmd_id, flag, type, crc8, body)
         if session_id > 0 then
             session_id = socket.stoken | session_id
+            --迟到的响应已按超时处理，丢弃
+            if flag & FLAG_REQ ~= FLAG_REQ and not socket.finish_call(session_id) then
+                return
+            end
         end
         qxpcall(self.on_socket_rpc, "on_socket_rpc: {}", self, cmd_id, flag, session_id, body)
     end
+    socket.on_call_timeout = function(session_id, reason)
+        thread_mgr:try_response(session_id, false, reason)
+    end
+    socket.on_late_response = function(session_id, late)
+        log_warn("[NetClient][on_late_response] session {} response {}ms after timeout", session_id, late)
+    end
     socket.on_error = function(token, err)
         thread_mgr:fork(function()
             self:on_socket_error(token, err)
@@ -130,6 +141,7 @@ function NetClient:write(cmd_id, data, type, session_id, flag)
     if not session_id or session_id <= 0 then
         return true
     end
+    self.socket.track_call(session_id, RPC_CALL_TIMEOUT)
     return thread_mgr:yield(session_id, cmd_id, RPC_CALL_TIMEOUT)
 end
 
@@ -144,6 +156,10 @@ function NetClient:call(cmd_id, data, type)
         return false
     end
     local session_id = self.socket.build_session_id()
+    if not session_id then
+        log_err("[NetClient][call] session id exhausted! cmd_id:{}", cmd_id)
+        return false
+    end
     return self:write(cmd_id, data, type or 0, session_id, FLAG_REQ)
 end
 
@@ -77,6 +77,10 @@ function NetServer:listen(ip, port, induce)
     listener.on_accept = function(session)
         qxpcall(self.on_socket_accept, "on_socket_accept: {}", self, session)
     end
+    listener.on_error = function(token, err)
+        log_err("[NetServer][listen] listener {}:{} closed: {}", ip, real_port, err)
+        self.listener = nil
+    end
     self.listener = listener
     self.ip, self.port = ip, real_port
     self.broad_token = listener.token
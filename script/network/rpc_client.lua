@@ -2,6 +2,7 @@
 
 local tunpack           = table.unpack
 local log_err           = logger.err
+local log_warn          = logger.warn
 local qdefer            = quanta.defer
 local qxpcall           = quanta.xpcall
 local hash_code         = codec.hash_code
@@ -118,6 +119,24 @@ function RpcClient:connect()
         local send_len = socket.forward_broadcast(session_id, FLAG_REQ, service_id, quanta.id, rpc, ...)
         return self:on_call_router(rpc, token, send_len)
     end
+    socket.call_region_master = function(rpc, session_id, service_id, region, ...)
+        local send_len = socket.forward_region_master(session_id, FLAG_REQ, service_id, region, quanta.id, rpc, ...)
+        return self:on_call_router(rpc, token, send_len)
+    end
+    socket.call_group = function(rpc, session_id, service_id, group, ...)
+        local send_len = socket.forward_group(session_id, FLAG_REQ, service_id, group, quanta.id, rpc, ...)
+        return self:on_call_router(rpc, token, send_len)
+    end
+    socket.on_call_timeout = function(session_id, reason)
+        thread_mgr:try_response(session_id, false, reason)
+    end
+    socket.on_late_response = function(session_id, late)
+        log_warn("[RpcClient][on_late_response] session {} response {}ms after timeout", session_id, late)
+    end
+    socket.on_route_error = function(session_id, code, reason)
+        log_err("[RpcClient][on_route_error] session {} route failed: {}({})", session_id, reason, code)
+        thread_mgr:response(session_id, false, reason)
+    end
     socket.on_error = function(stoken, err)
         self:on_socket_error(stoken, err)
     end
@@ -163,7 +182,10 @@ function RpcClient:on_socket_rpc(socket, session_id, rpc_flag, recv_len, source,
         thread_mgr:fork(dispatch_rpc_message, ...)
         return
     end
-    thread_mgr:response(session_id, ...)
+    --迟到的响应已按超时处理，丢弃
+    if socket.finish_call(session_id) then
+        thread_mgr:response(session_id, ...)
+    end
 end
 
 --错误处理
@@ -209,6 +231,7 @@ function RpcClient:forward_transfer(target_id, session_id, service_id, rpc, ...)
     if self.alive then
         if self.socket.transfer(rpc, session_id, target_id, service_id, ...) then
             if session_id > 0 then
+                self.socket.track_call(session_id, RPC_TIMEOUT)
                 return thread_mgr:yield(session_id, rpc, RPC_TIMEOUT)
             end
         end
@@ -238,6 +261,7 @@ function RpcClient:call(rpc, ...)
     if self.alive then
         local session_id = thread_mgr:build_session_id()
         if self.socket.call_rpc(rpc, session_id, FLAG_REQ, ...) then
+            self.socket.track_call(session_id, RPC_TIMEOUT)
             return thread_mgr:yield(session_id, rpc, RPC_TIMEOUT)
         end
     end
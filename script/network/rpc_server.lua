@@ -49,6 +49,10 @@ function RpcServer:__init(holder, ip, port, induce)
     listener.on_accept = function(client)
         qxpcall(self.on_socket_accept, "on_socket_accept: {}", self, client)
     end
+    listener.on_error = function(token, err)
+        log_err("[RpcServer][setup] listener {}:{} closed: {}", ip, real_port, err)
+        self.listener = nil
+    end
     self.holder = holder
     self.listener = listener
     self.ip, self.port = ip, real_port
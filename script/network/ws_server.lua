@@ -67,6 +67,10 @@ function WSServer:listen(ip, port, induce)
     listener.on_accept = function(session)
         qxpcall(self.on_socket_accept, "on_socket_accept: {}", self, session)
     end
+    listener.on_error = function(token, err)
+        log_err("[WSServer][listen] listener {}:{} closed: {}", ip, real_port, err)
+        self.listener = nil
+    end
     log_info("[WSServer][listen] start listen at: {}:{}", ip, real_port)
     self.ip, self.port = ip, port
     self.listener = listener
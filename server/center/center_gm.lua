@@ -54,6 +54,33 @@ function CenterGM:register()
             example = "set_logger_level 0 2",
             tip = "示例中,设置指定服务的日志输出等级"
         },
+        {
+            name = "set_logger_filter",
+            gm_type = LOCAL,
+            group = "运维",
+            desc = "开关服务日志feature/tag",
+            args = "service_id|integer target_type|integer target|string status|bool",
+            example = "set_logger_filter 0 1 devops 0",
+            tip = "示例中,关闭指定服务devops的日志输出(target_type 1:feature,2:tag; status 1开启0关闭)"
+        },
+        {
+            name = "set_logger_rate",
+            gm_type = LOCAL,
+            group = "运维",
+            desc = "设置服务日志限流",
+            args = "service_id|integer rate|integer burst|integer",
+            example = "set_logger_rate 0 100 200",
+            tip = "示例中,同一feature和格式的日志每秒最多100条,允许200条突发,rate为0关闭"
+        },
+        {
+            name = "show_socket_stats",
+            gm_type = LOCAL,
+            group = "运维",
+            desc = "显示网络统计",
+            args = "service_name|string index|integer",
+            example = "show_socket_stats lobby 1",
+            tip = "示例中,显示lobby1的连接数、收发字节/包数、发送队列和错误统计"
+        },
         {
             name = "show_snapshot",
             gm_type = LOCAL,
@@ -117,6 +144,20 @@ function CenterGM:set_logger_level(service_id, level)
     router_mgr:broadcast(service_id, "rpc_set_logger_level", level)
 end
 
+-- 开关日志feature/tag
+function CenterGM:set_logger_filter(service_id, target_type, target, status)
+    log_info("[CenterGM][set_logger_filter] service_id: {}, type:{}, target:{}, status:{}", service_id, target_type, target, status)
+    -- 通知服务
+    router_mgr:broadcast(service_id, "rpc_set_logger_filter", target_type, target, status)
+end
+
+-- 设置日志限流
+function CenterGM:set_logger_rate(service_id, rate, burst)
+    log_info("[CenterGM][set_logger_rate] service_id: {}, rate:{}, burst:{}", service_id, rate, burst)
+    -- 通知服务
+    router_mgr:broadcast(service_id, "rpc_set_logger_rate", rate, burst)
+end
+
 -- 显示系统快照
 function CenterGM:show_snapshot(service_name, index)
     log_info("[CenterGM][show_snapshot] service_name: {}, index:{}", service_name, index)
@@ -138,6 +179,23 @@ function CenterGM:show_snapshot(service_name, index)
     return res
 end
 
+-- 显示网络统计
+function CenterGM:show_socket_stats(service_name, index)
+    log_info("[CenterGM][show_socket_stats] service_name: {}, index:{}", service_name, index)
+    local quanta_id = make_sid(name2sid(service_name), index)
+    local ok, codeoe, res
+    if service_name == "router" then
+        ok, codeoe, res = router_mgr:call_router_id(quanta_id, "rpc_show_socket_stats")
+    else
+        ok, codeoe, res = router_mgr:call_target(quanta_id, "rpc_show_socket_stats")
+    end
+    if not ok then
+        log_err("[CenterGM][show_socket_stats] exec service={}-{} failed! codeoe={},res={}", service_name, index, codeoe, res)
+        return codeoe
+    end
+    return res
+end
+
 -- export
 quanta.center_gm = CenterGM()
 
@@ -48,3 +48,20 @@ log_dump("pb encode: {}", #tpb_str)
 local tdata = pbdecode("ncmd_cs.test_message", tpb_str)
 log_dump("pb decode:{}", tdata)
 
+
+--pbcodec编解码往返，session_id只保留低16位
+local FLAG_REQ      = 0x01
+local sunpack       = string.unpack
+local pbcodec       = protobuf.pbcodec()
+local session_id    = 0x00070009
+local packet = pbcodec.encode(session_id, NCmdId.NID_HEARTBEAT_REQ, FLAG_REQ, 0, 0, pb_data)
+local plen = sunpack("<I4", packet)
+assert(plen == #packet - 4, "pbcodec len mismatch")
+assert(pbcodec.check(packet) == #packet, "pbcodec check mismatch")
+assert(pbcodec.check(packet:sub(1, #packet - 1)) == 0, "pbcodec short packet")
+local recv_len, rsession_id, cmd_id, flag, ptype, crc8, body = pbcodec.decode(packet)
+assert(recv_len == #packet, "pbcodec recv_len mismatch")
+assert(rsession_id == (session_id & 0xffff), "pbcodec session_id mismatch")
+assert(cmd_id == NCmdId.NID_HEARTBEAT_REQ and flag == FLAG_REQ and ptype == 0 and crc8 == 0, "pbcodec header mismatch")
+assert(body and body.serial == pb_data.serial and body.time == pb_data.time, "pbcodec body mismatch")
+log_dump("pbcodec round trip ok: len={}, session_id={}", plen, rsession_id)
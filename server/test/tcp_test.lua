@@ -3,6 +3,10 @@
 local log_debug     = logger.debug
 
 local thread_mgr    = quanta.get("thread_mgr")
+local socket_mgr    = quanta.get("socket_mgr")
+
+local FLAG_REQ      = quanta.enum("FlagMask", "REQ")
+local FLAG_RES      = quanta.enum("FlagMask", "RES")
 
 if quanta.index == 1 then
     local tcp = luabus.tcp()
@@ -68,4 +72,35 @@ elseif quanta.index == 2 then
             end
         end
     end)
+elseif quanta.index == 3 then
+    --socket_mgr接受的连接收发
+    local listener = socket_mgr.listen("127.0.0.1", 8702)
+    log_debug("rpc-svr listen: {}", listener ~= nil)
+    listener.on_accept = function(session)
+        log_debug("rpc-svr accept: {}, ip: {}", session.token, session.ip)
+        session.on_call = function(recv_len, session_id, rpc_flag, source, rpc, index)
+            log_debug("rpc-svr recv: {} {} from {}", rpc, index, source)
+            session.call(session_id, FLAG_RES, 0, rpc, index)
+        end
+        session.on_error = function(token, err)
+            log_debug("rpc-svr session {} error: {}", token, err)
+        end
+    end
+    local client = socket_mgr.connect("127.0.0.1", 8702, 1000)
+    client.on_connect = function(res)
+        log_debug("rpc-cli connect: {}", res)
+        if res == "ok" then
+            client.call(1, FLAG_REQ, quanta.id, "rpc_echo", 1)
+        end
+    end
+    client.on_call = function(recv_len, session_id, rpc_flag, source, rpc, index)
+        log_debug("rpc-cli recv: {} {} session: {}", rpc, index, session_id)
+        thread_mgr:fork(function()
+            thread_mgr:sleep(1000)
+            client.call(session_id + 1, FLAG_REQ, quanta.id, rpc, index + 1)
+        end)
+    end
+    client.on_error = function(token, err)
+        log_debug("rpc-cli error: {}", err)
+    end
 end
@@ -3,6 +3,7 @@
 local log_debug     = logger.debug
 
 local thread_mgr    = quanta.get("thread_mgr")
+local socket_mgr    = quanta.get("socket_mgr")
 
 if quanta.index == 1 then
     local udp = luabus.udp()
@@ -48,4 +49,23 @@ elseif quanta.index == 2 then
             thread_mgr:sleep(1000)
         end
     end)
+elseif quanta.index == 3 then
+    --socket_mgr托管的udp，收包通过on_recv回调
+    local server = socket_mgr.bind_udp("127.0.0.1", 8602)
+    local client = socket_mgr.bind_udp("127.0.0.1", 8603)
+    log_debug("udp-mgr bind: {}, {}", server ~= nil, client ~= nil)
+    server.on_recv = function(buf, ip, port)
+        log_debug("udp-mgr-svr on_recv: {} from {}:{}", buf, ip, port)
+        server.sendto(buf, ip, port)
+    end
+    local index = 0
+    client.on_recv = function(buf, ip, port)
+        index = index + 1
+        log_debug("udp-mgr-cli on_recv: {} from {}:{}, stats: {}", buf, ip, port, client.stats())
+        thread_mgr:fork(function()
+            thread_mgr:sleep(1000)
+            client.sendto(string.format("client send %d", index), "127.0.0.1", 8602)
+        end)
+    end
+    client.sendto("client send 0!", "127.0.0.1", 8602)
 end